@@ -2,12 +2,17 @@ import syncwave_core
 
 print("Asking Rust to find audio devices...")
 try:
-    devices = syncwave_core.get_output_devices()
+    devices = syncwave_core.list_audio_devices()
+
+    hosts = [name for name, direction in devices if direction == "host"]
+    devices = [(name, direction) for name, direction in devices if direction != "host"]
 
     print(f"\nFound {len(devices)} devices:")
     print("-" * 30)
-    for device in devices:
-        print(f"🔊 {device}")
+    for name, direction in devices:
+        icon = "🎤" if direction == "input" else "🔊"
+        print(f"{icon} [{direction}] {name}")
+    print(f"\nAvailable hosts: {', '.join(hosts)}")
 
 except Exception as e:
     print(f"❌ Error: {e}")
\ No newline at end of file
@@ -8,6 +8,8 @@ Enhanced Receiver with:
 import socket
 import pyaudio
 import struct
+import sys
+import zlib
 import time
 import collections
 from threading import Thread, Lock
@@ -22,11 +24,18 @@ except:
 
 PORT = 5555
 HEADER_MAGIC = b"SYNC"
-PROTOCOL_VERSION = 1
+PROTOCOL_VERSION = 9
 
 # Packet types
 PACKET_TYPE_RAW = 0
 PACKET_TYPE_OPUS = 1
+PACKET_TYPE_KEEPALIVE = 2
+PACKET_TYPE_EOS = 4
+PACKET_TYPE_HELLO = 5
+# Seconds between header requests while waiting
+HELLO_INTERVAL = 0.5
+# Bit 7 of the type byte marks the last packet of a stream
+PACKET_FLAG_LAST = 0x80
 
 # Jitter buffer settings
 JITTER_BUFFER_SIZE = 10  # Number of packets to buffer
@@ -53,8 +62,12 @@ class JitterBuffer:
             return len(self.buffer)
 
 def parse_header(data):
-    """Parse header packet: [MAGIC][VERSION][SAMPLE_RATE][CHANNELS][COMPRESSION]"""
-    if len(data) < 12:
+    """Parse header packet: [MAGIC][VERSION][SAMPLE_RATE][CHANNELS][FLAGS][SOURCE_RATE][SESSION][NAME_LEN][NAME]
+
+    FLAGS bit 0 marks Opus, bit 1 encrypted, bit 2 authenticated packets and
+    bit 4 raw packets holding i16 samples.
+    """
+    if len(data) < 21:
         return None
     
     magic = data[:4]
@@ -64,29 +77,65 @@ def parse_header(data):
     version = data[4]
     sample_rate = struct.unpack('<I', data[5:9])[0]
     channels = struct.unpack('<H', data[9:11])[0]
-    compression = data[11]
+    flags = data[11]
+    compression = flags & 1
+    source_rate = struct.unpack('<I', data[12:16])[0]
+    session_id = struct.unpack('<I', data[16:20])[0]
+    name_len = data[20]
+    if len(data) < 21 + name_len:
+        return None
+    try:
+        stream_name = data[21:21 + name_len].decode('utf-8') or None
+    except UnicodeDecodeError:
+        return None
     
     return {
         'version': version,
         'sample_rate': sample_rate,
         'channels': channels,
         'compression': compression,
+        'encrypted': bool(flags & 2),
+        'authenticated': bool(flags & 4),
+        'source_rate': source_rate,
+        'raw_i16': bool(flags & 16),
+        'monotonic_clock': bool(flags & 32),
+        'planar': bool(flags & 64),
+        'session_id': session_id,
+        'stream_name': stream_name,
         'compression_name': 'Opus' if compression == 1 else 'Raw'
     }
 
 def parse_audio_packet(data):
-    """Parse audio packet: [TYPE][TIMESTAMP][SIZE][DATA]"""
-    if len(data) < 11:
+    """Parse audio packet: [TYPE][SEQUENCE][TIMESTAMP][FRAG_INDEX][FRAG_COUNT][SESSION][SIZE][DATA][CRC32]
+
+    Raw fragments split on sample frames, so each one can be played as it arrives.
+    """
+    if len(data) < 25:
         return None
     
-    packet_type = data[0]
-    timestamp = struct.unpack('<Q', data[1:9])[0]
-    size = struct.unpack('<H', data[9:11])[0]
-    audio_data = data[11:11+size]
+    # Drop packets whose trailing CRC32 doesn't match
+    crc = struct.unpack('<I', data[-4:])[0]
+    data = data[:-4]
+    if zlib.crc32(data) != crc:
+        return None
+    
+    packet_type = data[0] & ~PACKET_FLAG_LAST
+    sequence = struct.unpack('<I', data[1:5])[0]
+    timestamp = struct.unpack('<Q', data[5:13])[0]
+    fragment_index = data[13]
+    fragment_count = data[14]
+    session_id = struct.unpack('<I', data[15:19])[0]
+    size = struct.unpack('<H', data[19:21])[0]
+    audio_data = data[21:21+size]
     
     return {
         'type': packet_type,
+        'last': bool(data[0] & PACKET_FLAG_LAST),
+        'sequence': sequence,
         'timestamp': timestamp,
+        'fragment_index': fragment_index,
+        'fragment_count': fragment_count,
+        'session_id': session_id,
         'size': size,
         'data': audio_data
     }
@@ -95,6 +144,11 @@ def get_timestamp_us():
     """Get current timestamp in microseconds"""
     return int(time.time() * 1_000_000)
 
+def build_hello():
+    """Build a HELLO packet asking the sender to resend its header right away"""
+    packet = struct.pack('<BIQBBIH', PACKET_TYPE_HELLO, 0, get_timestamp_us(), 0, 1, 0, 0)
+    return packet + struct.pack('<I', zlib.crc32(packet))
+
 print(f"🎧 Enhanced receiver listening on port {PORT}...")
 print("Features: Opus codec, Jitter buffer, Latency measurement\n")
 
@@ -106,6 +160,7 @@ sock.bind(("0.0.0.0", PORT))
 print("⏳ Waiting for configuration header...")
 config = None
 audio_packet_count = 0
+last_hello = 0.0
 
 while config is None:
     data, addr = sock.recvfrom(8192)
@@ -113,14 +168,32 @@ while config is None:
     if config:
         print(f"✅ Config received from {addr}:")
         print(f"   Protocol Version: {config['version']}")
+        if config['stream_name']:
+            print(f"   Name: {config['stream_name']}")
         print(f"   Sample Rate: {config['sample_rate']} Hz")
         print(f"   Channels: {config['channels']}")
         print(f"   Compression: {config['compression_name']}")
+        if config['encrypted']:
+            # Decrypting needs the sender's AES-256-GCM key, which this script doesn't take
+            print("❌ Stream is encrypted; use the syncwave client with encryption_key")
+            sys.exit(1)
+        if config['authenticated']:
+            # Authenticated packets end in an HMAC instead of the CRC32 checked below
+            print("❌ Stream is authenticated; use the syncwave client with psk")
+            sys.exit(1)
+        if config['planar']:
+            # Planar buffers only make sense once their fragments are put back together
+            print("❌ Stream uses planar channel_layout; use the syncwave client or send interleaved")
+            sys.exit(1)
         if audio_packet_count > 0:
             print(f"   (Skipped {audio_packet_count} audio packets while waiting)")
         print()
     else:
         audio_packet_count += 1
+        # Joined mid-stream: ask the sender for the header instead of waiting for a resend
+        if time.time() - last_hello >= HELLO_INTERVAL:
+            sock.sendto(build_hello(), addr)
+            last_hello = time.time()
         if audio_packet_count % 100 == 0:
             print(f"   Still waiting... ({audio_packet_count} packets received)")
 
@@ -166,22 +239,33 @@ try:
         data, addr = sock.recvfrom(65536)
         
         # Skip header packets
-        if len(data) == 12 and data[:4] == HEADER_MAGIC:
+        if data[:4] == HEADER_MAGIC:
             continue
         
         # Parse audio packet
         packet = parse_audio_packet(data)
         if not packet:
             continue
+        # A restarted sender starts a new session this script wasn't set up for
+        if packet['session_id'] != config['session_id']:
+            continue
+        # Keepalives only show the sender is still there while idle
+        if packet['type'] == PACKET_TYPE_KEEPALIVE:
+            continue
+        # The sender stopped cleanly, so there's nothing left to wait for
+        if packet['type'] == PACKET_TYPE_EOS:
+            print("\n\n🏁 Sender ended the stream")
+            break
         
         packet_count += 1
         bytes_received += len(data)
         
-        # Calculate latency
-        receive_time = get_timestamp_us()
-        latency_us = receive_time - packet['timestamp']
-        total_latency_us += latency_us
-        latency_count += 1
+        # Calculate latency; monotonic timestamps count from the sender's start, not the epoch
+        if not config['monotonic_clock']:
+            receive_time = get_timestamp_us()
+            latency_us = receive_time - packet['timestamp']
+            total_latency_us += latency_us
+            latency_count += 1
         
         # Decode if needed
         if packet['type'] == PACKET_TYPE_OPUS and decoder:
@@ -193,6 +277,11 @@ try:
             except Exception as e:
                 print(f"⚠️  Opus decode error: {e}")
                 continue
+        elif config['raw_i16']:
+            # Raw i16 samples, widened to the float32 the output expects
+            count = len(packet['data']) // 2
+            samples = struct.unpack(f'<{count}h', packet['data'][:count * 2])
+            jitter_buffer.add(struct.pack(f'<{count}f', *(s / 32767 for s in samples)))
         else:
             # Raw audio data
             jitter_buffer.add(packet['data'])
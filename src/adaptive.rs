@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::crypto::PacketAuth;
+use crate::protocol::{build_packet, get_timestamp_us, parse_packet, verify_crc, AudioPacket, PACKET_TYPE_HELLO, PACKET_TYPE_LOSS_REPORT, PACKET_TYPE_PING};
+
+/// Bounds used when the caller doesn't give any.
+pub(crate) const DEFAULT_MIN_KBPS: u32 = 16;
+pub(crate) const DEFAULT_MAX_KBPS: u32 = 256;
+
+// How often receivers report, and how the sender reacts: loss above the upper
+// threshold cuts the bitrate by a quarter, while steps up only follow a hold
+// period without any lossy report so one bad receiver among several wins
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const LOSSY_PERCENT: f32 = 5.0;
+const CLEAN_PERCENT: f32 = 1.0;
+const DECREASE_FACTOR: f32 = 0.75;
+const INCREASE_FACTOR: f32 = 1.1;
+const INCREASE_HOLD: Duration = Duration::from_secs(3);
+// A sequence this far behind the newest one means the sender restarted
+const RESTART_DISTANCE: u32 = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Header replies are small, but a spoofed source could still aim them at a
+// victim, so senders, and receivers answering probes, reply at most this often
+// whoever is asking
+const HELLO_REPLY_INTERVAL: Duration = Duration::from_millis(100);
+// Receivers without a header ask again this often while audio keeps coming
+const HELLO_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+// Clock pings are rare, since wall clocks drift slowly, and the offset comes
+// from the exchange with the shortest round trip among the last few, as the
+// one least skewed by queueing in either direction
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const PING_REPLY_INTERVAL: Duration = Duration::from_millis(100);
+const PING_WINDOW: usize = 8;
+
+/// Packets a receiver expected and lost over one report interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LossReport {
+    pub expected: u32,
+    pub lost: u32,
+}
+
+impl LossReport {
+    fn loss_percent(&self) -> f32 {
+        self.lost as f32 * 100.0 / self.expected.max(1) as f32
+    }
+
+    /// Builds the report datagram; reports carry no audio, so they're signed
+    /// when a psk is set but never encrypted.
+    pub(crate) fn encode(&self, auth: Option<&PacketAuth>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8);
+        data.extend_from_slice(&self.expected.to_le_bytes());
+        data.extend_from_slice(&self.lost.to_le_bytes());
+        build_packet(PACKET_TYPE_LOSS_REPORT, 0, 0, get_timestamp_us(), 0, 1, &data, auth)
+    }
+
+    /// Reads a report from a verified packet, or returns None for anything else.
+    fn from_packet(packet: &AudioPacket) -> Option<Self> {
+        if packet.packet_type != PACKET_TYPE_LOSS_REPORT || packet.data.len() != 8 {
+            return None;
+        }
+        Some(LossReport {
+            expected: u32::from_le_bytes(packet.data[0..4].try_into().ok()?),
+            lost: u32::from_le_bytes(packet.data[4..8].try_into().ok()?),
+        })
+    }
+}
+
+// Checks the CRC or MAC of a datagram sent back to the sender
+pub(crate) fn verified_packet<'a>(datagram: &'a [u8], auth: Option<&PacketAuth>) -> Option<AudioPacket<'a>> {
+    let body = match auth {
+        Some(auth) => auth.verify(datagram)?,
+        None => verify_crc(datagram)?,
+    };
+    parse_packet(body)
+}
+
+/// Counts received audio sequence numbers to work out loss for reports.
+pub(crate) struct LossCounter {
+    newest: Option<u32>,
+    // Newest sequence when the current interval started
+    base: u32,
+    received: u32,
+    started: Instant,
+}
+
+impl LossCounter {
+    pub(crate) fn new() -> Self {
+        LossCounter { newest: None, base: 0, received: 0, started: Instant::now() }
+    }
+
+    /// Counts one received packet, returning true if it looks like the sender restarted.
+    pub(crate) fn record(&mut self, sequence: u32) -> bool {
+        let restarted = match self.newest {
+            Some(newest) if sequence.wrapping_sub(newest) < u32::MAX / 2 => {
+                self.newest = Some(sequence);
+                false
+            }
+            Some(newest) if newest.wrapping_sub(sequence) >= RESTART_DISTANCE => {
+                self.start_at(sequence);
+                self.received = 0;
+                true
+            }
+            // Reordered packets still count as received
+            Some(_) => false,
+            None => {
+                self.start_at(sequence);
+                false
+            }
+        };
+        self.received += 1;
+        restarted
+    }
+
+    fn start_at(&mut self, sequence: u32) {
+        self.newest = Some(sequence);
+        self.base = sequence.wrapping_sub(1);
+    }
+
+    /// Returns the report for the interval once it has run its course.
+    pub(crate) fn take_report(&mut self, now: Instant) -> Option<LossReport> {
+        if now.duration_since(self.started) < REPORT_INTERVAL {
+            return None;
+        }
+        let newest = self.newest?;
+        let expected = newest.wrapping_sub(self.base);
+        let report = LossReport { expected, lost: expected.saturating_sub(self.received) };
+        self.base = newest;
+        self.received = 0;
+        self.started = now;
+        (expected > 0).then_some(report)
+    }
+}
+
+// Answers a receiver's PING with its timestamp and this end's receive and send times
+fn ping_reply(packet: &AudioPacket, received_us: u64, auth: Option<&PacketAuth>) -> Option<Vec<u8>> {
+    if packet.data.len() != 8 {
+        return None;
+    }
+    let mut data = Vec::with_capacity(24);
+    data.extend_from_slice(packet.data);
+    data.extend_from_slice(&received_us.to_le_bytes());
+    let transmitted_us = get_timestamp_us();
+    data.extend_from_slice(&transmitted_us.to_le_bytes());
+    Some(build_packet(PACKET_TYPE_PING, 0, 0, transmitted_us, 0, 1, &data, auth))
+}
+
+/// Estimates how far the sender's wall clock is ahead of this one from
+/// occasional PING exchanges.
+pub(crate) struct ClockSync {
+    // Offset and round trip of recent exchanges, in microseconds
+    samples: VecDeque<(i64, u64)>,
+    last_ping: Option<Instant>,
+    // Timestamp of the PING awaiting an answer, so stale or stray answers are ignored
+    pending: Option<u64>,
+}
+
+impl ClockSync {
+    pub(crate) fn new() -> Self {
+        ClockSync { samples: VecDeque::with_capacity(PING_WINDOW), last_ping: None, pending: None }
+    }
+
+    /// Pings the sender at `peer` if PING_INTERVAL has passed since the last one.
+    pub(crate) fn poll(&mut self, socket: &UdpSocket, peer: SocketAddr, auth: Option<&PacketAuth>) {
+        let now = Instant::now();
+        if self.last_ping.is_some_and(|at| now.duration_since(at) < PING_INTERVAL) {
+            return;
+        }
+        self.last_ping = Some(now);
+        let origin_us = get_timestamp_us();
+        self.pending = Some(origin_us);
+        let _ = socket.send_to(&build_packet(PACKET_TYPE_PING, 0, 0, origin_us, 0, 1, &origin_us.to_le_bytes(), auth), peer);
+    }
+
+    /// Takes the sender's answer to the pending PING, arriving at `arrived_us`.
+    pub(crate) fn on_reply(&mut self, packet: &AudioPacket, arrived_us: u64) {
+        if packet.data.len() != 24 {
+            return;
+        }
+        let field = |index: usize| u64::from_le_bytes(packet.data[index * 8..index * 8 + 8].try_into().unwrap_or_default()) as i64;
+        let (origin, received, transmitted) = (field(0), field(1), field(2));
+        if self.pending != Some(origin as u64) {
+            return;
+        }
+        self.pending = None;
+        let arrived = arrived_us as i64;
+        let offset_us = ((received - origin) + (transmitted - arrived)) / 2;
+        let round_trip_us = ((arrived - origin) - (transmitted - received)).max(0) as u64;
+        if self.samples.len() == PING_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((offset_us, round_trip_us));
+    }
+
+    /// Sender clock minus this one, in microseconds, or None before any answer.
+    pub(crate) fn offset_us(&self) -> Option<i64> {
+        self.samples.iter().min_by_key(|(_, round_trip_us)| *round_trip_us).map(|(offset_us, _)| *offset_us)
+    }
+}
+
+/// Bitrate the encoder should use, set by the feedback thread and read by the sender.
+#[derive(Clone)]
+pub(crate) struct SharedBitrate(Arc<AtomicU32>);
+
+impl SharedBitrate {
+    pub(crate) fn new(kbps: u32) -> Self {
+        SharedBitrate(Arc::new(AtomicU32::new(kbps)))
+    }
+
+    pub(crate) fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, kbps: u32) {
+        self.0.store(kbps, Ordering::Relaxed);
+    }
+}
+
+/// Moves the bitrate between `min_kbps` and `max_kbps` as loss reports come in.
+pub(crate) struct BitrateController {
+    min_kbps: u32,
+    max_kbps: u32,
+    kbps: u32,
+    last_lossy: Option<Instant>,
+}
+
+impl BitrateController {
+    pub(crate) fn new(min_kbps: u32, max_kbps: u32, start_kbps: u32) -> Self {
+        BitrateController { min_kbps, max_kbps, kbps: start_kbps.clamp(min_kbps, max_kbps), last_lossy: None }
+    }
+
+    pub(crate) fn kbps(&self) -> u32 {
+        self.kbps
+    }
+
+    /// Applies one report, returning the new bitrate if it changed.
+    pub(crate) fn on_report(&mut self, report: LossReport, now: Instant) -> Option<u32> {
+        let loss = report.loss_percent();
+        let target = if loss >= LOSSY_PERCENT {
+            self.last_lossy = Some(now);
+            (self.kbps as f32 * DECREASE_FACTOR) as u32
+        } else if loss <= CLEAN_PERCENT && self.last_lossy.is_none_or(|at| now.duration_since(at) >= INCREASE_HOLD) {
+            // Always move by at least 1 kbps so low bitrates can climb
+            ((self.kbps as f32 * INCREASE_FACTOR) as u32).max(self.kbps + 1)
+        } else {
+            self.kbps
+        };
+        let target = target.clamp(self.min_kbps, self.max_kbps);
+        (target != self.kbps).then(|| {
+            self.kbps = target;
+            target
+        })
+    }
+}
+
+/// Listens on the sender's socket for datagrams from receivers: HELLOs are
+/// answered with `header`, PINGs with this end's clock, and loss reports steer
+/// the bitrate when `adaptive` is set.
+pub(crate) struct FeedbackListener {
+    pub(crate) socket: UdpSocket,
+    pub(crate) auth: Option<PacketAuth>,
+    // The encoded header datagram, as from protocol::encode_header; replaced
+    // when the server is reconfigured
+    pub(crate) header: Arc<Mutex<Vec<u8>>>,
+    pub(crate) adaptive: Option<(BitrateController, SharedBitrate)>,
+}
+
+impl FeedbackListener {
+    /// Runs until `running` clears.
+    pub(crate) fn spawn(mut self, running: Arc<AtomicBool>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            // The timeout only affects receives, so the audio thread's sends are unchanged
+            if let Err(e) = self.socket.set_read_timeout(Some(POLL_INTERVAL)) {
+                error!("Receiver feedback disabled, socket timeout failed: {}", e);
+                return;
+            }
+            let mut buf = [0u8; 64];
+            let mut last_hello_reply: Option<Instant> = None;
+            let mut last_ping_reply: Option<Instant> = None;
+            while running.load(Ordering::SeqCst) {
+                let Ok((len, from)) = self.socket.recv_from(&mut buf) else { continue };
+                let received_us = get_timestamp_us();
+                let Some(packet) = verified_packet(&buf[..len], self.auth.as_ref()) else { continue };
+                let now = Instant::now();
+                if packet.packet_type == PACKET_TYPE_PING {
+                    if last_ping_reply.is_none_or(|at| now.duration_since(at) >= PING_REPLY_INTERVAL) {
+                        if let Some(reply) = ping_reply(&packet, received_us, self.auth.as_ref()) {
+                            last_ping_reply = Some(now);
+                            let _ = self.socket.send_to(&reply, from);
+                        }
+                    }
+                    continue;
+                }
+                if packet.packet_type == PACKET_TYPE_HELLO {
+                    if last_hello_reply.is_none_or(|at| now.duration_since(at) >= HELLO_REPLY_INTERVAL) {
+                        last_hello_reply = Some(now);
+                        let _ = self.socket.send_to(&self.header.lock().unwrap(), from);
+                        info!("Sent header to {} on request", from);
+                    }
+                    continue;
+                }
+                let (Some(report), Some((controller, bitrate))) = (LossReport::from_packet(&packet), &mut self.adaptive) else { continue };
+                if let Some(kbps) = controller.on_report(report, now) {
+                    info!("{} reported {}/{} packets lost, bitrate now {} kbps", from, report.lost, report.expected, kbps);
+                    bitrate.set(kbps);
+                }
+            }
+        })
+    }
+}
+
+/// Asks the sender at `peer` for the stream header, at most every
+/// HELLO_RETRY_INTERVAL; `last_hello` tracks when the previous request went out.
+pub(crate) fn send_hello(socket: &UdpSocket, peer: SocketAddr, auth: Option<&PacketAuth>, last_hello: &mut Option<Instant>) {
+    let now = Instant::now();
+    if last_hello.is_some_and(|at| now.duration_since(at) < HELLO_RETRY_INTERVAL) {
+        return;
+    }
+    *last_hello = Some(now);
+    let _ = socket.send_to(&build_packet(PACKET_TYPE_HELLO, 0, 0, get_timestamp_us(), 0, 1, &[], auth), peer);
+    info!("Audio arrived without its header, asking {} for one", peer);
+}
+
+/// Answers a HELLO from `peer`, e.g. a ping_target probe, with one of our
+/// own, at most every HELLO_REPLY_INTERVAL; `last_reply` tracks the previous answer.
+pub(crate) fn answer_hello(socket: &UdpSocket, peer: SocketAddr, auth: Option<&PacketAuth>, last_reply: &mut Option<Instant>) {
+    let now = Instant::now();
+    if last_reply.is_some_and(|at| now.duration_since(at) < HELLO_REPLY_INTERVAL) {
+        return;
+    }
+    *last_reply = Some(now);
+    let _ = socket.send_to(&build_packet(PACKET_TYPE_HELLO, 0, 0, get_timestamp_us(), 0, 1, &[], auth), peer);
+}
+
+/// Sends `report` back to the sender at `peer`, ignoring failures: the next
+/// interval will simply try again.
+pub(crate) fn send_report(socket: &UdpSocket, peer: SocketAddr, report: LossReport, auth: Option<&PacketAuth>) {
+    let _ = socket.send_to(&report.encode(auth), peer);
+}
@@ -0,0 +1,191 @@
+use crossbeam_queue::ArrayQueue;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant};
+
+use crate::sender::SenderStats;
+
+// Capture buffers in circulation; with all of them queued the oldest makes way
+const QUEUE_BLOCKS: usize = 16;
+// Samples each buffer is allocated for up front; a bigger capture buffer grows
+// one once, after which it's reused like the rest
+pub(crate) const BLOCK_SAMPLES: usize = 8192;
+// Longest the send thread sleeps between checks when nothing wakes it
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+// How long a drain waits for the send thread to catch up
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const DRAIN_POLL: Duration = Duration::from_millis(1);
+
+struct Blocks {
+    // Emptied buffers for capture to fill, and filled ones waiting to be sent
+    free: ArrayQueue<Vec<f32>>,
+    filled: ArrayQueue<Vec<f32>>,
+    // Buffers pushed and not yet through the sink
+    pending: AtomicUsize,
+    running: AtomicBool,
+}
+
+/// The capture callback's end of the queue to the send thread. Pushing copies
+/// into a preallocated buffer and moves it through a lock-free queue, so the
+/// realtime callback never allocates, locks or waits on the network.
+#[derive(Clone)]
+pub(crate) struct CaptureQueue {
+    blocks: Arc<Blocks>,
+    stats: Arc<SenderStats>,
+    thread: Thread,
+}
+
+impl CaptureQueue {
+    /// Queues a copy of `samples` for the send thread. When it's behind and
+    /// every buffer is queued, the oldest is dropped to make room and counted
+    /// in `backpressure_drops`.
+    pub(crate) fn push(&self, samples: &[f32]) {
+        let mut block = match self.blocks.free.pop() {
+            Some(block) => {
+                self.blocks.pending.fetch_add(1, Ordering::SeqCst);
+                block
+            }
+            None => match self.blocks.filled.pop() {
+                Some(oldest) => {
+                    self.stats.backpressure_drops.fetch_add(1, Ordering::Relaxed);
+                    oldest
+                }
+                // The send thread only ever holds one, so this is a momentary race
+                None => {
+                    self.stats.backpressure_drops.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            },
+        };
+        block.clear();
+        block.extend_from_slice(samples);
+        // Can't be full: there are only QUEUE_BLOCKS buffers in all
+        let _ = self.blocks.filled.push(block);
+        self.thread.unpark();
+    }
+}
+
+/// Thread that feeds queued capture buffers to a sink, e.g. the packet
+/// sender, in order. Stops once everything queued has gone through.
+pub(crate) struct SendThread {
+    blocks: Arc<Blocks>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SendThread {
+    /// Starts the thread calling `sink` with each buffer, and returns the
+    /// queue for the capture callback to push to.
+    pub(crate) fn start(stats: Arc<SenderStats>, mut sink: impl FnMut(&[f32]) + Send + 'static) -> (CaptureQueue, SendThread) {
+        let blocks = Arc::new(Blocks { free: ArrayQueue::new(QUEUE_BLOCKS), filled: ArrayQueue::new(QUEUE_BLOCKS), pending: AtomicUsize::new(0), running: AtomicBool::new(true) });
+        for _ in 0..QUEUE_BLOCKS {
+            let _ = blocks.free.push(Vec::with_capacity(BLOCK_SAMPLES));
+        }
+        let thread_blocks = blocks.clone();
+
+        let thread = thread::spawn(move || loop {
+            match thread_blocks.filled.pop() {
+                Some(block) => {
+                    sink(&block);
+                    let _ = thread_blocks.free.push(block);
+                    thread_blocks.pending.fetch_sub(1, Ordering::SeqCst);
+                }
+                // Only stop once everything queued before stopping is out
+                None if !thread_blocks.running.load(Ordering::SeqCst) => return,
+                None => thread::park_timeout(IDLE_WAIT),
+            }
+        });
+
+        let queue = CaptureQueue { blocks: blocks.clone(), stats, thread: thread.thread().clone() };
+        (queue, SendThread { blocks, thread: Some(thread) })
+    }
+
+    /// Waits, up to a second, for everything queued so far to go through the sink.
+    pub(crate) fn drain(&self) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while self.blocks.pending.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(DRAIN_POLL);
+        }
+    }
+
+    /// Sends everything still queued and stops the thread.
+    pub(crate) fn finish(&mut self) {
+        self.blocks.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SendThread {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::mpsc;
+
+    // Counts allocations made on the current thread only, so other tests
+    // running alongside don't show up
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    #[test]
+    fn pushing_never_allocates() {
+        let (queue, mut send_thread) = SendThread::start(Arc::new(SenderStats::new(Vec::new())), |_| {});
+        // 10ms of stereo at 48kHz, like a typical capture callback
+        let samples = vec![0.25f32; 960];
+        let before = ALLOCATIONS.with(Cell::get);
+        for _ in 0..1000 {
+            queue.push(&samples);
+        }
+        assert_eq!(ALLOCATIONS.with(Cell::get), before);
+        send_thread.finish();
+    }
+
+    #[test]
+    fn a_stalled_sink_loses_the_oldest_buffers() {
+        let stats = Arc::new(SenderStats::new(Vec::new()));
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let (seen_tx, seen_rx) = mpsc::channel();
+        let (queue, mut send_thread) = SendThread::start(stats.clone(), move |block| {
+            // Held up until the gate closes
+            let _ = gate_rx.recv();
+            let _ = seen_tx.send(block[0]);
+        });
+        let pushes = QUEUE_BLOCKS + 5;
+        for i in 0..pushes {
+            queue.push(&[i as f32]);
+        }
+        drop(gate_tx);
+        send_thread.finish();
+
+        let seen: Vec<f32> = seen_rx.iter().collect();
+        assert_eq!(stats.backpressure_drops.load(Ordering::Relaxed), (pushes - QUEUE_BLOCKS) as u64);
+        assert_eq!(seen.len(), QUEUE_BLOCKS);
+        assert_eq!(seen.last(), Some(&((pushes - 1) as f32)));
+    }
+}
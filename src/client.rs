@@ -0,0 +1,341 @@
+use pyo3::prelude::*;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::codec::StreamDecoder;
+use crate::crypto::{auth_from_psk, cipher_from_key};
+use crate::devices::{select_host, select_output_device, supports_channels};
+use crate::error::{config_error, device_error};
+use crate::jitter::{JitterBuffer, Released};
+use crate::net::{parse_multicast_group, socket_address};
+use crate::protocol::StreamHeader;
+use crate::receiver::{PacketReceiver, Received};
+use crate::resample::Resampler;
+use crate::stats::{LatencyTracker, LossTracker, PlaybackStats};
+use crate::transport::parse_transport;
+
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Converts decoded audio from the stream's rate to the output device's, so a
+/// device running at another rate doesn't play it pitch-shifted.
+struct RateConverter {
+    resampler: Option<Resampler>,
+    resampled: Vec<f32>,
+}
+
+impl RateConverter {
+    fn new(stream_rate: u32, device_rate: u32, channels: u16) -> Self {
+        RateConverter { resampler: (stream_rate != device_rate).then(|| Resampler::new(stream_rate, device_rate, channels)), resampled: Vec::new() }
+    }
+
+    /// Appends `samples` at the device rate to `out`.
+    fn convert(&mut self, samples: &[f32], out: &mut impl Extend<f32>) {
+        match &mut self.resampler {
+            Some(resampler) => {
+                self.resampled.clear();
+                resampler.process(samples, &mut self.resampled);
+                out.extend(self.resampled.iter().copied());
+            }
+            None => out.extend(samples.iter().copied()),
+        }
+    }
+}
+
+/// Decoder and output stream for one set of negotiated stream parameters.
+struct Playback {
+    header: StreamHeader,
+    decoder: StreamDecoder,
+    decoded: Vec<f32>,
+    converter: RateConverter,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    max_queued: usize,
+    stats: Arc<PlaybackStats>,
+    // Samples in the last decoded packet, used to size the silence for lost ones
+    last_packet_samples: usize,
+    _stream: cpal::Stream,
+}
+
+impl Playback {
+    fn new(header: StreamHeader, device: &cpal::Device, stats: Arc<PlaybackStats>) -> PyResult<Self> {
+        let decoder = StreamDecoder::new(&header)?;
+        // Play at the device's own rate; without a default config, try the stream's
+        let device_rate = match device.default_output_config() {
+            Ok(config) => config.sample_rate().0,
+            Err(e) => {
+                warn!("No default output config ({}), playing at the stream rate", e);
+                header.sample_rate
+            }
+        };
+        // Raw streams can carry any channel count, which the output has to take as is
+        if header.channels > 2 && !supports_channels(device, header.channels, device_rate, cpal::SampleFormat::F32) {
+            return Err(device_error(format!("Output device can't play {} channels", header.channels)));
+        }
+        let config = cpal::StreamConfig {
+            channels: header.channels,
+            sample_rate: cpal::SampleRate(device_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let queue_clone = queue.clone();
+        let stats_clone = stats.clone();
+        // Nothing has played yet, so the wait for the first packet isn't an underrun
+        let mut starved = true;
+        let stream = device.build_output_stream(
+            &config,
+            move |out: &mut [f32], _: &_| {
+                let mut queue = queue_clone.lock().unwrap();
+                // Counted once each time the output runs dry, however long it stays that way
+                if queue.len() < out.len() {
+                    if !starved {
+                        stats_clone.underruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                    starved = true;
+                } else {
+                    starved = false;
+                }
+                for sample in out.iter_mut() {
+                    // Play silence until the network catches up
+                    *sample = queue.pop_front().unwrap_or(0.0);
+                }
+            },
+            move |err| error!("Playback error: {}", err),
+            None
+        ).map_err(|e| device_error(format!("Build output stream failed: {}", e)))?;
+        stream.play().map_err(|e| device_error(format!("Play stream failed: {}", e)))?;
+
+        if let Some(name) = header.name {
+            info!("Receiving stream '{}'", name.as_str());
+        }
+        info!("Playing {}Hz, {} channels, compression: {}", header.sample_rate, header.channels, header.encoding_name());
+        if header.source_rate != header.sample_rate {
+            info!("Sender resampled from {}Hz", header.source_rate);
+        }
+        if device_rate != header.sample_rate {
+            info!("Resampling {}Hz to the output device's {}Hz", header.sample_rate, device_rate);
+        }
+
+        Ok(Playback {
+            header,
+            decoder,
+            decoded: Vec::new(),
+            converter: RateConverter::new(header.sample_rate, device_rate, header.channels),
+            queue,
+            // Cap the backlog at one second so a stalled output can't grow it forever
+            max_queued: device_rate as usize * header.channels as usize,
+            stats,
+            last_packet_samples: 0,
+            _stream: stream,
+        })
+    }
+
+    /// Switches to a restarted sender's new session on the same output: a fresh
+    /// decoder, and nothing left over from the old stream.
+    fn restart(&mut self, header: StreamHeader) -> PyResult<()> {
+        self.decoder = StreamDecoder::new(&header)?;
+        self.header = header;
+        self.last_packet_samples = 0;
+        self.queue.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Waits for queued audio to play out, up to the one-second queue limit.
+    fn drain(&self) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline && !self.queue.lock().unwrap().is_empty() {
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+    }
+
+    fn play(&mut self, released: Released) {
+        let mut queue = self.queue.lock().unwrap();
+        match released {
+            Released::Packet(packet) => {
+                let queued = queue.len();
+                self.decoded.clear();
+                self.decoder.decode(packet.packet_type, &packet.data, &mut self.decoded);
+                self.converter.convert(&self.decoded, &mut *queue);
+                self.last_packet_samples = queue.len() - queued;
+            }
+            // An empty queue means the output already played the gap as silence,
+            // e.g. while a sender skipped quiet buffers, so adding more would only add delay
+            Released::Lost(_) if queue.is_empty() => {}
+            Released::Lost(count) => {
+                let silence = self.last_packet_samples * count as usize;
+                queue.extend(std::iter::repeat_n(0.0, silence.min(self.max_queued)));
+            }
+        }
+        if queue.len() > self.max_queued {
+            let excess = queue.len() - self.max_queued;
+            queue.drain(0..excess);
+            self.stats.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn start_audio_client(py: Python, bind_ip: String, bind_port: u16, multicast_group: Option<String>, transport: Option<String>, stats_callback: Option<PyObject>, jitter_ms: Option<u32>, encryption_key: Option<String>, psk: Option<String>, host: Option<String>, allow_loopback: Option<bool>, clock_sync: Option<bool>, output_device: Option<String>) -> PyResult<()> {
+    let cipher = encryption_key.map(|key| cipher_from_key(&key)).transpose()?;
+    let auth = psk.map(|psk| auth_from_psk(&psk)).transpose()?;
+    let transport_name = transport.unwrap_or_else(|| "udp".to_string());
+    let transport = parse_transport(&transport_name).ok_or_else(|| config_error(format!("Unknown transport '{}' (use \"udp\" or \"tcp\")", transport_name)))?;
+    let bind_addr = socket_address(&bind_ip, bind_port);
+    // Wake up periodically even without traffic so Ctrl+C reaches Python
+    let mut receiver = PacketReceiver::bind(&bind_addr, transport, SIGNAL_CHECK_INTERVAL)?;
+    info!("Listening for audio on: {}", bind_addr);
+    if let Some(cipher) = cipher {
+        receiver.decrypt_with(cipher);
+    }
+    if let Some(auth) = auth {
+        receiver.authenticate_with(auth);
+    }
+    // Off by default so a sender in this process isn't played back into its own capture
+    if allow_loopback.unwrap_or(false) {
+        receiver.accept_local_streams();
+    }
+    // Off by default: same-machine streams and synced clocks don't need it
+    if clock_sync.unwrap_or(false) {
+        receiver.sync_clock()?;
+        info!("Measuring the sender's clock offset for latency");
+    }
+    if let Some(group) = multicast_group {
+        let group_addr = parse_multicast_group(&group).ok_or_else(|| config_error(format!("'{}' is not a multicast address (224.0.0.0/4 or ff00::/8)", group)))?;
+        receiver.join_multicast(group_addr)?;
+        info!("Joined multicast group {}", group_addr);
+    }
+
+    let host = select_host(host.as_deref());
+    // Resolved up front so a wrong name fails before any audio arrives
+    let device = select_output_device(&host, output_device.as_deref())?;
+    info!("Playing on: {}", device.name().unwrap_or_else(|_| "unknown device".to_string()));
+
+    py.allow_threads(move || {
+        let mut playback: Option<Playback> = None;
+        let mut latency = LatencyTracker::new(false);
+        let mut loss = LossTracker::new();
+        // Kept across output rebuilds so the counts cover the whole session
+        let playback_stats = Arc::new(PlaybackStats::default());
+        let mut jitter = JitterBuffer::new(jitter_ms.unwrap_or(40));
+        let mut last_signal_check = Instant::now();
+        let mut last_stats = Instant::now();
+
+        loop {
+            if last_signal_check.elapsed() >= SIGNAL_CHECK_INTERVAL {
+                Python::with_gil(|py| py.check_signals())?;
+                last_signal_check = Instant::now();
+            }
+            if let Some(callback) = stats_callback.as_ref().filter(|_| last_stats.elapsed() >= STATS_INTERVAL) {
+                if let Err(e) = Python::with_gil(|py| latency.report(py, callback, &loss, &playback_stats)) {
+                    error!("Stats callback error: {}", e);
+                }
+                last_stats = Instant::now();
+            }
+
+            latency.set_clock_offset(receiver.clock_offset_us());
+            match receiver.recv()? {
+                Received::Header(header) => {
+                    // A restart with the same parameters keeps the output but none of the old audio
+                    if let Some(playback) = playback.as_mut().filter(|p| header.restarts(&p.header)) {
+                        jitter.clear();
+                        loss = LossTracker::new();
+                        // A restarted sender's monotonic clock starts over too
+                        if header.monotonic_clock {
+                            latency = LatencyTracker::new(true);
+                        }
+                        playback.restart(header)?;
+                    }
+                    // The sender repeats its header; only rebuild when the parameters change
+                    if playback.as_ref().map(|p| p.header) != Some(header) {
+                        // Timestamps on another clock can't be averaged with the old ones
+                        if playback.as_ref().is_none_or(|p| p.header.monotonic_clock != header.monotonic_clock) {
+                            latency = LatencyTracker::new(header.monotonic_clock);
+                        }
+                        // Release the old output stream before opening a new one
+                        drop(playback.take());
+                        jitter.clear();
+                        playback = Some(Playback::new(header, &device, playback_stats.clone())?);
+                    }
+                }
+                Received::Packet(packet) => {
+                    if packet.last {
+                        info!("Sender ended the stream");
+                    }
+                    latency.record(packet.timestamp_us);
+                    loss.record(packet.sequence);
+                    // Audio that arrives before the first header can't be interpreted yet
+                    if playback.is_some() {
+                        jitter.push(&packet, Instant::now());
+                    }
+                }
+                Received::EndOfStream => {
+                    // Play out what's already buffered before closing the output
+                    if let Some(mut playback) = playback.take() {
+                        while let Some(released) = jitter.flush() {
+                            playback.play(released);
+                        }
+                        playback.drain();
+                    }
+                    info!("Sender stopped, closing playback");
+                    return Ok(());
+                }
+                Received::Keepalive | Received::Skipped => {}
+            }
+
+            if let Some(playback) = playback.as_mut() {
+                while let Some(released) = jitter.pop(Instant::now()) {
+                    playback.play(released);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::null_source::TestTone;
+    use crate::protocol::{as_u8_slice, samples_from_bytes};
+
+    // Rising zero crossings of the first channel, per second at `rate`
+    fn frequency(samples: &[f32], channels: usize, rate: u32) -> f32 {
+        let first: Vec<f32> = samples.iter().step_by(channels).copied().collect();
+        let rising = first.windows(2).filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0).count();
+        rising as f32 * rate as f32 / first.len() as f32
+    }
+
+    #[test]
+    fn plays_48k_tone_on_44k_device_at_same_pitch() {
+        let mut tone = Vec::new();
+        TestTone::new(48000, 2).fill(48000, &mut tone);
+        let mut decoded = Vec::new();
+        samples_from_bytes(as_u8_slice(&tone), &mut decoded);
+
+        let mut converter = RateConverter::new(48000, 44100, 2);
+        let mut played = VecDeque::new();
+        // In packet-sized pieces, as the client decodes them
+        for packet in decoded.chunks(960 * 2) {
+            converter.convert(packet, &mut played);
+        }
+        let played: Vec<f32> = played.into();
+
+        assert!((played.len() as i64 - 44100 * 2).abs() <= 4, "{} samples", played.len());
+        let pitch = frequency(&played, 2, 44100);
+        assert!((pitch - 440.0).abs() < 2.0, "{} Hz", pitch);
+    }
+
+    #[test]
+    fn leaves_matching_rates_untouched() {
+        let mut tone = Vec::new();
+        TestTone::new(48000, 2).fill(480, &mut tone);
+        let mut played = Vec::new();
+        RateConverter::new(48000, 48000, 2).convert(&tone, &mut played);
+        assert_eq!(played, tone);
+    }
+}
@@ -0,0 +1,376 @@
+use pyo3::prelude::*;
+use audiopus::{coder::Decoder as OpusDecoder, coder::Encoder as OpusEncoder, packet::Packet as OpusPacket, Application as OpusApplication, Bandwidth as OpusBandwidth, Bitrate as OpusBitrate, Channels as OpusChannels, SampleRate as OpusSampleRate, Signal as OpusSignal};
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use crate::error::codec_error;
+use crate::protocol::{bundled_frames, interleave, samples_from_bytes, samples_from_i16_bytes, StreamHeader, PACKET_TYPE_OPUS, PACKET_TYPE_OPUS_BUNDLE, PACKET_TYPE_RAW};
+
+// Largest Opus frame is 120ms, i.e. 5760 samples per channel at 48kHz
+const MAX_OPUS_FRAME_SAMPLES: usize = 5760;
+
+// Opus puts at most 1275 bytes in one frame of 20ms or less; longer packets hold
+// several frames behind a TOC byte, a frame count and up to 2 length bytes each
+const MAX_OPUS_FRAME_BYTES: usize = 1275;
+const MAX_OPUS_BITRATE_KBPS: u32 = 510;
+// Small floor so tiny low-bitrate frames don't hit the retry path
+const MIN_PACKET_BUFFER: usize = 128;
+
+// Packet length assumed when concealing a loss before anything has been decoded
+const DEFAULT_CONCEAL_MS: f32 = 20.0;
+
+thread_local! {
+    // decode_opus_packet's decoders by (sample_rate, channels), kept so concealment
+    // carries on from the packets that came before
+    static PACKET_DECODERS: RefCell<HashMap<(u32, u16), OpusDecoder>> = RefCell::new(HashMap::new());
+}
+
+/// Rate that Opus-incompatible sources are converted to before encoding.
+pub(crate) const OPUS_RESAMPLE_RATE: u32 = 48000;
+
+/// Frame durations the Opus encoder accepts, in milliseconds.
+pub(crate) const OPUS_FRAME_DURATIONS_MS: [f32; 6] = [2.5, 5.0, 10.0, 20.0, 40.0, 60.0];
+
+/// Maps a sample rate onto one Opus can encode/decode natively.
+pub(crate) fn opus_sample_rate(sample_rate: u32) -> Option<OpusSampleRate> {
+    match sample_rate {
+        8000 => Some(OpusSampleRate::Hz8000),
+        12000 => Some(OpusSampleRate::Hz12000),
+        16000 => Some(OpusSampleRate::Hz16000),
+        24000 => Some(OpusSampleRate::Hz24000),
+        48000 => Some(OpusSampleRate::Hz48000),
+        _ => None,
+    }
+}
+
+pub(crate) fn opus_channels(channels: u16) -> Option<OpusChannels> {
+    match channels {
+        1 => Some(OpusChannels::Mono),
+        2 => Some(OpusChannels::Stereo),
+        _ => None,
+    }
+}
+
+/// Number of samples per channel in one frame of `frame_ms` at `sample_rate`.
+pub(crate) fn frame_samples(sample_rate: u32, frame_ms: f32) -> usize {
+    // Work in tenths of a millisecond so 2.5ms frames stay exact
+    let frame_tenths = (frame_ms * 10.0).round() as usize;
+    sample_rate as usize * frame_tenths / 10_000
+}
+
+/// Upper bound on one encoded packet of `frame_ms` at `bitrate_kbps`, or at the
+/// 510 kbps maximum when the bitrate isn't known.
+pub(crate) fn max_packet_len(frame_ms: f32, bitrate_kbps: Option<u32>) -> usize {
+    let frames = (frame_ms / 20.0).ceil().max(1.0) as usize;
+    let framing = 2 + 2 * frames;
+    let codec_limit = frames * MAX_OPUS_FRAME_BYTES + framing;
+    // VBR overshoots its average on transients, so leave room for twice the target
+    let kbps = bitrate_kbps.unwrap_or(MAX_OPUS_BITRATE_KBPS).min(MAX_OPUS_BITRATE_KBPS) as f32;
+    let bitrate_limit = (2.0 * kbps * frame_ms / 8.0).ceil() as usize + framing;
+    codec_limit.min(bitrate_limit).max(MIN_PACKET_BUFFER)
+}
+
+/// Encodes one frame into `buffer`, which should hold `max_packet_len` bytes:
+/// Opus quietly lowers quality to fit a smaller one. If it still reports the
+/// buffer too small, the buffer grows to fit any packet and the frame is retried.
+pub(crate) fn encode_frame(encoder: &OpusEncoder, frame: &[f32], buffer: &mut Vec<u8>) -> audiopus::Result<usize> {
+    match encoder.encode_float(frame, buffer) {
+        Err(audiopus::Error::Opus(audiopus::ErrorCode::BufferTooSmall)) => {
+            // 120ms, the longest Opus packet
+            let grown = (buffer.len() * 2).max(max_packet_len(120.0, None));
+            warn!("Opus packet didn't fit {} bytes, growing the buffer to {}", buffer.len(), grown);
+            buffer.resize(grown, 0);
+            encoder.encode_float(frame, buffer)
+        }
+        result => result,
+    }
+}
+
+/// Bitrate the encoder is targeting, if it reports one.
+pub(crate) fn encoder_bitrate_kbps(encoder: &OpusEncoder) -> Option<u32> {
+    match encoder.bitrate() {
+        Ok(OpusBitrate::BitsPerSecond(bps)) => Some(bps as u32 / 1000),
+        _ => None,
+    }
+}
+
+/// Parses an `opus_application` name: "voip", "audio" or "lowdelay".
+pub(crate) fn parse_opus_application(name: &str) -> Option<OpusApplication> {
+    match name {
+        "voip" => Some(OpusApplication::Voip),
+        "audio" => Some(OpusApplication::Audio),
+        "lowdelay" => Some(OpusApplication::LowDelay),
+        _ => None,
+    }
+}
+
+/// Parses a `max_bandwidth` name: "narrow", "medium", "wide", "superwide" or "full".
+pub(crate) fn parse_bandwidth(name: &str) -> Option<OpusBandwidth> {
+    match name {
+        "narrow" => Some(OpusBandwidth::Narrowband),
+        "medium" => Some(OpusBandwidth::Mediumband),
+        "wide" => Some(OpusBandwidth::Wideband),
+        "superwide" => Some(OpusBandwidth::Superwideband),
+        "full" => Some(OpusBandwidth::Fullband),
+        _ => None,
+    }
+}
+
+/// Parses a `signal` name: "auto", "voice" or "music".
+pub(crate) fn parse_signal(name: &str) -> Option<OpusSignal> {
+    match name {
+        "auto" => Some(OpusSignal::Auto),
+        "voice" => Some(OpusSignal::Voice),
+        "music" => Some(OpusSignal::Music),
+        _ => None,
+    }
+}
+
+/// Encoder options chosen by the caller.
+#[derive(Clone)]
+pub(crate) struct EncoderSettings {
+    pub(crate) application: OpusApplication,
+    // None leaves the encoder on its automatic bitrate
+    pub(crate) bitrate_kbps: Option<u32>,
+    // Expected loss percentage when in-band FEC is on; None disables FEC
+    pub(crate) fec_loss_perc: Option<u8>,
+    pub(crate) dtx: bool,
+    // 0-10; None keeps libopus's default
+    pub(crate) complexity: Option<u8>,
+    // Upper limit on the coded audio band; None lets the encoder pick
+    pub(crate) max_bandwidth: Option<OpusBandwidth>,
+    // What the audio mostly is; Auto lets the encoder detect it
+    pub(crate) signal: OpusSignal,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings { application: OpusApplication::Audio, bitrate_kbps: None, fec_loss_perc: None, dtx: false, complexity: None, max_bandwidth: None, signal: OpusSignal::Auto }
+    }
+}
+
+/// Creates an Opus encoder for the stream configured with `settings`.
+pub(crate) fn new_opus_encoder(sample_rate: u32, channels: u16, settings: &EncoderSettings) -> PyResult<OpusEncoder> {
+    let rate = opus_sample_rate(sample_rate).ok_or_else(|| codec_error(format!("Sample rate {} Hz not supported by Opus (supported: 8k, 12k, 16k, 24k, 48k)", sample_rate)))?;
+    let opus_channels = opus_channels(channels).ok_or_else(|| codec_error(format!("Channel count {} not supported by Opus (1 or 2 only)", channels)))?;
+
+    let mut encoder = OpusEncoder::new(rate, opus_channels, settings.application).map_err(|e| codec_error(format!("Failed to create Opus encoder: {:?}", e)))?;
+
+    if let Some(kbps) = settings.bitrate_kbps {
+        encoder.set_bitrate(OpusBitrate::BitsPerSecond(kbps as i32 * 1000)).map_err(|e| codec_error(format!("Failed to set Opus bitrate: {:?}", e)))?;
+    }
+    match encoder.bitrate() {
+        Ok(OpusBitrate::BitsPerSecond(bps)) => info!("Opus bitrate: {} kbps{}", bps / 1000, if settings.bitrate_kbps.is_none() { " (auto)" } else { "" }),
+        _ => info!("Opus bitrate: auto"),
+    }
+
+    // FEC only helps if the receiving decoder asks for it when a packet goes missing
+    if let Some(loss_perc) = settings.fec_loss_perc {
+        encoder.set_inband_fec(true).map_err(|e| codec_error(format!("Failed to enable Opus FEC: {:?}", e)))?;
+        encoder.set_packet_loss_perc(loss_perc).map_err(|e| codec_error(format!("Failed to set Opus packet loss: {:?}", e)))?;
+        info!("Opus FEC enabled for {}% expected packet loss", loss_perc);
+    }
+    if settings.dtx {
+        encoder.set_dtx(true).map_err(|e| codec_error(format!("Failed to enable Opus DTX: {:?}", e)))?;
+        info!("Opus DTX enabled");
+    }
+    if let Some(complexity) = settings.complexity {
+        encoder.set_complexity(complexity).map_err(|e| codec_error(format!("Failed to set Opus complexity: {:?}", e)))?;
+        info!("Opus complexity: {}", complexity);
+    }
+    // A cap rather than a fixed band, so the encoder can still go narrower at low bitrates
+    if let Some(bandwidth) = settings.max_bandwidth {
+        encoder.set_max_bandwidth(bandwidth).map_err(|e| codec_error(format!("Failed to set Opus max bandwidth: {:?}", e)))?;
+        info!("Opus max bandwidth: {:?}", bandwidth);
+    }
+    if settings.signal != OpusSignal::Auto {
+        encoder.set_signal(settings.signal).map_err(|e| codec_error(format!("Failed to set Opus signal type: {:?}", e)))?;
+        info!("Opus signal type: {:?}", settings.signal);
+    }
+    Ok(encoder)
+}
+
+/// Decodes one Opus packet into interleaved f32 samples, for receivers written in Python.
+///
+/// A decoder is kept per sample_rate and channels on the calling thread, so a
+/// stream's packets should be passed in order. Empty `data` stands for a packet
+/// that never arrived and returns concealed audio as long as the last packet.
+/// With `fec`, `data` is the packet after a lost one and the lost one is
+/// recovered from its FEC data; decode it again without `fec` for its own audio.
+#[pyfunction]
+#[pyo3(signature = (data, sample_rate, channels, fec=false))]
+pub(crate) fn decode_opus_packet(data: &[u8], sample_rate: u32, channels: u16, fec: bool) -> PyResult<Vec<f32>> {
+    let rate = opus_sample_rate(sample_rate).ok_or_else(|| codec_error(format!("Sample rate {} Hz not supported by Opus (supported: 8k, 12k, 16k, 24k, 48k)", sample_rate)))?;
+    let opus_channels = opus_channels(channels).ok_or_else(|| codec_error(format!("Channel count {} not supported by Opus (1 or 2 only)", channels)))?;
+
+    PACKET_DECODERS.with(|decoders| -> PyResult<Vec<f32>> {
+        let mut decoders = decoders.borrow_mut();
+        let decoder = match decoders.entry((sample_rate, channels)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(OpusDecoder::new(rate, opus_channels).map_err(|e| codec_error(format!("Failed to create Opus decoder: {:?}", e)))?),
+        };
+        // Concealment and FEC fill exactly the gap they're given, so size it like the
+        // packets around it; a normal decode just needs room for the longest packet
+        let frames = if data.is_empty() {
+            match decoder.last_packet_duration() {
+                Ok(frames) if frames > 0 => frames as usize,
+                _ => frame_samples(sample_rate, DEFAULT_CONCEAL_MS),
+            }
+        } else if fec {
+            decoder.nb_samples(opus_packet(data)?).map_err(|e| codec_error(format!("Invalid Opus packet: {:?}", e)))?
+        } else {
+            MAX_OPUS_FRAME_SAMPLES
+        };
+        let packet = if data.is_empty() { None } else { Some(opus_packet(data)?) };
+        let mut samples = vec![0.0; frames * channels as usize];
+        let output = (&mut samples[..]).try_into().map_err(|e| codec_error(format!("Opus output buffer: {:?}", e)))?;
+        let decoded = decoder.decode_float(packet, output, fec && !data.is_empty()).map_err(|e| codec_error(format!("Opus decode error: {:?}", e)))?;
+        samples.truncate(decoded * channels as usize);
+        Ok(samples)
+    })
+}
+
+fn opus_packet(data: &[u8]) -> PyResult<OpusPacket<'_>> {
+    data.try_into().map_err(|e| codec_error(format!("Invalid Opus packet: {:?}", e)))
+}
+
+/// Turns the audio packets of one negotiated stream back into interleaved f32 samples.
+pub(crate) struct StreamDecoder {
+    channels: usize,
+    opus: Option<OpusDecoder>,
+    decode_buffer: Vec<f32>,
+    raw_i16: bool,
+    // Planar raw buffers are read into here before being interleaved
+    planar: Option<Vec<f32>>,
+}
+
+impl StreamDecoder {
+    pub(crate) fn new(header: &StreamHeader) -> PyResult<Self> {
+        let opus = if header.compressed {
+            let rate = opus_sample_rate(header.sample_rate).ok_or_else(|| codec_error(format!("Sample rate {} Hz not supported by Opus (supported: 8k, 12k, 16k, 24k, 48k)", header.sample_rate)))?;
+            let channels = opus_channels(header.channels).ok_or_else(|| codec_error(format!("Channel count {} not supported by Opus (1 or 2 only)", header.channels)))?;
+            let decoder = OpusDecoder::new(rate, channels).map_err(|e| codec_error(format!("Failed to create Opus decoder: {:?}", e)))?;
+            Some(decoder)
+        } else {
+            None
+        };
+
+        Ok(StreamDecoder {
+            channels: header.channels as usize,
+            opus,
+            decode_buffer: vec![0.0; MAX_OPUS_FRAME_SAMPLES * header.channels as usize],
+            raw_i16: header.raw_i16,
+            planar: header.planar.then(Vec::new),
+        })
+    }
+
+    /// Decodes one packet and appends its samples to `out`.
+    pub(crate) fn decode(&mut self, packet_type: u8, data: &[u8], out: &mut impl Extend<f32>) {
+        match packet_type {
+            PACKET_TYPE_RAW => {
+                let Some(planar) = &mut self.planar else { return raw_samples(data, self.raw_i16, out) };
+                planar.clear();
+                raw_samples(data, self.raw_i16, planar);
+                interleave(planar, self.channels, out);
+            }
+            PACKET_TYPE_OPUS => self.decode_opus(data, out),
+            PACKET_TYPE_OPUS_BUNDLE => {
+                for frame in bundled_frames(data) {
+                    self.decode_opus(frame, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn decode_opus(&mut self, data: &[u8], out: &mut impl Extend<f32>) {
+        let Some(decoder) = &mut self.opus else { return };
+        let (Ok(packet), Ok(output)) = (data.try_into(), (&mut self.decode_buffer[..]).try_into()) else { return };
+        match decoder.decode_float(Some(packet), output, false) {
+            Ok(frames) => out.extend(self.decode_buffer[..frames * self.channels].iter().copied()),
+            Err(e) => error!("Opus decode error: {:?}", e),
+        }
+    }
+}
+
+// Raw DATA as f32 samples, in whichever sample format the header announced
+fn raw_samples(data: &[u8], raw_i16: bool, out: &mut impl Extend<f32>) {
+    if raw_i16 {
+        samples_from_i16_bytes(data, out);
+    } else {
+        samples_from_bytes(data, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{i16_bytes_from_samples, PROTOCOL_VERSION};
+
+    // White noise is the hardest signal to compress, so it pushes the encoder to its bitrate
+    fn noise(len: usize) -> Vec<f32> {
+        let mut seed = 1u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 8) as f32 / (1 << 23) as f32 - 1.0
+            })
+            .collect()
+    }
+
+    fn stereo_encoder(bitrate_kbps: i32) -> OpusEncoder {
+        let mut encoder = OpusEncoder::new(OpusSampleRate::Hz48000, OpusChannels::Stereo, OpusApplication::Audio).unwrap();
+        encoder.set_bitrate(OpusBitrate::BitsPerSecond(bitrate_kbps * 1000)).unwrap();
+        encoder
+    }
+
+    #[test]
+    fn sixty_ms_frames_at_510_kbps_fit_the_computed_buffer() {
+        let encoder = stereo_encoder(510);
+        let frame = noise(frame_samples(48000, 60.0) * 2);
+        let buffer_len = max_packet_len(60.0, encoder_bitrate_kbps(&encoder));
+        assert_eq!(buffer_len, 3 * MAX_OPUS_FRAME_BYTES + 8);
+
+        // Opus shrinks packets to whatever space it's given, so compare against
+        // an unconstrained encode to be sure the bound never cost any quality
+        let mut unbounded = vec![0u8; 4 * buffer_len];
+        let mut buffer = vec![0u8; buffer_len];
+        for _ in 0..20 {
+            let wanted = encode_frame(&encoder, &frame, &mut unbounded).unwrap();
+            assert!(wanted <= buffer_len, "{} byte packet exceeds the {} byte bound", wanted, buffer_len);
+            let len = encode_frame(&encoder, &frame, &mut buffer).unwrap();
+            assert!(len > 2000 && len <= buffer_len);
+        }
+        // Never needed the retry
+        assert_eq!(buffer.len(), buffer_len);
+    }
+
+    #[test]
+    fn raw_i16_packets_decode_to_within_one_step() {
+        let header = StreamHeader { version: PROTOCOL_VERSION, sample_rate: 48000, channels: 2, compressed: false, encrypted: false, authenticated: false, loss_reports: false, raw_i16: true, monotonic_clock: false, planar: false, source_rate: 48000, session_id: 0, name: None };
+        let samples = noise(960 * 2);
+        let mut data = Vec::new();
+        i16_bytes_from_samples(&samples, &mut data);
+        assert_eq!(data.len(), samples.len() * header.raw_sample_bytes());
+
+        let mut decoded = Vec::new();
+        StreamDecoder::new(&header).unwrap().decode(PACKET_TYPE_RAW, &data, &mut decoded);
+        assert_eq!(decoded.len(), samples.len());
+        for (original, decoded) in samples.iter().zip(&decoded) {
+            assert!((original - decoded).abs() <= 1.0 / i16::MAX as f32, "{} decoded as {}", original, decoded);
+        }
+    }
+
+    #[test]
+    fn standalone_decode_conceals_a_missing_packet_at_the_same_length() {
+        let encoder = stereo_encoder(64);
+        let mut buffer = vec![0u8; max_packet_len(10.0, Some(64))];
+        let len = encode_frame(&encoder, &noise(480 * 2), &mut buffer).unwrap();
+
+        let decoded = decode_opus_packet(&buffer[..len], 48000, 2, false).unwrap();
+        assert_eq!(decoded.len(), 480 * 2);
+        let concealed = decode_opus_packet(&[], 48000, 2, false).unwrap();
+        assert_eq!(concealed.len(), 480 * 2);
+    }
+}
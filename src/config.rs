@@ -0,0 +1,404 @@
+// pyo3 0.20's #[new] expansion trips this lint on newer compilers
+#![allow(non_local_definitions)]
+
+use pyo3::prelude::*;
+
+/// A bitrate given from Python either as kbps or as the string "auto".
+#[derive(Clone, FromPyObject)]
+pub(crate) enum BitrateArg {
+    Kbps(u32),
+    Named(String),
+}
+
+impl IntoPy<PyObject> for BitrateArg {
+    fn into_py(self, py: Python) -> PyObject {
+        match self {
+            BitrateArg::Kbps(kbps) => kbps.into_py(py),
+            BitrateArg::Named(name) => name.into_py(py),
+        }
+    }
+}
+
+/// Everything `start_audio_server_with_config` needs, set by keyword.
+///
+/// Fields left as None take the same defaults as the matching
+/// `start_audio_server` arguments, and are checked when the server starts.
+#[pyclass]
+#[derive(Clone)]
+pub(crate) struct ServerConfig {
+    #[pyo3(get, set)]
+    pub(crate) target_ip: String,
+    #[pyo3(get, set)]
+    pub(crate) target_port: u16,
+    #[pyo3(get, set)]
+    pub(crate) use_compression: Option<bool>,
+    #[pyo3(get, set)]
+    pub(crate) broadcast: Option<bool>,
+    #[pyo3(get, set)]
+    pub(crate) source: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) device_name: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) resample: Option<bool>,
+    #[pyo3(get, set)]
+    pub(crate) frame_ms: Option<f32>,
+    #[pyo3(get, set)]
+    pub(crate) bitrate_kbps: Option<BitrateArg>,
+    #[pyo3(get, set)]
+    pub(crate) opus_application: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) bind_addr: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) bind_port: Option<u16>,
+    #[pyo3(get, set)]
+    pub(crate) stats_callback: Option<PyObject>,
+    #[pyo3(get, set)]
+    pub(crate) enable_fec: Option<bool>,
+    #[pyo3(get, set)]
+    pub(crate) packet_loss_perc: Option<u8>,
+    #[pyo3(get, set)]
+    pub(crate) enable_dtx: Option<bool>,
+    #[pyo3(get, set)]
+    pub(crate) multicast_group: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) transport: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) max_payload: Option<usize>,
+    #[pyo3(get, set)]
+    pub(crate) gain: Option<f32>,
+    #[pyo3(get, set)]
+    pub(crate) downmix_mono: Option<bool>,
+    /// Channel count to send. Opus only carries 1 or 2, so more, e.g. 6 for
+    /// 5.1 or 8 for 7.1, always go out raw whatever `use_compression` says.
+    #[pyo3(get, set)]
+    pub(crate) channels: Option<u16>,
+    /// 32-byte key as 64 hex digits or base64, or where to read it from:
+    /// "env:NAME" for an environment variable, "file:PATH" for a file.
+    #[pyo3(get, set)]
+    pub(crate) encryption_key: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) psk: Option<String>,
+    #[pyo3(get, set)]
+    pub(crate) keepalive_ms: Option<u32>,
+    #[pyo3(get, set)]
+    pub(crate) targets: Option<Vec<(String, u16)>>,
+    #[pyo3(get, set)]
+    pub(crate) adaptive_bitrate: Option<bool>,
+    #[pyo3(get, set)]
+    pub(crate) min_kbps: Option<u32>,
+    #[pyo3(get, set)]
+    pub(crate) max_kbps: Option<u32>,
+    #[pyo3(get, set)]
+    pub(crate) host: Option<String>,
+    /// Caps raw packets per second by sending several capture buffers together,
+    /// which delays audio by up to 1/max_packets_per_sec seconds. A cap below
+    /// what the raw bitrate needs at max_payload is rejected.
+    #[pyo3(get, set)]
+    pub(crate) max_packets_per_sec: Option<u32>,
+    #[pyo3(get, set)]
+    pub(crate) duration_secs: Option<u64>,
+    #[pyo3(get, set)]
+    pub(crate) skip_silence: Option<bool>,
+    #[pyo3(get, set)]
+    pub(crate) silence_threshold: Option<f32>,
+    /// Rate of the test tone when `source` is "null".
+    #[pyo3(get, set)]
+    pub(crate) sample_rate: Option<u32>,
+    /// Opus encoder complexity from 0 to 10. Lower values use less CPU, which
+    /// helps on small senders like a Raspberry Pi, at some cost in quality.
+    #[pyo3(get, set)]
+    pub(crate) complexity: Option<i32>,
+    /// Highest audio band Opus may code: "narrow", "medium", "wide",
+    /// "superwide" or "full". Narrow bands keep speech clear at very low bitrates.
+    #[pyo3(get, set)]
+    pub(crate) max_bandwidth: Option<String>,
+    /// Position in `list_audio_devices()` to capture from, instead of `device_name`.
+    /// Only valid until devices change, so look it up again before each start.
+    #[pyo3(get, set)]
+    pub(crate) device_index: Option<usize>,
+    /// Fade-in at start and fade-out at stop in milliseconds, 5 by default; 0 turns it off.
+    #[pyo3(get, set)]
+    pub(crate) fade_ms: Option<f32>,
+    /// Records what an output device plays (`device_name`, or the default output).
+    /// Only WASAPI on Windows supports it; elsewhere the default input is captured.
+    #[pyo3(get, set)]
+    pub(crate) loopback: Option<bool>,
+    /// Spaces packets evenly, one frame apart, instead of sending each capture
+    /// buffer's packets together. Packets dropped when the queue fills are
+    /// counted as `packets_dropped` in stats.
+    #[pyo3(get, set)]
+    pub(crate) pace: Option<bool>,
+    /// Tells Opus what it's encoding: "voice" favours intelligibility, "music"
+    /// tonal fidelity, and "auto" (the default) lets the encoder decide.
+    #[pyo3(get, set)]
+    pub(crate) signal: Option<String>,
+    /// Path of a WAV file to write the outgoing audio to as well, as it was just
+    /// before encoding. Finalized when the server stops.
+    #[pyo3(get, set)]
+    pub(crate) tee_wav: Option<String>,
+    /// Capture buffer size in frames. Smaller buffers cut latency and send more,
+    /// smaller packets; unset, or a size the device refuses, keeps its default.
+    #[pyo3(get, set)]
+    pub(crate) buffer_frames: Option<u32>,
+    /// Copies of the header sent when the stream starts (default 5).
+    #[pyo3(get, set)]
+    pub(crate) header_repeat: Option<u32>,
+    /// Audio packets between header resends for receivers that join late
+    /// (default 1000). 0 turns the resends off, leaving the header_repeat
+    /// copies at the start, for stable links where receivers latch the
+    /// parameters once; late joiners then only get a header by sending HELLO.
+    #[pyo3(get, set)]
+    pub(crate) header_resend_every: Option<u64>,
+    /// Called with the error message when the capture stream reports an error,
+    /// e.g. because the device was unplugged.
+    #[pyo3(get, set)]
+    pub(crate) on_error: Option<PyObject>,
+    /// Times to try reopening a capture device that disappears mid-stream, with
+    /// growing waits in between (default 5); the server stops once they run
+    /// out, or as soon as the device goes with 0. Each try is passed to `on_error`.
+    #[pyo3(get, set)]
+    pub(crate) reconnect_attempts: Option<u32>,
+    /// Sample format of raw packets: "f32" (the default) or "i16", which halves
+    /// raw bandwidth at 16-bit resolution. Opus streams ignore it.
+    #[pyo3(get, set)]
+    pub(crate) raw_format: Option<String>,
+    /// Capture rate to ask the device for instead of its default, e.g. 48000 so
+    /// Opus needs no resampling. Falls back to the default, with a warning, if
+    /// the device can't run at it.
+    #[pyo3(get, set)]
+    pub(crate) preferred_rate: Option<u32>,
+    /// Socket send buffer (SO_SNDBUF) in bytes. Raise it if high-rate raw streams
+    /// drop packets in the kernel before they reach the wire; the size the OS
+    /// actually granted is logged.
+    #[pyo3(get, set)]
+    pub(crate) send_buffer_bytes: Option<usize>,
+    /// Stamps packets with time since the stream started on a monotonic clock
+    /// instead of the wall clock, so NTP corrections can't make them jump.
+    /// Receivers then report latency relative to the fastest packet seen.
+    #[pyo3(get, set)]
+    pub(crate) use_monotonic_clock: Option<bool>,
+    /// Packets in a row that may fail to send, e.g. on an unreachable network,
+    /// before it's reported to `on_error` (default 50). Transient errors like
+    /// a full socket buffer don't count.
+    #[pyo3(get, set)]
+    pub(crate) max_send_failures: Option<u32>,
+    /// Stops the server once `max_send_failures` is reached instead of only reporting it.
+    #[pyo3(get, set)]
+    pub(crate) stop_on_send_failure: Option<bool>,
+    /// Layout of raw buffers: "interleaved" (the default) or "planar", all of
+    /// one channel before the next. Receivers interleave again for playback;
+    /// Opus streams ignore it.
+    #[pyo3(get, set)]
+    pub(crate) channel_layout: Option<String>,
+    /// Human-readable name sent in the header for receivers to show, e.g.
+    /// "Living room". Names over 64 bytes of UTF-8 are cut short.
+    #[pyo3(get, set)]
+    pub(crate) stream_name: Option<String>,
+    /// Announces the server over mDNS as _syncwave._udp, with its bind port,
+    /// target port and stream_name, for `discover_streams`. Off by default.
+    #[pyo3(get, set)]
+    pub(crate) advertise: Option<bool>,
+    /// Hops multicast packets may cross (0-255). Defaults to 1, which keeps
+    /// them on the local subnet; needs multicast_group.
+    #[pyo3(get, set)]
+    pub(crate) multicast_ttl: Option<u32>,
+    /// Interface multicast is sent through, by name ("eth0") or address.
+    /// Defaults to the one the OS picks; needs multicast_group.
+    #[pyo3(get, set)]
+    pub(crate) multicast_interface: Option<String>,
+    /// Soft-clips audio after gain instead of hard clamping it, for boosting
+    /// quiet or hot sources without harsh distortion. Off by default.
+    #[pyo3(get, set)]
+    pub(crate) limiter: Option<bool>,
+    /// Called as `process_callback(samples, sample_rate, channels)` with each
+    /// captured block as an array.array('f') of interleaved samples (wrap it
+    /// with numpy.frombuffer); the float32 array it returns, of the same
+    /// length, is sent instead. It runs on its own thread behind a short
+    /// queue, which adds a capture buffer or more of latency, and blocks it
+    /// can't keep up with are dropped, so keep it fast.
+    #[pyo3(get, set)]
+    pub(crate) process_callback: Option<PyObject>,
+    /// Stops the server, releasing the device, once capture has stayed below
+    /// silence_threshold for this many seconds; time spent paused doesn't
+    /// count. stop_reason on the handle then reads "idle".
+    #[pyo3(get, set)]
+    pub(crate) max_idle_secs: Option<u64>,
+    /// Opus frames sent in each packet (1-8, default 1). Bundling cuts the
+    /// per-packet overhead and packet rate at low bitrates and small frame_ms,
+    /// but each frame waits for its bundle to fill, adding up to
+    /// (frames_per_packet - 1) * frame_ms of latency, and a lost packet loses
+    /// every frame in it. frame_ms * frames_per_packet can be at most 120 ms.
+    #[pyo3(get, set)]
+    pub(crate) frames_per_packet: Option<u8>,
+    /// Rate to encode Opus at (8000, 12000, 16000, 24000 or 48000), whatever
+    /// the device captures at; audio is resampled to it and the header
+    /// announces it, so receivers decode at the same rate. E.g. 16000 for
+    /// voice. Defaults to the capture rate, or 48000 when Opus can't run at
+    /// that. Only applies with use_compression.
+    #[pyo3(get, set)]
+    pub(crate) opus_rate: Option<u32>,
+    /// Milliseconds of audio sent as silence when a capture device starts,
+    /// 30 by default, covering the partial or zeroed buffers some devices
+    /// deliver at first that would otherwise pop. 0 sends every sample.
+    #[pyo3(get, set)]
+    pub(crate) warmup_ms: Option<u32>,
+}
+
+#[pymethods]
+impl ServerConfig {
+    #[new]
+    #[pyo3(signature = (
+        target_ip, target_port, *, use_compression=None, broadcast=None, source=None, device_name=None, resample=None,
+        frame_ms=None, bitrate_kbps=None, opus_application=None, bind_addr=None, bind_port=None, stats_callback=None,
+        enable_fec=None, packet_loss_perc=None, enable_dtx=None, multicast_group=None, transport=None, max_payload=None,
+        gain=None, downmix_mono=None, channels=None, encryption_key=None, psk=None, keepalive_ms=None, targets=None,
+        adaptive_bitrate=None, min_kbps=None, max_kbps=None, host=None, max_packets_per_sec=None,
+        duration_secs=None, skip_silence=None, silence_threshold=None,
+        sample_rate=None, complexity=None, max_bandwidth=None, device_index=None, fade_ms=None, loopback=None, pace=None, signal=None, tee_wav=None, buffer_frames=None,
+        header_repeat=None, header_resend_every=None, on_error=None, reconnect_attempts=None, raw_format=None, preferred_rate=None, send_buffer_bytes=None, use_monotonic_clock=None,
+        max_send_failures=None, stop_on_send_failure=None, channel_layout=None, stream_name=None, advertise=None, multicast_ttl=None,
+        multicast_interface=None, limiter=None, process_callback=None, max_idle_secs=None,
+        frames_per_packet=None, opus_rate=None, warmup_ms=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        target_ip: String,
+        target_port: u16,
+        use_compression: Option<bool>,
+        broadcast: Option<bool>,
+        source: Option<String>,
+        device_name: Option<String>,
+        resample: Option<bool>,
+        frame_ms: Option<f32>,
+        bitrate_kbps: Option<BitrateArg>,
+        opus_application: Option<String>,
+        bind_addr: Option<String>,
+        bind_port: Option<u16>,
+        stats_callback: Option<PyObject>,
+        enable_fec: Option<bool>,
+        packet_loss_perc: Option<u8>,
+        enable_dtx: Option<bool>,
+        multicast_group: Option<String>,
+        transport: Option<String>,
+        max_payload: Option<usize>,
+        gain: Option<f32>,
+        downmix_mono: Option<bool>,
+        channels: Option<u16>,
+        encryption_key: Option<String>,
+        psk: Option<String>,
+        keepalive_ms: Option<u32>,
+        targets: Option<Vec<(String, u16)>>,
+        adaptive_bitrate: Option<bool>,
+        min_kbps: Option<u32>,
+        max_kbps: Option<u32>,
+        host: Option<String>,
+        max_packets_per_sec: Option<u32>,
+        duration_secs: Option<u64>,
+        skip_silence: Option<bool>,
+        silence_threshold: Option<f32>,
+        sample_rate: Option<u32>,
+        complexity: Option<i32>,
+        max_bandwidth: Option<String>,
+        device_index: Option<usize>,
+        fade_ms: Option<f32>,
+        loopback: Option<bool>,
+        pace: Option<bool>,
+        signal: Option<String>,
+        tee_wav: Option<String>,
+        buffer_frames: Option<u32>,
+        header_repeat: Option<u32>,
+        header_resend_every: Option<u64>,
+        on_error: Option<PyObject>,
+        reconnect_attempts: Option<u32>,
+        raw_format: Option<String>,
+        preferred_rate: Option<u32>,
+        send_buffer_bytes: Option<usize>,
+        use_monotonic_clock: Option<bool>,
+        max_send_failures: Option<u32>,
+        stop_on_send_failure: Option<bool>,
+        channel_layout: Option<String>,
+        stream_name: Option<String>,
+        advertise: Option<bool>,
+        multicast_ttl: Option<u32>,
+        multicast_interface: Option<String>,
+        limiter: Option<bool>,
+        process_callback: Option<PyObject>,
+        max_idle_secs: Option<u64>,
+        frames_per_packet: Option<u8>,
+        opus_rate: Option<u32>,
+        warmup_ms: Option<u32>,
+    ) -> Self {
+        ServerConfig {
+            target_ip,
+            target_port,
+            use_compression,
+            broadcast,
+            source,
+            device_name,
+            resample,
+            frame_ms,
+            bitrate_kbps,
+            opus_application,
+            bind_addr,
+            bind_port,
+            stats_callback,
+            enable_fec,
+            packet_loss_perc,
+            enable_dtx,
+            multicast_group,
+            transport,
+            max_payload,
+            gain,
+            downmix_mono,
+            channels,
+            encryption_key,
+            psk,
+            keepalive_ms,
+            targets,
+            adaptive_bitrate,
+            min_kbps,
+            max_kbps,
+            host,
+            max_packets_per_sec,
+            duration_secs,
+            skip_silence,
+            silence_threshold,
+            sample_rate,
+            complexity,
+            max_bandwidth,
+            device_index,
+            fade_ms,
+            loopback,
+            pace,
+            signal,
+            tee_wav,
+            buffer_frames,
+            header_repeat,
+            header_resend_every,
+            on_error,
+            reconnect_attempts,
+            raw_format,
+            preferred_rate,
+            send_buffer_bytes,
+            use_monotonic_clock,
+            max_send_failures,
+            stop_on_send_failure,
+            channel_layout,
+            stream_name,
+            advertise,
+            multicast_ttl,
+            multicast_interface,
+            limiter,
+            process_callback,
+            max_idle_secs,
+            frames_per_packet,
+            opus_rate,
+            warmup_ms,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        // Leaves the key and psk out so configs can be logged safely
+        format!("ServerConfig(target_ip={:?}, target_port={}, use_compression={:?}, transport={:?})", self.target_ip, self.target_port, self.use_compression, self.transport)
+    }
+}
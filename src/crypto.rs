@@ -0,0 +1,132 @@
+use pyo3::prelude::*;
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::error::config_error;
+
+// Encrypted packets carry [NONCE(12)][CIPHERTEXT][TAG(16)] as their DATA. The
+// packet's TYPE, SEQUENCE, FRAG_INDEX and FRAG_COUNT are bound in as associated
+// data, so they can't be changed without the packet failing to decrypt.
+//
+// This protects the audio from being read on the network. It doesn't prove who
+// the sender is: anyone holding the key can produce valid packets.
+const NONCE_LEN: usize = 12;
+
+/// Bytes encryption adds to each packet's payload.
+pub(crate) const ENCRYPTION_OVERHEAD: usize = NONCE_LEN + 16;
+
+/// Parses a 32-byte AES-256 key given as 64 hex digits or as base64.
+pub(crate) fn parse_key(key: &str) -> Option<[u8; 32]> {
+    let key = key.trim();
+    let bytes = hex::decode(key).or_else(|_| base64::engine::general_purpose::STANDARD.decode(key)).ok()?;
+    bytes.try_into().ok()
+}
+
+/// Parses `encryption_key` from Python into a cipher, without echoing the key in errors.
+///
+/// Besides the key itself it takes "env:NAME", the key in environment variable
+/// NAME, or "file:PATH", a file holding the key as hex or base64 text or as 32
+/// raw bytes.
+pub(crate) fn cipher_from_key(key: &str) -> PyResult<PacketCipher> {
+    let malformed = |source: &str| config_error(format!("encryption_key{} must be 32 bytes given as 64 hex digits or base64", source));
+    let key = if let Some(name) = key.strip_prefix("env:") {
+        let value = std::env::var(name).map_err(|e| config_error(format!("encryption_key environment variable {} unusable: {}", name, e)))?;
+        parse_key(&value).ok_or_else(|| malformed(&format!(" from ${}", name)))?
+    } else if let Some(path) = key.strip_prefix("file:") {
+        let contents = std::fs::read(path).map_err(|e| config_error(format!("encryption_key file {} unreadable: {}", path, e)))?;
+        let parsed = std::str::from_utf8(&contents).ok().and_then(parse_key);
+        parsed.or_else(|| contents.try_into().ok()).ok_or_else(|| config_error(format!("encryption_key in {} must be 32 raw bytes, 64 hex digits or base64", path)))?
+    } else {
+        parse_key(key).ok_or_else(|| malformed(""))?
+    };
+    Ok(PacketCipher::new(&key))
+}
+
+/// AES-256-GCM over packet payloads with a key shared by sender and receiver.
+#[derive(Clone)]
+pub(crate) struct PacketCipher {
+    cipher: Aes256Gcm,
+}
+
+impl PacketCipher {
+    pub(crate) fn new(key: &[u8; 32]) -> Self {
+        PacketCipher { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)) }
+    }
+
+    /// Encrypts `payload` under a fresh random nonce, which is prepended to the result.
+    pub(crate) fn encrypt(&self, associated: &[u8], payload: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        // Encryption only fails for payloads far beyond any datagram size
+        if let Ok(ciphertext) = self.cipher.encrypt(&nonce, Payload { msg: payload, aad: associated }) {
+            sealed.extend_from_slice(&ciphertext);
+        }
+        sealed
+    }
+
+    /// Decrypts a nonce-prefixed payload, or returns None if it was tampered with
+    /// or sealed under another key.
+    pub(crate) fn decrypt(&self, associated: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < ENCRYPTION_OVERHEAD {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: associated }).ok()
+    }
+}
+
+/// Bytes of HMAC-SHA256 kept at the end of authenticated datagrams.
+pub(crate) const MAC_LEN: usize = 16;
+
+/// Signs and checks datagrams with HMAC-SHA256 under a pre-shared key.
+///
+/// Authenticated packets end in the MAC where they would otherwise carry the
+/// CRC32, since the MAC already catches corruption; headers get the MAC
+/// appended. This stops hosts without the key from injecting audio or stream
+/// parameters, but a captured packet can still be replayed.
+#[derive(Clone)]
+pub(crate) struct PacketAuth {
+    mac: Hmac<Sha256>,
+}
+
+impl PacketAuth {
+    fn new(psk: &[u8]) -> Self {
+        PacketAuth { mac: <Hmac<Sha256> as Mac>::new_from_slice(psk).expect("HMAC accepts any key length") }
+    }
+
+    /// Returns the MAC to append to `data`.
+    pub(crate) fn sign(&self, data: &[u8]) -> [u8; MAC_LEN] {
+        let mut mac = self.mac.clone();
+        mac.update(data);
+        let mut tag = [0u8; MAC_LEN];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+        tag
+    }
+
+    /// Checks the trailing MAC and returns the datagram without it, or None if forged.
+    pub(crate) fn verify<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        let body_len = data.len().checked_sub(MAC_LEN)?;
+        let (body, tag) = data.split_at(body_len);
+        let mut mac = self.mac.clone();
+        mac.update(body);
+        mac.verify_truncated_left(tag).ok().map(|()| body)
+    }
+}
+
+/// Parses `psk` from Python; any non-empty string works as the shared secret.
+pub(crate) fn auth_from_psk(psk: &str) -> PyResult<PacketAuth> {
+    if psk.is_empty() {
+        return Err(config_error("psk must not be empty"));
+    }
+    Ok(PacketAuth::new(psk.as_bytes()))
+}
+
+/// Packet fields bound into the encryption of its payload.
+pub(crate) fn associated_data(packet_type: u8, session_id: u32, sequence: u32, fragment_index: u8, fragment_count: u8) -> [u8; 11] {
+    let sequence = sequence.to_le_bytes();
+    let session = session_id.to_le_bytes();
+    [packet_type, sequence[0], sequence[1], sequence[2], sequence[3], fragment_index, fragment_count, session[0], session[1], session[2], session[3]]
+}
@@ -0,0 +1,243 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use cpal::traits::{DeviceTrait, HostTrait};
+
+use crate::error::{config_error, device_error};
+
+/// Lists every audio device of `host` as `(name, direction)` where direction is
+/// "input" or "output", followed by each audio host available here as `(name, "host")`.
+/// A device's position in the list is its `device_index` for the server.
+#[pyfunction]
+pub(crate) fn list_audio_devices(host: Option<String>) -> PyResult<Vec<(String, String)>> {
+    let host = select_host(host.as_deref());
+    let mut devices: Vec<(String, String)> = enumerate_devices(&host)?
+        .into_iter()
+        .map(|(_, name, direction)| (name, direction.to_string()))
+        .collect();
+    devices.extend(host_names().into_iter().map(|name| (name.to_string(), "host".to_string())));
+
+    Ok(devices)
+}
+
+/// Lists the config ranges the device called `device_name` supports as an "input"
+/// or "output", each as a dict of min_sample_rate, max_sample_rate, channels and
+/// sample_format (e.g. "f32").
+#[pyfunction]
+pub(crate) fn device_supported_configs(py: Python, device_name: String, direction: String, host: Option<String>) -> PyResult<Vec<PyObject>> {
+    let host = select_host(host.as_deref());
+    let ranges: Vec<cpal::SupportedStreamConfigRange> = match direction.as_str() {
+        "input" => {
+            let device = host.input_devices().ok().and_then(|mut devices| devices.find(|d| d.name().is_ok_and(|n| n == device_name)));
+            let device = device.ok_or_else(|| device_not_found(&device_name))?;
+            device.supported_input_configs().map_err(|e| device_error(format!("Listing configs failed: {}", e)))?.collect()
+        }
+        "output" => {
+            let device = host.output_devices().ok().and_then(|mut devices| devices.find(|d| d.name().is_ok_and(|n| n == device_name)));
+            let device = device.ok_or_else(|| device_not_found(&device_name))?;
+            device.supported_output_configs().map_err(|e| device_error(format!("Listing configs failed: {}", e)))?.collect()
+        }
+        other => return Err(config_error(format!("Unknown direction '{}' (use \"input\" or \"output\")", other))),
+    };
+
+    ranges
+        .iter()
+        .map(|range| {
+            let config = PyDict::new(py);
+            config.set_item("min_sample_rate", range.min_sample_rate().0)?;
+            config.set_item("max_sample_rate", range.max_sample_rate().0)?;
+            config.set_item("channels", range.channels())?;
+            config.set_item("sample_format", range.sample_format().to_string())?;
+            Ok(config.into())
+        })
+        .collect()
+}
+
+/// Opens the audio host called `name` (e.g. "ALSA", "JACK", "WASAPI"), falling
+/// back to the default host when none is given or it can't be used here.
+pub(crate) fn select_host(name: Option<&str>) -> cpal::Host {
+    let Some(name) = name else { return cpal::default_host() };
+    let Some(id) = cpal::available_hosts().into_iter().find(|id| id.name().eq_ignore_ascii_case(name)) else {
+        warn!("Audio host '{}' not available (available: {}), using the default", name, host_names().join(", "));
+        return cpal::default_host();
+    };
+    match cpal::host_from_id(id) {
+        Ok(host) => host,
+        Err(e) => {
+            warn!("Audio host '{}' failed to open ({}), using the default", name, e);
+            cpal::default_host()
+        }
+    }
+}
+
+fn host_names() -> Vec<&'static str> {
+    cpal::available_hosts().iter().map(|id| id.name()).collect()
+}
+
+// Inputs then outputs, in the order list_audio_devices reports them
+fn enumerate_devices(host: &cpal::Host) -> PyResult<Vec<(cpal::Device, String, &'static str)>> {
+    let mut devices = Vec::new();
+    let inputs = host.input_devices().map_err(|e| device_error(format!("Listing input devices failed: {}", e)))?;
+    collect_named(inputs, "input", &mut devices);
+    let outputs = host.output_devices().map_err(|e| device_error(format!("Listing output devices failed: {}", e)))?;
+    collect_named(outputs, "output", &mut devices);
+    Ok(devices)
+}
+
+fn collect_named(devices: impl Iterator<Item = cpal::Device>, direction: &'static str, out: &mut Vec<(cpal::Device, String, &'static str)>) {
+    for device in devices {
+        match device.name() {
+            Ok(name) => out.push((device, name, direction)),
+            // One broken device shouldn't hide the rest
+            Err(e) => warn!("Skipping {} device without a name: {}", direction, e),
+        }
+    }
+}
+
+/// Resolves the capture device. `source` is "output" (loopback of the default
+/// output, the default), "input" (default microphone) or a device name.
+/// `device_name` picks a device by name, searching only the direction given
+/// by `source` when that is "input" or "output".
+pub(crate) fn select_device(host: &cpal::Host, source: Option<&str>, device_name: Option<&str>) -> PyResult<(cpal::Device, cpal::SupportedStreamConfig)> {
+    if let Some(name) = device_name {
+        return match source {
+            Some("input") => find_input(host, name)?,
+            Some("output") => find_output(host, name)?,
+            _ => find_device(host, name)?,
+        }
+        .ok_or_else(|| device_not_found(name));
+    }
+
+    match source.unwrap_or("output") {
+        "output" => {
+            let device = host.default_output_device().ok_or_else(|| device_error("No output device found"))?;
+            let config = device.default_output_config().map_err(default_config_error)?;
+            Ok((device, config))
+        }
+        "input" => {
+            let device = host.default_input_device().ok_or_else(|| device_error("No input device found"))?;
+            let config = device.default_input_config().map_err(default_config_error)?;
+            Ok((device, config))
+        }
+        name => find_device(host, name)?.ok_or_else(|| device_not_found(name)),
+    }
+}
+
+/// Resolves the capture device at position `index` of `list_audio_devices`,
+/// capturing from it as an input or as a loopback of its output depending on
+/// which it is listed as. Indices are only stable within one enumeration:
+/// plugging in or removing a device can shift them.
+pub(crate) fn select_device_by_index(host: &cpal::Host, index: usize) -> PyResult<(cpal::Device, cpal::SupportedStreamConfig)> {
+    let mut devices = enumerate_devices(host)?;
+    let count = devices.len();
+    if index >= count {
+        return Err(device_error(format!("Device index {} out of range ({} devices listed)", index, count)));
+    }
+    let (device, name, direction) = devices.swap_remove(index);
+    info!("Device index {} is {} device '{}'", index, direction, name);
+    let config = match direction {
+        "input" => device.default_input_config(),
+        _ => device.default_output_config(),
+    }
+    .map_err(default_config_error)?;
+    Ok((device, config))
+}
+
+// Loopback capture, i.e. recording what a device plays, by platform:
+//   Windows, WASAPI: supported. cpal opens an output device handed to
+//     build_input_stream in loopback mode, so no other API is involved
+//   Windows, ASIO: not supported
+//   macOS, CoreAudio: not supported; route through a virtual device such as
+//     BlackHole and pick it by device_name
+//   Linux, ALSA and JACK: not supported; capture the PulseAudio or PipeWire
+//     "Monitor of ..." source by device_name instead
+fn supports_loopback(host: &cpal::Host) -> bool {
+    #[cfg(target_os = "windows")]
+    {
+        host.id() == cpal::HostId::Wasapi
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = host;
+        false
+    }
+}
+
+/// Resolves the playback device: the output called `device_name`, or the
+/// default output.
+pub(crate) fn select_output_device(host: &cpal::Host, device_name: Option<&str>) -> PyResult<cpal::Device> {
+    let Some(name) = device_name else {
+        return host.default_output_device().ok_or_else(|| device_error("No output device found"));
+    };
+    let outputs = host.output_devices().map_err(|e| device_error(format!("Listing output devices failed: {}", e)))?;
+    let mut names = Vec::new();
+    collect_named(outputs, "output", &mut names);
+    match names.iter().position(|(_, found, _)| found == name) {
+        Some(index) => Ok(names.swap_remove(index).0),
+        None => {
+            let available: Vec<&str> = names.iter().map(|(_, found, _)| found.as_str()).collect();
+            Err(device_error(format!("Output device '{}' not found (available: {})", name, available.join(", "))))
+        }
+    }
+}
+
+/// Resolves an output device to capture in loopback mode: the one called
+/// `device_name`, or the default output. Where the host can't do loopback this
+/// warns and captures the default input instead.
+pub(crate) fn select_loopback_device(host: &cpal::Host, device_name: Option<&str>) -> PyResult<(cpal::Device, cpal::SupportedStreamConfig)> {
+    if !supports_loopback(host) {
+        warn!("Loopback capture needs WASAPI on Windows, not available on {}; capturing the default input instead", host.id().name());
+        return select_device(host, Some("input"), None);
+    }
+    info!("Capturing output in loopback mode");
+    select_device(host, Some("output"), device_name)
+}
+
+/// Returns true if `device` can capture `channels` channels in `sample_format` at
+/// `sample_rate`, either as an input or as a loopback of its output.
+pub(crate) fn supports_channels(device: &cpal::Device, channels: u16, sample_rate: u32, sample_format: cpal::SampleFormat) -> bool {
+    let matches = |range: cpal::SupportedStreamConfigRange| {
+        range.channels() == channels
+            && range.sample_format() == sample_format
+            && (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&sample_rate)
+    };
+    device.supported_input_configs().is_ok_and(|mut ranges| ranges.any(matches))
+        || device.supported_output_configs().is_ok_and(|mut ranges| ranges.any(matches))
+}
+
+fn find_device(host: &cpal::Host, name: &str) -> PyResult<Option<(cpal::Device, cpal::SupportedStreamConfig)>> {
+    // Prefer real inputs, then fall back to outputs for loopback capture
+    match find_input(host, name)? {
+        Some(found) => Ok(Some(found)),
+        None => find_output(host, name),
+    }
+}
+
+fn find_input(host: &cpal::Host, name: &str) -> PyResult<Option<(cpal::Device, cpal::SupportedStreamConfig)>> {
+    let Ok(mut devices) = host.input_devices() else { return Ok(None) };
+    match devices.find(|d| d.name().is_ok_and(|n| n == name)) {
+        Some(device) => {
+            let config = device.default_input_config().map_err(default_config_error)?;
+            Ok(Some((device, config)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn find_output(host: &cpal::Host, name: &str) -> PyResult<Option<(cpal::Device, cpal::SupportedStreamConfig)>> {
+    let Ok(mut devices) = host.output_devices() else { return Ok(None) };
+    match devices.find(|d| d.name().is_ok_and(|n| n == name)) {
+        Some(device) => {
+            let config = device.default_output_config().map_err(default_config_error)?;
+            Ok(Some((device, config)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn default_config_error(e: cpal::DefaultStreamConfigError) -> PyErr {
+    device_error(format!("Config failed: {}", e))
+}
+
+fn device_not_found(name: &str) -> PyErr {
+    device_error(format!("Audio device '{}' not found", name))
+}
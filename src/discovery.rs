@@ -0,0 +1,125 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::error::{config_error, socket_error};
+use crate::protocol::PROTOCOL_VERSION;
+
+// Senders announce themselves under this type; the port is the one they send
+// from, which is also where HELLOs and loss reports reach them
+const SERVICE_TYPE: &str = "_syncwave._udp.local.";
+const DEFAULT_DISCOVERY_SECS: f64 = 3.0;
+// How long a stopping server waits for its goodbye to go out
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A server announced over mDNS until dropped.
+pub(crate) struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Announces a sender on `bind_port` streaming to `target_port`, on every
+    /// interface, with `stream_name` when it has one.
+    pub(crate) fn start(session_id: u32, bind_port: u16, target_port: u16, stream_name: Option<&str>) -> PyResult<Self> {
+        let daemon = ServiceDaemon::new().map_err(|e| socket_error(format!("mDNS start failed: {}", e)))?;
+        // The SESSION keeps instances apart when several servers share a host
+        let instance = format!("syncwave-{:08x}", session_id);
+        let mut properties = HashMap::from([("version".to_string(), PROTOCOL_VERSION.to_string()), ("target_port".to_string(), target_port.to_string())]);
+        if let Some(name) = stream_name {
+            properties.insert("stream_name".to_string(), name.to_string());
+        }
+        let info = ServiceInfo::new(SERVICE_TYPE, &instance, &format!("{}.local.", instance), "", bind_port, properties)
+            .map_err(|e| socket_error(format!("mDNS service invalid: {}", e)))?
+            .enable_addr_auto();
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info).map_err(|e| socket_error(format!("mDNS register failed: {}", e)))?;
+        Ok(Advertisement { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Ok(status) = self.daemon.unregister(&self.fullname) {
+            let _ = status.recv_timeout(UNREGISTER_TIMEOUT);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// A sender found while browsing.
+struct FoundStream {
+    name: String,
+    host: String,
+    addresses: Vec<String>,
+    port: u16,
+    target_port: Option<u16>,
+    stream_name: Option<String>,
+    version: Option<u8>,
+}
+
+impl FoundStream {
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("name", &self.name)?;
+        dict.set_item("host", &self.host)?;
+        dict.set_item("addresses", &self.addresses)?;
+        dict.set_item("port", self.port)?;
+        dict.set_item("target_port", self.target_port)?;
+        dict.set_item("stream_name", &self.stream_name)?;
+        dict.set_item("version", self.version)?;
+        Ok(dict)
+    }
+}
+
+/// Browses the LAN for servers started with advertise=True for `timeout_secs`
+/// (3 by default) and returns a dict per sender: name (the mDNS instance),
+/// host, addresses, port (where it sends from), target_port, stream_name and
+/// the protocol version it speaks.
+#[pyfunction]
+pub(crate) fn discover_streams(py: Python, timeout_secs: Option<f64>) -> PyResult<Vec<PyObject>> {
+    let timeout_secs = timeout_secs.unwrap_or(DEFAULT_DISCOVERY_SECS);
+    if !timeout_secs.is_finite() || timeout_secs <= 0.0 {
+        return Err(config_error(format!("timeout_secs {} must be a positive number", timeout_secs)));
+    }
+    let found = py.allow_threads(|| browse(Duration::from_secs_f64(timeout_secs)))?;
+    found.iter().map(|stream| stream.to_dict(py).map(Into::into)).collect()
+}
+
+fn browse(timeout: Duration) -> PyResult<Vec<FoundStream>> {
+    let daemon = ServiceDaemon::new().map_err(|e| socket_error(format!("mDNS start failed: {}", e)))?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(|e| socket_error(format!("mDNS browse failed: {}", e)))?;
+    // Keyed by instance so a sender answering more than once is listed once
+    let mut found: HashMap<String, FoundStream> = HashMap::new();
+    let deadline = Instant::now() + timeout;
+    while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+        let Ok(event) = events.recv_timeout(wait) else { break };
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                let name = info.get_fullname().trim_end_matches(SERVICE_TYPE).trim_end_matches('.').to_string();
+                let mut addresses: Vec<String> = info.get_addresses().iter().map(|addr| addr.to_string()).collect();
+                addresses.sort();
+                found.insert(name.clone(), FoundStream {
+                    name,
+                    host: info.get_hostname().to_string(),
+                    addresses,
+                    port: info.get_port(),
+                    target_port: info.get_property_val_str("target_port").and_then(|port| port.parse().ok()),
+                    stream_name: info.get_property_val_str("stream_name").map(str::to_string),
+                    version: info.get_property_val_str("version").and_then(|version| version.parse().ok()),
+                });
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                found.remove(fullname.trim_end_matches(SERVICE_TYPE).trim_end_matches('.'));
+            }
+            _ => {}
+        }
+    }
+    let _ = daemon.stop_browse(SERVICE_TYPE);
+    let _ = daemon.shutdown();
+    let mut found: Vec<FoundStream> = found.into_values().collect();
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
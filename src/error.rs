@@ -0,0 +1,55 @@
+// pyo3 0.20's #[new] expansion trips this lint on newer compilers
+#![allow(non_local_definitions)]
+
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+/// Raised by syncwave_core when streaming fails. `kind` says which part failed:
+/// "socket", "device", "codec" or "config"; `message` is the readable detail.
+#[pyclass(extends = PyException)]
+pub(crate) struct SyncwaveError {
+    #[pyo3(get)]
+    kind: String,
+    #[pyo3(get)]
+    message: String,
+}
+
+#[pymethods]
+impl SyncwaveError {
+    #[new]
+    fn new(message: String, kind: String) -> Self {
+        SyncwaveError { kind, message }
+    }
+
+    fn __str__(&self) -> String {
+        self.message.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("SyncwaveError({:?}, kind={:?})", self.message, self.kind)
+    }
+}
+
+fn syncwave_error(kind: &str, message: impl Into<String>) -> PyErr {
+    PyErr::new::<SyncwaveError, _>((message.into(), kind.to_string()))
+}
+
+/// Binding, connecting, sending or receiving on the network failed.
+pub(crate) fn socket_error(message: impl Into<String>) -> PyErr {
+    syncwave_error("socket", message)
+}
+
+/// An audio device was missing or couldn't be opened or started.
+pub(crate) fn device_error(message: impl Into<String>) -> PyErr {
+    syncwave_error("device", message)
+}
+
+/// The Opus encoder or decoder couldn't be set up for the stream.
+pub(crate) fn codec_error(message: impl Into<String>) -> PyErr {
+    syncwave_error("codec", message)
+}
+
+/// An argument was invalid or conflicts with another.
+pub(crate) fn config_error(message: impl Into<String>) -> PyErr {
+    syncwave_error("config", message)
+}
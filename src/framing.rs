@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+
+/// Accumulates interleaved samples from capture callbacks and hands them out as
+/// fixed-size encoder frames. Backed by a ring buffer, so taking a frame costs
+/// O(frame_len) no matter how much is still queued behind it.
+pub(crate) struct FrameBuffer {
+    samples: VecDeque<f32>,
+    frame: Vec<f32>,
+}
+
+impl FrameBuffer {
+    pub(crate) fn new(frame_len: usize) -> Self {
+        FrameBuffer {
+            samples: VecDeque::with_capacity(frame_len * 4),
+            frame: vec![0.0; frame_len],
+        }
+    }
+
+    pub(crate) fn push(&mut self, data: &[f32]) {
+        self.samples.extend(data);
+    }
+
+    /// Returns the next complete frame, or None until enough samples arrive.
+    pub(crate) fn next_frame(&mut self) -> Option<&[f32]> {
+        let frame_len = self.frame.len();
+        if self.samples.len() < frame_len {
+            return None;
+        }
+        for (dst, src) in self.frame.iter_mut().zip(self.samples.drain(..frame_len)) {
+            *dst = src;
+        }
+        Some(&self.frame)
+    }
+
+    /// Returns whatever is left as one frame padded with silence, emptying the buffer.
+    pub(crate) fn finish_frame(&mut self) -> &[f32] {
+        self.frame.fill(0.0);
+        for (dst, src) in self.frame.iter_mut().zip(self.samples.drain(..)) {
+            *dst = src;
+        }
+        &self.frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_span_callback_boundaries() {
+        let mut buffer = FrameBuffer::new(4);
+        let mut frames = Vec::new();
+        let mut next = 0.0;
+
+        // Callback sizes that never line up with the frame size
+        for size in [3, 7, 1, 5, 2] {
+            let data: Vec<f32> = (0..size).map(|i| next + i as f32).collect();
+            next += size as f32;
+            buffer.push(&data);
+            while let Some(frame) = buffer.next_frame() {
+                frames.push(frame.to_vec());
+            }
+        }
+
+        // 18 samples in: four full frames out, two samples left waiting
+        assert_eq!(frames.len(), 4);
+        let flat: Vec<f32> = frames.concat();
+        let expected: Vec<f32> = (0..16).map(|i| i as f32).collect();
+        assert_eq!(flat, expected);
+        assert!(buffer.next_frame().is_none());
+
+        buffer.push(&[18.0, 19.0]);
+        assert_eq!(buffer.next_frame(), Some(&[16.0, 17.0, 18.0, 19.0][..]));
+    }
+}
@@ -0,0 +1,138 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::crypto::MAC_LEN;
+use crate::protocol::{parse_header, verify_crc, StreamName, CRC_LEN, HEADER_LEN, HEADER_MAGIC, PACKET_FLAG_LAST, PACKET_PREFIX_LEN, PACKET_TYPE_EOS, PACKET_TYPE_HELLO, PACKET_TYPE_KEEPALIVE, PACKET_TYPE_LOSS_REPORT, PACKET_TYPE_OPUS, PACKET_TYPE_OPUS_BUNDLE, PACKET_TYPE_PING, PACKET_TYPE_RAW};
+
+/// One datagram found in a dump, or the point where the dump stopped making sense.
+#[derive(Default)]
+struct Entry {
+    offset: usize,
+    packet_type: String,
+    last: bool,
+    sequence: Option<u32>,
+    timestamp_us: Option<u64>,
+    fragment_index: Option<u8>,
+    fragment_count: Option<u8>,
+    session_id: Option<u32>,
+    // Only headers carry one
+    stream_name: Option<StreamName>,
+    // DATA bytes the SIZE field announces, and how many the dump holds
+    declared_length: Option<usize>,
+    actual_length: usize,
+    // None for headers, and for MAC trailers, which need the psk to check
+    crc_ok: Option<bool>,
+    error: Option<String>,
+}
+
+impl Entry {
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("offset", self.offset)?;
+        dict.set_item("packet_type", &self.packet_type)?;
+        dict.set_item("last", self.last)?;
+        dict.set_item("sequence", self.sequence)?;
+        dict.set_item("timestamp_us", self.timestamp_us)?;
+        dict.set_item("fragment_index", self.fragment_index)?;
+        dict.set_item("fragment_count", self.fragment_count)?;
+        dict.set_item("session_id", self.session_id)?;
+        dict.set_item("stream_name", self.stream_name.as_ref().map(StreamName::as_str))?;
+        dict.set_item("declared_length", self.declared_length)?;
+        dict.set_item("actual_length", self.actual_length)?;
+        dict.set_item("crc_ok", self.crc_ok)?;
+        dict.set_item("error", &self.error)?;
+        Ok(dict)
+    }
+}
+
+fn type_name(packet_type: u8) -> Option<&'static str> {
+    match packet_type {
+        PACKET_TYPE_RAW => Some("raw"),
+        PACKET_TYPE_OPUS => Some("opus"),
+        PACKET_TYPE_OPUS_BUNDLE => Some("opus_bundle"),
+        PACKET_TYPE_KEEPALIVE => Some("keepalive"),
+        PACKET_TYPE_LOSS_REPORT => Some("loss_report"),
+        PACKET_TYPE_EOS => Some("eos"),
+        PACKET_TYPE_HELLO => Some("hello"),
+        PACKET_TYPE_PING => Some("ping"),
+        _ => None,
+    }
+}
+
+/// Parses a dump of SYNC datagrams written back to back, e.g. by a packet tap.
+///
+/// Returns a dict per datagram with offset, packet_type ("header", "raw",
+/// "opus", "opus_bundle", "keepalive", "loss_report", "eos", "hello" or "ping"), last, sequence,
+/// timestamp_us, fragment_index, fragment_count, session_id, stream_name (headers
+/// only), declared_length (from the SIZE field), actual_length (DATA bytes
+/// present), crc_ok and error. Nothing in the
+/// dump marks where a datagram ends but its own SIZE, so a truncated or
+/// unrecognisable datagram is reported with an error and ends the list.
+#[pyfunction]
+pub(crate) fn inspect_packets(py: Python, data: &[u8]) -> PyResult<Vec<PyObject>> {
+    parse_dump(data).iter().map(|entry| entry.to_dict(py).map(Into::into)).collect()
+}
+
+fn parse_dump(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    // Datagrams after an authenticated header end in a MAC instead of the CRC
+    let mut trailer_len = CRC_LEN;
+
+    while offset < data.len() {
+        let rest = &data[offset..];
+        if rest.starts_with(HEADER_MAGIC) {
+            let header = parse_header(rest);
+            let len = header.map_or(HEADER_LEN, |header| header.encoded_len()) + if header.is_some_and(|header| header.authenticated) { MAC_LEN } else { 0 };
+            let mut entry = Entry { offset, packet_type: "header".to_string(), session_id: header.map(|header| header.session_id), stream_name: header.and_then(|header| header.name), declared_length: Some(len), actual_length: rest.len().min(len), ..Entry::default() };
+            if header.is_none() || rest.len() < len {
+                entry.error = Some(format!("Truncated header: {} of {} bytes", rest.len(), len));
+                entries.push(entry);
+                break;
+            }
+            trailer_len = if header.is_some_and(|header| header.authenticated) { MAC_LEN } else { CRC_LEN };
+            entries.push(entry);
+            offset += len;
+            continue;
+        }
+
+        let mut entry = Entry { offset, actual_length: rest.len(), ..Entry::default() };
+        if rest.len() < PACKET_PREFIX_LEN {
+            entry.packet_type = "unknown".to_string();
+            entry.error = Some(format!("Truncated packet: {} of {} prefix bytes", rest.len(), PACKET_PREFIX_LEN));
+            entries.push(entry);
+            break;
+        }
+        let Some(name) = type_name(rest[0] & !PACKET_FLAG_LAST) else {
+            entry.packet_type = "unknown".to_string();
+            entry.error = Some(format!("Unknown packet type {}", rest[0] & !PACKET_FLAG_LAST));
+            entries.push(entry);
+            break;
+        };
+        let declared = u16::from_le_bytes([rest[19], rest[20]]) as usize;
+        entry.packet_type = name.to_string();
+        entry.last = rest[0] & PACKET_FLAG_LAST != 0;
+        entry.sequence = Some(u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]));
+        entry.timestamp_us = Some(u64::from_le_bytes(rest[5..13].try_into().unwrap_or_default()));
+        entry.fragment_index = Some(rest[13]);
+        entry.fragment_count = Some(rest[14]);
+        entry.session_id = Some(u32::from_le_bytes([rest[15], rest[16], rest[17], rest[18]]));
+        entry.declared_length = Some(declared);
+        entry.actual_length = (rest.len() - PACKET_PREFIX_LEN).min(declared);
+
+        let len = PACKET_PREFIX_LEN + declared + trailer_len;
+        if rest.len() < len {
+            entry.error = Some(format!("Truncated packet: {} of {} bytes", rest.len(), len));
+            entries.push(entry);
+            break;
+        }
+        entry.crc_ok = (trailer_len == CRC_LEN).then(|| verify_crc(&rest[..len]).is_some());
+        // The size was still sound, so later datagrams can be read past this one
+        if rest[14] == 0 || rest[13] >= rest[14] {
+            entry.error = Some(format!("Fragment index {} out of range for {} fragments", rest[13], rest[14]));
+        }
+        entries.push(entry);
+        offset += len;
+    }
+    entries
+}
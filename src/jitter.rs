@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::protocol::AudioPacket;
+
+// A packet this far behind the playback position means the sender restarted
+const RESTART_DISTANCE: u64 = 500;
+
+/// An audio packet held by the jitter buffer.
+pub(crate) struct BufferedPacket {
+    pub packet_type: u8,
+    pub data: Vec<u8>,
+    arrived: Instant,
+}
+
+/// What the jitter buffer releases next.
+pub(crate) enum Released {
+    Packet(BufferedPacket),
+    // This many packets never arrived in time and should be played as silence
+    Lost(u64),
+}
+
+/// Holds packets for a fixed delay so late and reordered ones can be put back
+/// in sequence order before playback.
+pub(crate) struct JitterBuffer {
+    delay: Duration,
+    // Keyed by sequence number extended past u32 wraparound
+    packets: BTreeMap<u64, BufferedPacket>,
+    next_sequence: Option<u64>,
+    last_sequence: u64,
+}
+
+impl JitterBuffer {
+    pub(crate) fn new(delay_ms: u32) -> Self {
+        JitterBuffer {
+            delay: Duration::from_millis(delay_ms as u64),
+            packets: BTreeMap::new(),
+            next_sequence: None,
+            last_sequence: 0,
+        }
+    }
+
+    /// Queues a packet that arrived at `now`. Duplicates and packets whose slot
+    /// has already been played or given up on are dropped.
+    pub(crate) fn push(&mut self, packet: &AudioPacket, now: Instant) {
+        let mut sequence = self.extend_sequence(packet.sequence);
+        if let Some(next) = self.next_sequence.filter(|&next| sequence < next) {
+            if next - sequence < RESTART_DISTANCE {
+                return;
+            }
+            self.clear();
+            sequence = self.extend_sequence(packet.sequence);
+        }
+        self.packets.entry(sequence).or_insert_with(|| BufferedPacket {
+            packet_type: packet.packet_type,
+            data: packet.data.to_vec(),
+            arrived: now,
+        });
+    }
+
+    /// Releases the oldest packet once it has been held for the full delay,
+    /// reporting any missing packets before it as lost.
+    pub(crate) fn pop(&mut self, now: Instant) -> Option<Released> {
+        let entry = self.packets.first_entry()?;
+        if now.duration_since(entry.get().arrived) < self.delay {
+            return None;
+        }
+        self.flush()
+    }
+
+    /// Releases the oldest packet without waiting out the delay, e.g. to play
+    /// out the rest once the stream has ended.
+    pub(crate) fn flush(&mut self) -> Option<Released> {
+        let entry = self.packets.first_entry()?;
+        let sequence = *entry.key();
+        if let Some(next) = self.next_sequence.filter(|&next| sequence > next) {
+            self.next_sequence = Some(sequence);
+            return Some(Released::Lost(sequence - next));
+        }
+        self.next_sequence = Some(sequence + 1);
+        Some(Released::Packet(entry.remove()))
+    }
+
+    /// Forgets everything, e.g. when the sender restarts with a new header.
+    pub(crate) fn clear(&mut self) {
+        self.packets.clear();
+        self.next_sequence = None;
+        self.last_sequence = 0;
+    }
+
+    // Picks the 64-bit sequence closest to the last one seen that has these low 32 bits
+    fn extend_sequence(&mut self, sequence: u32) -> u64 {
+        if self.packets.is_empty() && self.next_sequence.is_none() {
+            self.last_sequence = sequence as u64;
+            return self.last_sequence;
+        }
+        let delta = sequence.wrapping_sub(self.last_sequence as u32) as i32 as i64;
+        let extended = (self.last_sequence as i64 + delta).max(0) as u64;
+        self.last_sequence = self.last_sequence.max(extended);
+        extended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test packet carries its own sequence number as payload
+    fn push(buffer: &mut JitterBuffer, sequence: u32, now: Instant) {
+        let data = sequence.to_le_bytes();
+        buffer.push(&AudioPacket { packet_type: 0, last: false, sequence, timestamp_us: 0, fragment_index: 0, fragment_count: 1, session_id: 0, data: &data }, now);
+    }
+
+    fn drain(buffer: &mut JitterBuffer, now: Instant) -> Vec<String> {
+        std::iter::from_fn(|| buffer.pop(now))
+            .map(|released| match released {
+                Released::Packet(packet) => format!("packet {}", u32::from_le_bytes(packet.data.try_into().unwrap())),
+                Released::Lost(count) => format!("lost {}", count),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn out_of_order_packets_are_released_in_sequence() {
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new(40);
+        for sequence in [10, 12, 11, 14, 13] {
+            push(&mut buffer, sequence, start);
+        }
+
+        // Nothing comes out until the packets have been held for the delay
+        assert!(buffer.pop(start + Duration::from_millis(39)).is_none());
+        assert_eq!(drain(&mut buffer, start + Duration::from_millis(40)), ["packet 10", "packet 11", "packet 12", "packet 13", "packet 14"]);
+    }
+
+    #[test]
+    fn gaps_are_reported_as_lost_and_late_packets_dropped() {
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new(0);
+        push(&mut buffer, 1, start);
+        push(&mut buffer, 4, start);
+        assert_eq!(drain(&mut buffer, start), ["packet 1", "lost 2", "packet 4"]);
+
+        // Packet 2 shows up after its slot was played as silence
+        push(&mut buffer, 2, start);
+        push(&mut buffer, 5, start);
+        assert_eq!(drain(&mut buffer, start), ["packet 5"]);
+
+        // A sender that restarts from 0 is followed rather than treated as late
+        push(&mut buffer, 9000, start);
+        assert_eq!(drain(&mut buffer, start), ["lost 8994", "packet 9000"]);
+        push(&mut buffer, 0, start);
+        assert_eq!(drain(&mut buffer, start), ["packet 0"]);
+    }
+
+    #[test]
+    fn sequence_wraparound_keeps_order() {
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new(0);
+        for sequence in [u32::MAX, 0, u32::MAX - 1] {
+            push(&mut buffer, sequence, start);
+        }
+        assert_eq!(drain(&mut buffer, start), [format!("packet {}", u32::MAX - 1), format!("packet {}", u32::MAX), "packet 0".to_string()]);
+    }
+}
@@ -0,0 +1,14 @@
+// Log macros that route everything through the "syncwave" logger, so Python can
+// configure it with logging.getLogger("syncwave") regardless of the Rust module.
+
+macro_rules! info {
+    ($($arg:tt)+) => { log::info!(target: "syncwave", $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log::warn!(target: "syncwave", $($arg)+) };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log::error!(target: "syncwave", $($arg)+) };
+}
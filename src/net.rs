@@ -0,0 +1,132 @@
+use pyo3::prelude::*;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use socket2::SockRef;
+
+use crate::error::{config_error, socket_error};
+
+/// Returns true when `ip` is an IPv6 literal, bracketed or not.
+fn is_ipv6(ip: &str) -> bool {
+    ip.trim_start_matches('[').trim_end_matches(']').parse::<Ipv6Addr>().is_ok()
+}
+
+/// Formats `ip` and `port` as a socket address string, bracketing IPv6 literals.
+pub(crate) fn socket_address(ip: &str, port: u16) -> String {
+    if is_ipv6(ip) {
+        format!("[{}]:{}", ip.trim_start_matches('[').trim_end_matches(']'), port)
+    } else {
+        format!("{}:{}", ip, port)
+    }
+}
+
+/// Local address for sending to `target_ip`.
+///
+/// Without `bind_addr` the wildcard address of the target's family is used, and
+/// without `bind_port` an ephemeral port.
+pub(crate) fn local_address(bind_addr: Option<&str>, bind_port: Option<u16>, target_ip: &str) -> String {
+    let bind_ip = bind_addr.unwrap_or(if is_ipv6(target_ip) { "::" } else { "0.0.0.0" });
+    socket_address(bind_ip, bind_port.unwrap_or(0))
+}
+
+/// Binds the local socket for sending to `target_ip`; see `local_address` for the defaults.
+pub(crate) fn bind_sender_socket(bind_addr: Option<&str>, bind_port: Option<u16>, target_ip: &str) -> PyResult<UdpSocket> {
+    let local_addr = local_address(bind_addr, bind_port, target_ip);
+    UdpSocket::bind(&local_addr).map_err(|e| match e.kind() {
+        ErrorKind::AddrInUse => socket_error(format!("Socket bind failed: {} is already in use", local_addr)),
+        _ => socket_error(format!("Socket bind failed on {}: {}", local_addr, e)),
+    })
+}
+
+/// Asks the OS for a `bytes` send buffer (SO_SNDBUF) and returns the size it
+/// granted, which may be clamped to a system limit or, on Linux, doubled.
+pub(crate) fn set_send_buffer(socket: SockRef, bytes: usize) -> PyResult<usize> {
+    socket.set_send_buffer_size(bytes).map_err(|e| socket_error(format!("Setting the send buffer to {} bytes failed: {}", bytes, e)))?;
+    socket.send_buffer_size().map_err(|e| socket_error(format!("Reading the send buffer size failed: {}", e)))
+}
+
+/// Multicast stays on the local link unless the caller allows more hops.
+pub(crate) const DEFAULT_MULTICAST_TTL: u32 = 1;
+
+/// Parses `group` as an IPv4 (224.0.0.0/4) or IPv6 (ff00::/8) multicast address.
+pub(crate) fn parse_multicast_group(group: &str) -> Option<IpAddr> {
+    let addr = group.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok()?;
+    addr.is_multicast().then_some(addr)
+}
+
+/// Sets the TTL (hop limit for IPv6) and loopback options for sending to a
+/// multicast `group`, and sends through `interface` when given: an interface
+/// name such as "eth0" or one of its addresses.
+pub(crate) fn configure_multicast_sender(socket: &UdpSocket, group: IpAddr, ttl: u32, interface: Option<&str>) -> PyResult<()> {
+    let interface = interface.map(|interface| find_interface(interface, group.is_ipv6())).transpose()?;
+    let socket = SockRef::from(socket);
+    let result = match group {
+        IpAddr::V4(_) => socket.set_multicast_ttl_v4(ttl).and_then(|()| socket.set_multicast_loop_v4(true)).and_then(|()| match interface.as_ref().map(|interface| interface.ip()) {
+            Some(IpAddr::V4(addr)) => socket.set_multicast_if_v4(&addr),
+            _ => Ok(()),
+        }),
+        IpAddr::V6(_) => socket.set_multicast_hops_v6(ttl).and_then(|()| socket.set_multicast_loop_v6(true)).and_then(|()| match interface.as_ref().and_then(|interface| interface.index) {
+            Some(index) => socket.set_multicast_if_v6(index),
+            None => Ok(()),
+        }),
+    };
+    result.map_err(|e| socket_error(format!("Multicast setup failed: {}", e)))?;
+    if let Some(interface) = interface {
+        info!("Sending multicast through {} ({})", interface.name, interface.ip());
+    }
+    Ok(())
+}
+
+// Looks `interface` up by name or address among this host's interfaces, taking
+// the first address of the group's family
+fn find_interface(interface: &str, ipv6: bool) -> PyResult<if_addrs::Interface> {
+    let interfaces = if_addrs::get_if_addrs().map_err(|e| socket_error(format!("Listing network interfaces failed: {}", e)))?;
+    let wanted = interface.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok();
+    interfaces
+        .into_iter()
+        .filter(|candidate| candidate.ip().is_ipv6() == ipv6)
+        .find(|candidate| candidate.name == interface || wanted == Some(candidate.ip()))
+        .ok_or_else(|| config_error(format!("multicast_interface '{}' has no {} address here", interface, if ipv6 { "IPv6" } else { "IPv4" })))
+}
+
+/// Subscribes a receiving socket to the multicast `group` on the default interface.
+pub(crate) fn join_multicast_group(socket: &UdpSocket, group: IpAddr) -> PyResult<()> {
+    let result = match group {
+        IpAddr::V4(group) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(group) => socket.join_multicast_v6(&group, 0),
+    };
+    result.map_err(|e| socket_error(format!("Joining multicast group {} failed: {}", group, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn ipv6_targets_are_bracketed() {
+        let target = socket_address("::1", 5555);
+        assert_eq!(target, "[::1]:5555");
+        assert!(target.parse::<SocketAddr>().unwrap().is_ipv6());
+        assert_eq!(socket_address("[fe80::1]", 5555), "[fe80::1]:5555");
+        assert_eq!(local_address(None, None, "::1"), "[::]:0");
+    }
+
+    #[test]
+    fn ipv4_and_hostnames_are_unchanged() {
+        assert_eq!(socket_address("192.168.1.20", 5555), "192.168.1.20:5555");
+        assert_eq!(socket_address("localhost", 5555), "localhost:5555");
+        assert_eq!(local_address(None, None, "192.168.1.20"), "0.0.0.0:0");
+    }
+
+    #[test]
+    fn multicast_interfaces_resolve_by_address_or_fail() {
+        assert_eq!(find_interface("127.0.0.1", false).unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(find_interface("no-such-interface0", false).is_err());
+    }
+
+    #[test]
+    fn explicit_bind_address_and_port_are_used() {
+        assert_eq!(local_address(Some("192.168.1.5"), Some(6000), "192.168.1.20"), "192.168.1.5:6000");
+        assert_eq!(local_address(None, Some(6000), "::1"), "[::]:6000");
+    }
+}
@@ -0,0 +1,112 @@
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::codec::frame_samples;
+use crate::sender::PacketSender;
+
+/// `source` value that streams a test tone instead of capturing from a device.
+pub(crate) const NULL_SOURCE: &str = "null";
+/// Test tone format unless the caller picks another.
+pub(crate) const NULL_DEFAULT_RATE: u32 = 48000;
+pub(crate) const NULL_DEFAULT_CHANNELS: u16 = 2;
+
+// Handed to the sender in buffers about the size a capture device delivers
+const BUFFER_MS: f32 = 10.0;
+const TONE_HZ: f32 = 440.0;
+const AMPLITUDE: f32 = 0.5;
+
+/// Deterministic 440 Hz sine, the same in every channel.
+pub(crate) struct TestTone {
+    sample_rate: u32,
+    channels: usize,
+    // Frames generated so far, so buffers join up without a click
+    position: u64,
+}
+
+impl TestTone {
+    pub(crate) fn new(sample_rate: u32, channels: u16) -> Self {
+        TestTone { sample_rate, channels: channels as usize, position: 0 }
+    }
+
+    /// Appends the next `frames` frames of interleaved samples to `out`.
+    pub(crate) fn fill(&mut self, frames: usize, out: &mut Vec<f32>) {
+        for _ in 0..frames {
+            // Wrap once per second so the phase stays precise on long runs
+            let offset = (self.position % self.sample_rate as u64) as f32;
+            let sample = AMPLITUDE * (TAU * TONE_HZ * offset / self.sample_rate as f32).sin();
+            out.extend(std::iter::repeat_n(sample, self.channels));
+            self.position += 1;
+        }
+    }
+}
+
+/// Stands in for a capture device, feeding a test tone into `sender` in real
+/// time from its own thread. Dropping it stops the thread.
+pub(crate) struct NullSource {
+    paused: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullSource {
+    pub(crate) fn start(sample_rate: u32, channels: u16, sender: Arc<Mutex<PacketSender>>) -> Self {
+        let paused = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(true));
+        let (paused_clone, running_clone) = (paused.clone(), running.clone());
+
+        let thread = thread::spawn(move || {
+            let mut tone = TestTone::new(sample_rate, channels);
+            let frames = frame_samples(sample_rate, BUFFER_MS);
+            let buffer_duration = Duration::from_secs_f64(BUFFER_MS as f64 / 1000.0);
+            let mut buffer = Vec::with_capacity(frames * channels as usize);
+            let mut next_send = Instant::now();
+
+            while running_clone.load(Ordering::SeqCst) {
+                // Checked under the lock so nothing goes out once pause() has returned
+                // and the sender has been taken, e.g. to finish the stream
+                let sent = match sender.lock() {
+                    Ok(mut sender) if !paused_clone.load(Ordering::SeqCst) => {
+                        buffer.clear();
+                        tone.fill(frames, &mut buffer);
+                        sender.send_samples(&buffer);
+                        true
+                    }
+                    _ => false,
+                };
+                // Like a paused device, deliver nothing and pick up from now on resume
+                if !sent {
+                    thread::sleep(buffer_duration);
+                    next_send = Instant::now();
+                    continue;
+                }
+
+                next_send += buffer_duration;
+                if let Some(wait) = next_send.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+        });
+
+        NullSource { paused, running, thread: Some(thread) }
+    }
+
+    pub(crate) fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn play(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for NullSource {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
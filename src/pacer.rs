@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::sender::SenderStats;
+use crate::transport::Transport;
+
+// Datagrams held before the oldest is dropped: about two seconds of 20ms frames
+const QUEUE_LIMIT: usize = 100;
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+struct Queue {
+    datagrams: Mutex<VecDeque<(Vec<u8>, Duration)>>,
+    ready: Condvar,
+    running: AtomicBool,
+}
+
+/// Sends datagrams from its own thread, spaced by the audio each one carries,
+/// so a capture callback's worth of packets doesn't leave as one burst.
+pub(crate) struct Pacer {
+    queue: Arc<Queue>,
+    stats: Arc<SenderStats>,
+    frame_duration: Duration,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Pacer {
+    /// Starts the sending thread on `transport`, a clone of the stream's own.
+    /// `frame_duration` is how long one Opus packet plays for.
+    pub(crate) fn start(transport: Transport, stats: Arc<SenderStats>, frame_duration: Duration) -> Self {
+        let queue = Arc::new(Queue { datagrams: Mutex::new(VecDeque::new()), ready: Condvar::new(), running: AtomicBool::new(true) });
+        let (thread_queue, thread_stats) = (queue.clone(), stats.clone());
+
+        let thread = thread::spawn(move || {
+            let mut next_send = Instant::now();
+            loop {
+                let (datagram, audio) = {
+                    let mut datagrams = thread_queue.datagrams.lock().unwrap();
+                    loop {
+                        if let Some(next) = datagrams.pop_front() {
+                            break next;
+                        }
+                        // Only stop once everything queued before stopping is out
+                        if !thread_queue.running.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        datagrams = thread_queue.ready.wait_timeout(datagrams, IDLE_WAIT).unwrap().0;
+                    }
+                };
+
+                // Never run ahead of schedule, but don't save up time while idle
+                // or behind either, as that would come out as a burst later
+                let now = Instant::now();
+                match next_send.checked_duration_since(now) {
+                    Some(wait) => thread::sleep(wait),
+                    None => next_send = now,
+                }
+                thread_stats.send_counted(&transport, &datagram);
+                next_send += audio;
+            }
+        });
+
+        Pacer { queue, stats, frame_duration, thread: Some(thread) }
+    }
+
+    pub(crate) fn frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    /// Queues `datagram`, which carries `audio` worth of playback; the next one
+    /// goes out that long after it. Drops the oldest datagram when full.
+    pub(crate) fn push(&self, datagram: Vec<u8>, audio: Duration) {
+        let mut datagrams = self.queue.datagrams.lock().unwrap();
+        if datagrams.len() >= QUEUE_LIMIT {
+            datagrams.pop_front();
+            self.stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        datagrams.push_back((datagram, audio));
+        self.queue.ready.notify_one();
+    }
+
+    /// Sends everything still queued, at the usual pace, and stops the thread.
+    pub(crate) fn finish(&mut self) {
+        self.queue.running.store(false, Ordering::SeqCst);
+        self.queue.ready.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Pacer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,138 @@
+use pyo3::prelude::*;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{config_error, socket_error};
+use crate::net::{bind_sender_socket, socket_address};
+use crate::recorder::RecordingHandle;
+
+// Start of every packet log, followed by its format version
+const LOG_MAGIC: &[u8; 4] = b"SWPL";
+const LOG_VERSION: u8 = 1;
+// How often the recording thread checks for stop requests without traffic
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// How often a replay checks for Ctrl+C
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Writes every datagram arriving on `bind_ip:bind_port` to `output_path`, as
+/// is, until the handle is stopped or `duration_secs` is up. Each record is the
+/// microseconds since recording started (u64 LE), the length (u32 LE) and the
+/// datagram itself, after a "SWPL" magic and version byte.
+#[pyfunction]
+pub(crate) fn record_raw_packets(bind_ip: String, bind_port: u16, output_path: String, duration_secs: Option<u64>) -> PyResult<RecordingHandle> {
+    let bind_addr = socket_address(&bind_ip, bind_port);
+    let socket = UdpSocket::bind(&bind_addr).map_err(|e| socket_error(format!("Socket bind failed on {}: {}", bind_addr, e)))?;
+    socket.set_read_timeout(Some(STOP_CHECK_INTERVAL)).map_err(|e| socket_error(format!("Setting the read timeout failed: {}", e)))?;
+    // Open the file up front so a bad path is reported to the caller, not the thread
+    let file = File::create(&output_path).map_err(|e| PyErr::new::<pyo3::exceptions::PyOSError, _>(format!("Failed to create {}: {}", output_path, e)))?;
+    info!("Recording packets from {} to {}", bind_addr, output_path);
+
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let running = Arc::new(AtomicBool::new(true));
+    let running_clone = running.clone();
+
+    let thread = thread::spawn(move || {
+        let mut writer = BufWriter::new(file);
+        let mut packets = 0u64;
+        let deadline = duration_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+        // Stop on an explicit stop() or when the handle is dropped
+        let stopped = || !matches!(stop_rx.try_recv(), Err(TryRecvError::Empty)) || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        match record_packets(&socket, &mut writer, &mut packets, stopped).and_then(|()| writer.flush()) {
+            Ok(()) => info!("Packet recording finished: {} packets written to {}", packets, output_path),
+            Err(e) => error!("Packet recording to {} failed after {} packets: {}", output_path, packets, e),
+        }
+        running_clone.store(false, Ordering::SeqCst);
+    });
+
+    Ok(RecordingHandle::new(stop_tx, thread, running))
+}
+
+/// Sends the datagrams in a log written by `record_raw_packets` to
+/// `target_ip:target_port`, byte for byte, and returns how many went out.
+/// With `realtime` they keep their recorded spacing; otherwise they go out as
+/// fast as possible.
+#[pyfunction]
+pub(crate) fn replay_raw_packets(py: Python, file_path: String, target_ip: String, target_port: u16, realtime: bool) -> PyResult<u64> {
+    let file = File::open(&file_path).map_err(|e| PyErr::new::<pyo3::exceptions::PyOSError, _>(format!("Failed to open {}: {}", file_path, e)))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 5];
+    reader.read_exact(&mut magic).map_err(|_| config_error(format!("{} is not a packet log", file_path)))?;
+    if magic[..4] != LOG_MAGIC[..] {
+        return Err(config_error(format!("{} is not a packet log", file_path)));
+    }
+    if magic[4] != LOG_VERSION {
+        return Err(config_error(format!("{} is packet log version {}, expected {}", file_path, magic[4], LOG_VERSION)));
+    }
+    let socket = bind_sender_socket(None, None, &target_ip)?;
+    let target_addr = socket_address(&target_ip, target_port);
+    info!("Replaying {} to {}", file_path, target_addr);
+
+    py.allow_threads(move || {
+        let started = Instant::now();
+        let mut last_signal_check = Instant::now();
+        let mut packets = 0u64;
+        let mut datagram = Vec::new();
+        while let Some(elapsed_us) = read_record(&mut reader, &mut datagram).map_err(|e| config_error(format!("{} is cut short or corrupt after {} packets: {}", file_path, packets, e)))? {
+            if last_signal_check.elapsed() >= SIGNAL_CHECK_INTERVAL {
+                Python::with_gil(|py| py.check_signals())?;
+                last_signal_check = Instant::now();
+            }
+            if realtime {
+                if let Some(wait) = (started + Duration::from_micros(elapsed_us)).checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+            socket.send_to(&datagram, &target_addr).map_err(|e| socket_error(format!("Send failed: {}", e)))?;
+            packets += 1;
+        }
+        info!("Replay finished: {} packets sent", packets);
+        Ok(packets)
+    })
+}
+
+// Writes the log's preamble, then every datagram until `stopped` says so
+fn record_packets(socket: &UdpSocket, writer: &mut impl Write, packets: &mut u64, stopped: impl Fn() -> bool) -> io::Result<()> {
+    writer.write_all(LOG_MAGIC)?;
+    writer.write_all(&[LOG_VERSION])?;
+    let started = Instant::now();
+    let mut buf = vec![0u8; 65536];
+    while !stopped() {
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        write_record(writer, started.elapsed().as_micros() as u64, &buf[..len])?;
+        *packets += 1;
+    }
+    Ok(())
+}
+
+fn write_record(writer: &mut impl Write, elapsed_us: u64, datagram: &[u8]) -> io::Result<()> {
+    writer.write_all(&elapsed_us.to_le_bytes())?;
+    writer.write_all(&(datagram.len() as u32).to_le_bytes())?;
+    writer.write_all(datagram)
+}
+
+// Reads the next record into `datagram` and returns its time, or None at a
+// clean end of the file
+fn read_record(reader: &mut impl Read, datagram: &mut Vec<u8>) -> io::Result<Option<u64>> {
+    let mut prefix = [0u8; 12];
+    match reader.read_exact(&mut prefix[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut prefix[1..])?;
+    let elapsed_us = u64::from_le_bytes(prefix[0..8].try_into().unwrap());
+    let len = u32::from_le_bytes(prefix[8..12].try_into().unwrap()) as usize;
+    datagram.resize(len, 0);
+    reader.read_exact(datagram)?;
+    Ok(Some(elapsed_us))
+}
@@ -0,0 +1,71 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use crate::adaptive::verified_packet;
+use crate::crypto::auth_from_psk;
+use crate::error::{config_error, socket_error};
+use crate::net::{bind_sender_socket, socket_address};
+use crate::protocol::{build_packet, get_timestamp_us, parse_header, PACKET_TYPE_HELLO};
+
+const DEFAULT_PING_TIMEOUT_MS: u32 = 1000;
+
+/// Checks whether something is listening at `target_ip:target_port` by sending
+/// it a HELLO and waiting up to `timeout_ms` (1000 by default) for an answer.
+///
+/// Only a cooperating peer answers: a receiver from `start_audio_client` or
+/// `record_stream_to_wav` replies with a HELLO of its own, and a server with its
+/// header. A mistyped address, a port nothing is bound to, a firewall and a
+/// receiver still on an older build all look the same: no answer. Give `psk`
+/// when the receiver was started with one, or it ignores the probe. TCP
+/// receivers can't be probed.
+///
+/// Returns a dict with reachable, rtt_ms (None without an answer) and
+/// responder ("receiver", "sender" or None).
+#[pyfunction]
+pub(crate) fn ping_target(py: Python, target_ip: String, target_port: u16, timeout_ms: Option<u32>, psk: Option<String>) -> PyResult<PyObject> {
+    let timeout_ms = timeout_ms.unwrap_or(DEFAULT_PING_TIMEOUT_MS);
+    if timeout_ms == 0 {
+        return Err(config_error("timeout_ms must be at least 1"));
+    }
+    let auth = psk.map(|psk| auth_from_psk(&psk)).transpose()?;
+    let socket = bind_sender_socket(None, None, &target_ip)?;
+    let target_addr = socket_address(&target_ip, target_port);
+
+    let answer = py.allow_threads(move || -> PyResult<Option<(Duration, &'static str)>> {
+        let sent = Instant::now();
+        socket.send_to(&build_packet(PACKET_TYPE_HELLO, 0, 0, get_timestamp_us(), 0, 1, &[], auth.as_ref()), &target_addr).map_err(|e| socket_error(format!("Send to {} failed: {}", target_addr, e)))?;
+        let deadline = sent + Duration::from_millis(timeout_ms as u64);
+        let mut buf = vec![0u8; 65536];
+        // Anything else arriving, e.g. a stream sent this way, is passed over
+        while let Some(wait) = deadline.checked_duration_since(Instant::now()).filter(|wait| !wait.is_zero()) {
+            socket.set_read_timeout(Some(wait)).map_err(|e| socket_error(format!("Setting the read timeout failed: {}", e)))?;
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                // Some platforms report the ICMP "port unreachable" for nothing being bound there
+                Err(e) if matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused) => break,
+                Err(e) => return Err(socket_error(format!("Socket receive failed: {}", e))),
+            };
+            let datagram = &buf[..len];
+            if parse_header(datagram).is_some() {
+                return Ok(Some((sent.elapsed(), "sender")));
+            }
+            if verified_packet(datagram, auth.as_ref()).is_some_and(|packet| packet.packet_type == PACKET_TYPE_HELLO) {
+                return Ok(Some((sent.elapsed(), "receiver")));
+            }
+        }
+        Ok(None)
+    })?;
+
+    match answer {
+        Some((rtt, responder)) => info!("{}:{} answered as a {} in {:.1} ms", target_ip, target_port, responder, rtt.as_secs_f64() * 1000.0),
+        None => warn!("No answer from {}:{} within {} ms", target_ip, target_port, timeout_ms),
+    }
+    let result = PyDict::new(py);
+    result.set_item("reachable", answer.is_some())?;
+    result.set_item("rtt_ms", answer.map(|(rtt, _)| rtt.as_secs_f64() * 1000.0))?;
+    result.set_item("responder", answer.map(|(_, responder)| responder))?;
+    Ok(result.into())
+}
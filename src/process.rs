@@ -0,0 +1,99 @@
+use pyo3::prelude::*;
+use pyo3::buffer::PyBuffer;
+use pyo3::types::PyBytes;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use crate::error::config_error;
+use crate::protocol::as_u8_slice;
+
+// Blocks waiting on the callback, each way, before capture starts dropping audio
+const PROCESS_QUEUE_BLOCKS: usize = 8;
+
+/// Runs a Python `process_callback` over captured audio on its own thread, so
+/// the capture callback never waits on the GIL. Processed blocks come back a
+/// capture buffer or more later; a callback too slow to keep up loses whole
+/// blocks rather than stalling capture.
+pub(crate) struct ProcessHook {
+    blocks: Option<SyncSender<Vec<f32>>>,
+    processed: Receiver<Vec<f32>>,
+    thread: Option<JoinHandle<()>>,
+    dropped: u64,
+}
+
+impl ProcessHook {
+    /// Calls `callback(samples, sample_rate, channels)` for every block, with
+    /// `samples` an array.array('f') of interleaved samples. The float32 array
+    /// or sequence it returns replaces the block and must be the same length;
+    /// when it isn't, or the callback raises, the block goes out unprocessed.
+    pub(crate) fn start(callback: PyObject, sample_rate: u32, channels: u16) -> Self {
+        let (blocks, received) = mpsc::sync_channel::<Vec<f32>>(PROCESS_QUEUE_BLOCKS);
+        let (results, processed) = mpsc::sync_channel::<Vec<f32>>(PROCESS_QUEUE_BLOCKS);
+
+        let thread = thread::spawn(move || {
+            let mut failed = false;
+            // Ends once the capture side closes its queue or stops taking results
+            for block in received {
+                let block = match Python::with_gil(|py| call_process(py, &callback, &block, sample_rate, channels)) {
+                    Ok(processed) => processed,
+                    Err(e) => {
+                        if !failed {
+                            error!("process_callback failed, sending audio unprocessed: {}", e);
+                            failed = true;
+                        }
+                        block
+                    }
+                };
+                if results.send(block).is_err() {
+                    break;
+                }
+            }
+        });
+
+        ProcessHook { blocks: Some(blocks), processed, thread: Some(thread), dropped: 0 }
+    }
+
+    /// Queues `samples` for the callback without waiting, dropping them if it's behind.
+    pub(crate) fn submit(&mut self, samples: &[f32]) {
+        let Some(blocks) = &self.blocks else { return };
+        if let Err(TrySendError::Full(_)) = blocks.try_send(samples.to_vec()) {
+            if self.dropped == 0 {
+                warn!("process_callback is falling behind capture, dropping audio");
+            }
+            self.dropped += 1;
+        }
+    }
+
+    /// A block the callback has finished with, if one is ready.
+    pub(crate) fn next_processed(&self) -> Option<Vec<f32>> {
+        self.processed.try_recv().ok()
+    }
+
+    /// Waits for the callback to get through everything queued and returns it.
+    pub(crate) fn finish(&mut self) -> Vec<Vec<f32>> {
+        self.blocks.take();
+        let remaining = self.processed.iter().collect();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if self.dropped > 0 {
+            warn!("process_callback fell behind: {} blocks dropped", self.dropped);
+        }
+        remaining
+    }
+}
+
+// Hands one block to the callback and checks what comes back
+fn call_process(py: Python, callback: &PyObject, block: &[f32], sample_rate: u32, channels: u16) -> PyResult<Vec<f32>> {
+    let samples = py.import("array")?.getattr("array")?.call1(("f", PyBytes::new(py, as_u8_slice(block))))?;
+    let returned = callback.call1(py, (samples, sample_rate, channels))?;
+    let returned = returned.as_ref(py);
+    let processed = match PyBuffer::<f32>::get(returned) {
+        Ok(buffer) => buffer.to_vec(py)?,
+        Err(_) => returned.extract::<Vec<f32>>()?,
+    };
+    if processed.len() != block.len() {
+        return Err(config_error(format!("process_callback returned {} samples for a block of {}", processed.len(), block.len())));
+    }
+    Ok(processed)
+}
@@ -0,0 +1,499 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::crc::crc32;
+use crate::crypto::PacketAuth;
+use crate::transport::Transport;
+
+// Wire format, all integers little-endian:
+//   Header: [MAGIC(4)][VERSION(1)][SAMPLE_RATE(4)][CHANNELS(2)][FLAGS(1)][SOURCE_RATE(4)][SESSION(4)][NAME_LEN(1)][NAME(n)]
+//   Packet: [TYPE(1)][SEQUENCE(4)][TIMESTAMP(8)][FRAG_INDEX(1)][FRAG_COUNT(1)][SESSION(4)][SIZE(2)][DATA(n)][CRC32(4)]
+// FLAGS bit 0 means packets carry Opus, bit 1 that their DATA is encrypted and
+// bit 2 that datagrams are authenticated (both in crypto.rs), bit 3 that
+// the sender wants loss reports, bit 4 that raw DATA holds i16 samples
+// instead of f32, bit 5 that TIMESTAMP is on a monotonic clock and bit 6 that
+// raw DATA is planar; the other bits are reserved and sent as 0. Authenticated packets end in an HMAC instead of
+// the CRC32, and authenticated headers have the HMAC appended.
+// SEQUENCE counts audio buffers from 0 and wraps at u32::MAX, so receivers can
+// detect loss and reordering. TIMESTAMP is microseconds since the Unix epoch,
+// or with FLAGS bit 5 microseconds since the sender started on a clock that
+// never jumps, which receivers can only compare between packets.
+// SAMPLE_RATE is the rate of the audio on the wire; SOURCE_RATE is the rate it
+// was captured at, which differs when the sender resampled for Opus.
+// SESSION is picked at random each time a sender starts and repeated in all of
+// its packets, so receivers can tell a restarted stream from the old one and
+// drop stale packets; packets from receivers carry 0. Receivers also skip the
+// SESSIONs of senders in their own process, which multicast or broadcast
+// loopback would otherwise feed back to them.
+// NAME is an optional human-readable stream name in UTF-8, at most
+// MAX_STREAM_NAME_LEN bytes; NAME_LEN is 0 for an unnamed stream.
+// Raw buffers larger than the sender's max payload are split into FRAG_COUNT
+// packets sharing one SEQUENCE, numbered by FRAG_INDEX from 0; everything else
+// is a single packet with FRAG_INDEX 0 and FRAG_COUNT 1. Fragments split on
+// sample-frame boundaries, so each one is also playable on its own, except
+// in planar streams: there the whole buffer holds all of channel 0, then all
+// of channel 1 and so on, and only the reassembled buffer can be played.
+// OPUS_BUNDLE packets carry several Opus frames in a row, from senders set to
+// bundle them, each as [LEN(2)][FRAME(n)] in DATA. The bundle takes a single
+// SEQUENCE, so losing the packet loses every frame in it.
+// KEEPALIVE packets have no audio and carry the next SEQUENCE without using
+// it up, so the audio packet after them has the same one; senders emit them
+// while idle so NAT mappings and receivers know the stream is still up.
+// LOSS_REPORT packets go the other way, from receiver to sender about once a
+// second when the header asks for them, with DATA [EXPECTED(4)][LOST(4)]
+// counted from SEQUENCE gaps; they drive adaptive bitrate (see adaptive.rs).
+// Bit 7 of TYPE marks the last audio packet of a stream that ended cleanly;
+// TYPE is the remaining bits. EOS packets follow it, repeated a few times in
+// case one is lost, with no audio and the SEQUENCE after the last packet's:
+// the sender has stopped and receivers can close instead of waiting.
+// HELLO packets also go from receiver to sender, with no DATA: a receiver that
+// gets audio before any header asks for one, and the sender answers with the
+// header straight away, at most a few times a second in total. ping_target
+// sends one to a receiver the same way, which answers with a HELLO of its own.
+// PING packets estimate the offset between the two ends' wall clocks, as in
+// SNTP: the receiver sends its TIMESTAMP as 8 bytes of DATA, and the sender
+// answers with a PING whose DATA is that timestamp, when the PING arrived and
+// when the answer left, each a u64 in microseconds.
+// CRC32 is the IEEE CRC over every packet byte before it; receivers drop
+// packets that fail it rather than feed corrupt data to the decoder.
+pub(crate) const HEADER_MAGIC: &[u8; 4] = b"SYNC";
+pub(crate) const PROTOCOL_VERSION: u8 = 9;
+pub(crate) const PACKET_TYPE_RAW: u8 = 0;
+pub(crate) const PACKET_TYPE_OPUS: u8 = 1;
+pub(crate) const PACKET_TYPE_KEEPALIVE: u8 = 2;
+pub(crate) const PACKET_TYPE_LOSS_REPORT: u8 = 3;
+pub(crate) const PACKET_TYPE_EOS: u8 = 4;
+pub(crate) const PACKET_TYPE_HELLO: u8 = 5;
+pub(crate) const PACKET_TYPE_PING: u8 = 6;
+pub(crate) const PACKET_TYPE_OPUS_BUNDLE: u8 = 7;
+pub(crate) const PACKET_FLAG_LAST: u8 = 1 << 7;
+
+const FLAG_COMPRESSED: u8 = 1 << 0;
+const FLAG_ENCRYPTED: u8 = 1 << 1;
+const FLAG_AUTHENTICATED: u8 = 1 << 2;
+const FLAG_LOSS_REPORTS: u8 = 1 << 3;
+const FLAG_RAW_I16: u8 = 1 << 4;
+const FLAG_MONOTONIC_CLOCK: u8 = 1 << 5;
+const FLAG_PLANAR: u8 = 1 << 6;
+
+// Up to and including NAME_LEN, so an unnamed header is exactly this long
+pub(crate) const HEADER_LEN: usize = 21;
+pub(crate) const MAX_STREAM_NAME_LEN: usize = 64;
+pub(crate) const PACKET_PREFIX_LEN: usize = 21;
+pub(crate) const CRC_LEN: usize = 4;
+
+/// A sender's human-readable name, held inline so headers stay Copy.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StreamName {
+    len: u8,
+    bytes: [u8; MAX_STREAM_NAME_LEN],
+}
+
+impl StreamName {
+    /// Cuts `name` down to MAX_STREAM_NAME_LEN bytes, on a character boundary.
+    pub(crate) fn new(name: &str) -> Self {
+        let mut len = name.len().min(MAX_STREAM_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0u8; MAX_STREAM_NAME_LEN];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        StreamName { len: len as u8, bytes }
+    }
+
+    // Names from the wire must be UTF-8 and no longer than a sender could write
+    fn from_wire(data: &[u8]) -> Option<Self> {
+        let name = std::str::from_utf8(data).ok()?;
+        (name.len() <= MAX_STREAM_NAME_LEN).then(|| StreamName::new(name))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for StreamName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// Stream parameters announced by the sender in the SYNC header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StreamHeader {
+    pub version: u8,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub compressed: bool,
+    pub encrypted: bool,
+    pub authenticated: bool,
+    pub loss_reports: bool,
+    // Raw packets carry i16 samples rather than f32
+    pub raw_i16: bool,
+    // TIMESTAMP counts from the sender's start rather than the Unix epoch
+    pub monotonic_clock: bool,
+    // Raw buffers are laid out channel by channel instead of interleaved
+    pub planar: bool,
+    pub source_rate: u32,
+    pub session_id: u32,
+    pub name: Option<StreamName>,
+}
+
+impl StreamHeader {
+    /// Bytes per sample in raw packet DATA.
+    pub(crate) fn raw_sample_bytes(&self) -> usize {
+        if self.raw_i16 { 2 } else { 4 }
+    }
+
+    /// True for the header of a restarted sender: a new session with the same parameters.
+    pub(crate) fn restarts(&self, previous: &StreamHeader) -> bool {
+        self.session_id != previous.session_id && *self == StreamHeader { session_id: self.session_id, ..*previous }
+    }
+
+    /// Bytes the header takes on the wire, before any MAC.
+    pub(crate) fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.name.map_or(0, |name| name.len as usize)
+    }
+
+    /// How packet DATA is encoded, for logs.
+    pub(crate) fn encoding_name(&self) -> &'static str {
+        match (self.compressed, self.raw_i16) {
+            (true, _) => "Opus",
+            (false, true) => "Raw i16",
+            (false, false) => "Raw",
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(self.encoded_len());
+        header.extend_from_slice(HEADER_MAGIC);
+        header.push(self.version);
+        header.extend_from_slice(&self.sample_rate.to_le_bytes());
+        header.extend_from_slice(&self.channels.to_le_bytes());
+        let mut flags = 0;
+        if self.compressed {
+            flags |= FLAG_COMPRESSED;
+        }
+        if self.encrypted {
+            flags |= FLAG_ENCRYPTED;
+        }
+        if self.authenticated {
+            flags |= FLAG_AUTHENTICATED;
+        }
+        if self.loss_reports {
+            flags |= FLAG_LOSS_REPORTS;
+        }
+        if self.raw_i16 {
+            flags |= FLAG_RAW_I16;
+        }
+        if self.monotonic_clock {
+            flags |= FLAG_MONOTONIC_CLOCK;
+        }
+        if self.planar {
+            flags |= FLAG_PLANAR;
+        }
+        header.push(flags);
+        header.extend_from_slice(&self.source_rate.to_le_bytes());
+        header.extend_from_slice(&self.session_id.to_le_bytes());
+        let name = self.name.as_ref().map_or("", StreamName::as_str);
+        header.push(name.len() as u8);
+        header.extend_from_slice(name.as_bytes());
+        header
+    }
+}
+
+/// A single audio packet borrowed from a received datagram.
+pub(crate) struct AudioPacket<'a> {
+    pub packet_type: u8,
+    // Set on the final packet before the sender stopped
+    pub last: bool,
+    pub sequence: u32,
+    pub timestamp_us: u64,
+    pub fragment_index: u8,
+    pub fragment_count: u8,
+    pub session_id: u32,
+    pub data: &'a [u8],
+}
+
+pub(crate) fn as_u8_slice(v: &[f32]) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(v.as_ptr() as *const u8, std::mem::size_of_val(v))
+    }
+}
+
+/// Builds the header datagram, signed when a psk is set.
+pub(crate) fn encode_header(header: &StreamHeader, auth: Option<&PacketAuth>) -> Vec<u8> {
+    let mut encoded = header.encode();
+    if let Some(auth) = auth {
+        let tag = auth.sign(&encoded);
+        encoded.extend_from_slice(&tag);
+    }
+    encoded
+}
+
+pub(crate) fn send_header(transport: &Transport, header: &StreamHeader, auth: Option<&PacketAuth>) -> Result<(), std::io::Error> {
+    transport.send(&encode_header(header, auth))?;
+    info!("Sent header: {}Hz, {} channels, compression: {}", header.sample_rate, header.channels, header.encoding_name());
+    Ok(())
+}
+
+// SESSIONs of the senders running in this process
+static LOCAL_SESSIONS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// SESSION of a sender in this process, counted as local until dropped.
+pub(crate) struct LocalSession(u32);
+
+impl LocalSession {
+    /// Picks a random SESSION for a sender that is starting up.
+    pub(crate) fn start() -> Self {
+        let session_id = OsRng.next_u32();
+        LOCAL_SESSIONS.lock().unwrap().push(session_id);
+        LocalSession(session_id)
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+// Another hold on the same SESSION, which stays local until every copy is dropped
+impl Clone for LocalSession {
+    fn clone(&self) -> Self {
+        LOCAL_SESSIONS.lock().unwrap().push(self.0);
+        LocalSession(self.0)
+    }
+}
+
+impl Drop for LocalSession {
+    fn drop(&mut self) {
+        let mut sessions = LOCAL_SESSIONS.lock().unwrap();
+        if let Some(index) = sessions.iter().position(|&session_id| session_id == self.0) {
+            sessions.swap_remove(index);
+        }
+    }
+}
+
+/// Whether a sender in this process is streaming with `session_id`.
+pub(crate) fn is_local_session(session_id: u32) -> bool {
+    LOCAL_SESSIONS.lock().unwrap().contains(&session_id)
+}
+
+/// Source of wall clock timestamps in microseconds since the Unix epoch;
+/// `get_timestamp_us` outside of tests, which can pass a fixed clock instead.
+pub(crate) type Clock = fn() -> u64;
+
+pub(crate) fn get_timestamp_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_packet(packet_type: u8, session_id: u32, sequence: u32, timestamp_us: u64, fragment_index: u8, fragment_count: u8, data: &[u8], auth: Option<&PacketAuth>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PACKET_PREFIX_LEN + data.len() + CRC_LEN);
+    packet.push(packet_type);
+    packet.extend_from_slice(&sequence.to_le_bytes());
+    packet.extend_from_slice(&timestamp_us.to_le_bytes());
+    packet.push(fragment_index);
+    packet.push(fragment_count);
+    packet.extend_from_slice(&session_id.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    packet.extend_from_slice(data);
+    match auth {
+        Some(auth) => {
+            let tag = auth.sign(&packet);
+            packet.extend_from_slice(&tag);
+        }
+        None => packet.extend_from_slice(&crc32(&packet).to_le_bytes()),
+    }
+    packet
+}
+
+/// Checks the trailing CRC32 and returns the packet without it, or None if corrupt.
+pub(crate) fn verify_crc(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < CRC_LEN {
+        return None;
+    }
+    let (body, crc) = data.split_at(data.len() - CRC_LEN);
+    (crc32(body) == u32::from_le_bytes(crc.try_into().ok()?)).then_some(body)
+}
+
+/// Returns the stream header if `data` is a SYNC header datagram.
+pub(crate) fn parse_header(data: &[u8]) -> Option<StreamHeader> {
+    if data.len() < HEADER_LEN || &data[0..4] != HEADER_MAGIC {
+        return None;
+    }
+    // Raw audio can have any number of channels, but not none
+    let channels = u16::from_le_bytes(data[9..11].try_into().ok()?);
+    if channels == 0 {
+        return None;
+    }
+    let name_len = data[HEADER_LEN - 1] as usize;
+    let name = match name_len {
+        0 => None,
+        _ => Some(StreamName::from_wire(data.get(HEADER_LEN..HEADER_LEN + name_len)?)?),
+    };
+    Some(StreamHeader {
+        version: data[4],
+        sample_rate: u32::from_le_bytes(data[5..9].try_into().ok()?),
+        channels,
+        compressed: data[11] & FLAG_COMPRESSED != 0,
+        encrypted: data[11] & FLAG_ENCRYPTED != 0,
+        authenticated: data[11] & FLAG_AUTHENTICATED != 0,
+        loss_reports: data[11] & FLAG_LOSS_REPORTS != 0,
+        raw_i16: data[11] & FLAG_RAW_I16 != 0,
+        monotonic_clock: data[11] & FLAG_MONOTONIC_CLOCK != 0,
+        planar: data[11] & FLAG_PLANAR != 0,
+        source_rate: u32::from_le_bytes(data[12..16].try_into().ok()?),
+        session_id: u32::from_le_bytes(data[16..20].try_into().ok()?),
+        name,
+    })
+}
+
+/// Splits a CRC-verified audio packet into its fields, rejecting truncated packets.
+pub(crate) fn parse_packet(data: &[u8]) -> Option<AudioPacket<'_>> {
+    if data.len() < PACKET_PREFIX_LEN {
+        return None;
+    }
+    let size = u16::from_le_bytes(data[19..21].try_into().ok()?) as usize;
+    let data_end = PACKET_PREFIX_LEN + size;
+    if data.len() < data_end || data[14] == 0 || data[13] >= data[14] {
+        return None;
+    }
+    Some(AudioPacket {
+        packet_type: data[0] & !PACKET_FLAG_LAST,
+        last: data[0] & PACKET_FLAG_LAST != 0,
+        sequence: u32::from_le_bytes(data[1..5].try_into().ok()?),
+        timestamp_us: u64::from_le_bytes(data[5..13].try_into().ok()?),
+        fragment_index: data[13],
+        fragment_count: data[14],
+        session_id: u32::from_le_bytes(data[15..19].try_into().ok()?),
+        data: &data[PACKET_PREFIX_LEN..data_end],
+    })
+}
+
+/// Appends one Opus frame, length first, to the DATA of a bundle.
+pub(crate) fn push_bundled_frame(bundle: &mut Vec<u8>, frame: &[u8]) {
+    bundle.extend_from_slice(&(frame.len() as u16).to_le_bytes());
+    bundle.extend_from_slice(frame);
+}
+
+/// The Opus frames in a bundle's DATA, in order, stopping at a truncated one.
+pub(crate) fn bundled_frames(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let len = u16::from_le_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+        let frame = rest.get(2..2 + len)?;
+        rest = &rest[2 + len..];
+        Some(frame)
+    })
+}
+
+/// Converts little-endian f32 bytes from a raw packet back into samples.
+pub(crate) fn samples_from_bytes(data: &[u8], out: &mut impl Extend<f32>) {
+    out.extend(data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])));
+}
+
+/// Appends `samples` to `out` as little-endian i16, clipping anything outside [-1, 1].
+pub(crate) fn i16_bytes_from_samples(samples: &[f32], out: &mut Vec<u8>) {
+    out.extend(samples.iter().flat_map(|&sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16).to_le_bytes()));
+}
+
+/// Converts little-endian i16 bytes from a raw packet back into samples.
+pub(crate) fn samples_from_i16_bytes(data: &[u8], out: &mut impl Extend<f32>) {
+    out.extend(data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32));
+}
+
+/// Appends interleaved `samples` to `out` one channel after another.
+pub(crate) fn deinterleave(samples: &[f32], channels: usize, out: &mut Vec<f32>) {
+    out.extend((0..channels).flat_map(|channel| samples.iter().skip(channel).step_by(channels).copied()));
+}
+
+/// Appends `planar` samples, laid out one channel after another, to `out` interleaved.
+pub(crate) fn interleave(planar: &[f32], channels: usize, out: &mut impl Extend<f32>) {
+    let frames = planar.len() / channels;
+    out.extend((0..frames * channels).map(|i| planar[(i % channels) * frames + i / channels]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn six_channel_raw_packets_round_trip() {
+        // One 10ms frame of 5.1 at 48kHz, each channel at its own level
+        let samples: Vec<f32> = (0..480 * 6).map(|i| (i % 6) as f32 / 6.0 - 0.5 + (i / 6) as f32 * 1e-4).collect();
+        let packet = build_packet(PACKET_TYPE_RAW, 0xC0FFEE, 42, 1_700_000_000_000_000, 0, 1, as_u8_slice(&samples), None);
+
+        let parsed = parse_packet(verify_crc(&packet).unwrap()).unwrap();
+        assert_eq!(parsed.packet_type, PACKET_TYPE_RAW);
+        assert_eq!(parsed.sequence, 42);
+        assert_eq!(parsed.timestamp_us, 1_700_000_000_000_000);
+        assert_eq!(parsed.session_id, 0xC0FFEE);
+        let mut decoded = Vec::new();
+        samples_from_bytes(parsed.data, &mut decoded);
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn planar_stereo_packets_interleave_back() {
+        // Left and right ramps in opposite directions so a swapped channel shows up
+        let samples: Vec<f32> = (0..480).flat_map(|i| [i as f32 / 480.0, -(i as f32) / 480.0]).collect();
+        let mut planar = Vec::new();
+        deinterleave(&samples, 2, &mut planar);
+        assert_eq!(planar[..480], samples.iter().step_by(2).copied().collect::<Vec<_>>()[..]);
+        let packet = build_packet(PACKET_TYPE_RAW, 0xC0FFEE, 7, 1_700_000_000_000_000, 0, 1, as_u8_slice(&planar), None);
+
+        let parsed = parse_packet(verify_crc(&packet).unwrap()).unwrap();
+        let mut received = Vec::new();
+        samples_from_bytes(parsed.data, &mut received);
+        let mut decoded = Vec::new();
+        interleave(&received, 2, &mut decoded);
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn packets_are_laid_out_byte_for_byte() {
+        let packet = build_packet(PACKET_TYPE_OPUS, 0x11223344, 0x01020304, 0x0102030405060708, 2, 3, &[0xAA, 0xBB], None);
+        let prefix = [
+            PACKET_TYPE_OPUS,
+            0x04, 0x03, 0x02, 0x01,
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+            2,
+            3,
+            0x44, 0x33, 0x22, 0x11,
+            0x02, 0x00,
+        ];
+        assert_eq!(prefix.len(), PACKET_PREFIX_LEN);
+        assert_eq!(packet[..PACKET_PREFIX_LEN], prefix);
+        assert_eq!(packet[PACKET_PREFIX_LEN..PACKET_PREFIX_LEN + 2], [0xAA, 0xBB]);
+        assert_eq!(packet[PACKET_PREFIX_LEN + 2..], crc32(&packet[..PACKET_PREFIX_LEN + 2]).to_le_bytes());
+    }
+
+    #[test]
+    fn bundled_frames_split_back_out_until_one_is_cut_short() {
+        let frames: [&[u8]; 3] = [&[1, 2, 3], &[], &[4; 300]];
+        let mut bundle = Vec::new();
+        for frame in frames {
+            push_bundled_frame(&mut bundle, frame);
+        }
+        assert_eq!(bundle.len(), 3 * 2 + 303);
+        assert_eq!(bundled_frames(&bundle).collect::<Vec<_>>(), frames);
+        assert_eq!(bundled_frames(&bundle[..bundle.len() - 1]).count(), 2);
+    }
+
+    #[test]
+    fn stream_names_are_cut_on_a_character_boundary_and_must_be_utf8() {
+        // 40 two-byte characters, so 64 bytes falls between characters only after 32 of them
+        let name = StreamName::new(&"é".repeat(40));
+        assert_eq!(name.as_str(), "é".repeat(32));
+        let header = StreamHeader { version: PROTOCOL_VERSION, sample_rate: 48000, channels: 2, compressed: true, encrypted: false, authenticated: false, loss_reports: false, raw_i16: false, monotonic_clock: false, planar: false, source_rate: 48000, session_id: 7, name: Some(name) };
+        let mut encoded = header.encode();
+        assert_eq!(encoded.len(), HEADER_LEN + 64);
+        assert_eq!(parse_header(&encoded), Some(header));
+
+        encoded[HEADER_LEN] = 0xFF;
+        assert_eq!(parse_header(&encoded), None);
+    }
+}
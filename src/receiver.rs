@@ -0,0 +1,335 @@
+use pyo3::prelude::*;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::adaptive::{answer_hello, send_hello, send_report, ClockSync, LossCounter};
+use crate::crypto::{associated_data, PacketAuth, PacketCipher};
+use crate::error::{config_error, socket_error};
+use crate::net::join_multicast_group;
+use crate::transport::{TcpSource, TransportKind};
+use crate::protocol::{get_timestamp_us, is_local_session, parse_header, parse_packet, verify_crc, AudioPacket, StreamHeader, PACKET_TYPE_EOS, PACKET_TYPE_HELLO, PACKET_TYPE_KEEPALIVE, PACKET_TYPE_PING, PROTOCOL_VERSION};
+
+/// What a single receive call produced.
+pub(crate) enum Received<'a> {
+    Header(StreamHeader),
+    Packet(AudioPacket<'a>),
+    // The sender is alive but idle; there is nothing to decode
+    Keepalive,
+    // The sender stopped cleanly; nothing more is coming
+    EndOfStream,
+    // Timed out, or the datagram failed validation
+    Skipped,
+}
+
+enum Source {
+    Udp(UdpSocket),
+    Tcp(TcpSource),
+}
+
+/// Collects the fragments of one raw buffer as they arrive in order.
+#[derive(Default)]
+struct Reassembly {
+    sequence: u32,
+    next_index: u8,
+    data: Vec<u8>,
+}
+
+impl Reassembly {
+    /// Adds a fragment, returning true once the buffer is complete. A missing or
+    /// out-of-order fragment discards the partial buffer.
+    fn push(&mut self, packet: &AudioPacket) -> bool {
+        if packet.fragment_index == 0 {
+            self.sequence = packet.sequence;
+            self.next_index = 0;
+            self.data.clear();
+        } else if packet.sequence != self.sequence || packet.fragment_index != self.next_index {
+            self.next_index = 0;
+            return false;
+        }
+        self.data.extend_from_slice(packet.data);
+        self.next_index = packet.fragment_index + 1;
+        self.next_index == packet.fragment_count
+    }
+}
+
+/// Receives SYNC datagrams over UDP or TCP, dropping anything that fails validation.
+pub(crate) struct PacketReceiver {
+    source: Source,
+    buf: Vec<u8>,
+    reassembly: Reassembly,
+    warned_version: bool,
+    dropped_corrupt: u64,
+    cipher: Option<PacketCipher>,
+    decrypted: Vec<u8>,
+    warned_encryption: bool,
+    dropped_undecryptable: u64,
+    auth: Option<PacketAuth>,
+    warned_auth: bool,
+    dropped_forged: u64,
+    // Set by headers from senders that adapt their bitrate to reported loss
+    loss_reports: bool,
+    loss: LossCounter,
+    // SESSION of the last accepted header; until one arrives, or while audio
+    // carries another SESSION, audio prompts a HELLO back to its sender
+    session_id: Option<u32>,
+    last_hello: Option<Instant>,
+    // When a HELLO, e.g. from ping_target, was last answered
+    last_hello_reply: Option<Instant>,
+    // Skips streams sent from this process, e.g. looped back by multicast
+    ignore_local: bool,
+    warned_local: bool,
+    // Set when the sender's clock offset should be measured
+    clock_sync: Option<ClockSync>,
+}
+
+impl PacketReceiver {
+    /// Binds `bind_addr`; receive calls return `Skipped` after `read_timeout`
+    /// without traffic so callers can poll for shutdown.
+    pub(crate) fn bind(bind_addr: &str, transport: TransportKind, read_timeout: Duration) -> PyResult<Self> {
+        let source = match transport {
+            TransportKind::Udp => {
+                let socket = UdpSocket::bind(bind_addr).map_err(|e| socket_error(format!("Socket bind failed: {}", e)))?;
+                socket.set_read_timeout(Some(read_timeout)).map_err(|e| socket_error(format!("Socket timeout failed: {}", e)))?;
+                Source::Udp(socket)
+            }
+            TransportKind::Tcp => Source::Tcp(TcpSource::bind(bind_addr, read_timeout)?),
+        };
+        Ok(PacketReceiver {
+            source,
+            buf: vec![0u8; 65536],
+            reassembly: Reassembly::default(),
+            warned_version: false,
+            dropped_corrupt: 0,
+            cipher: None,
+            decrypted: Vec::new(),
+            warned_encryption: false,
+            dropped_undecryptable: 0,
+            auth: None,
+            warned_auth: false,
+            dropped_forged: 0,
+            loss_reports: false,
+            loss: LossCounter::new(),
+            session_id: None,
+            last_hello: None,
+            last_hello_reply: None,
+            ignore_local: true,
+            warned_local: false,
+            clock_sync: None,
+        })
+    }
+
+    /// Decrypts packet payloads, accepting only streams whose header is marked encrypted.
+    pub(crate) fn decrypt_with(&mut self, cipher: PacketCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Accepts only datagrams signed with the same pre-shared key.
+    pub(crate) fn authenticate_with(&mut self, auth: PacketAuth) {
+        self.auth = Some(auth);
+    }
+
+    /// Also accepts streams sent from this process, for loopback testing.
+    pub(crate) fn accept_local_streams(&mut self) {
+        self.ignore_local = false;
+    }
+
+    /// Pings the sender now and then to estimate its clock offset; the
+    /// answers come back over UDP, so TCP streams can't be measured.
+    pub(crate) fn sync_clock(&mut self) -> PyResult<()> {
+        match &self.source {
+            Source::Udp(_) => {
+                self.clock_sync = Some(ClockSync::new());
+                Ok(())
+            }
+            Source::Tcp(_) => Err(config_error("clock_sync needs the \"udp\" transport")),
+        }
+    }
+
+    /// Sender clock minus this one in microseconds, once a PING has been answered.
+    pub(crate) fn clock_offset_us(&self) -> Option<i64> {
+        self.clock_sync.as_ref().and_then(ClockSync::offset_us)
+    }
+
+    /// Also receives what is sent to the multicast `group`.
+    pub(crate) fn join_multicast(&self, group: IpAddr) -> PyResult<()> {
+        match &self.source {
+            Source::Udp(socket) => join_multicast_group(socket, group),
+            Source::Tcp(_) => Err(config_error("Multicast needs the \"udp\" transport")),
+        }
+    }
+
+    pub(crate) fn recv(&mut self) -> PyResult<Received<'_>> {
+        let received = match &mut self.source {
+            Source::Udp(socket) => socket.recv_from(&mut self.buf).map(|(len, from)| Some((len, Some(from)))),
+            Source::Tcp(source) => source.recv(&mut self.buf).map(|len| len.map(|len| (len, None))),
+        };
+        let (len, from): (usize, Option<SocketAddr>) = match received {
+            Ok(Some(received)) => received,
+            Ok(None) => return Ok(Received::Skipped),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(Received::Skipped),
+            Err(e) => return Err(socket_error(format!("Socket receive failed: {}", e))),
+        };
+        let arrived_us = get_timestamp_us();
+        let data = &self.buf[..len];
+
+        if let Some(header) = parse_header(data) {
+            if is_own_stream(self.ignore_local, &mut self.warned_local, header.session_id) {
+                return Ok(Received::Skipped);
+            }
+            if header.version != PROTOCOL_VERSION {
+                if !self.warned_version {
+                    warn!("Ignoring header with protocol version {} (expected {})", header.version, PROTOCOL_VERSION);
+                    self.warned_version = true;
+                }
+                return Ok(Received::Skipped);
+            }
+            if header.authenticated != self.auth.is_some() {
+                if !self.warned_auth {
+                    if header.authenticated {
+                        warn!("Ignoring authenticated stream: no psk given");
+                    } else {
+                        warn!("Ignoring unauthenticated stream: a psk is set");
+                    }
+                    self.warned_auth = true;
+                }
+                return Ok(Received::Skipped);
+            }
+            if self.auth.as_ref().is_some_and(|auth| auth.verify(data).is_none()) {
+                count_dropped(&mut self.dropped_forged, "forged datagrams (wrong psk?)");
+                return Ok(Received::Skipped);
+            }
+            // Never play ciphertext as audio, nor accept plain audio when a key was given
+            if header.encrypted != self.cipher.is_some() {
+                if !self.warned_encryption {
+                    if header.encrypted {
+                        warn!("Ignoring encrypted stream: no encryption_key given");
+                    } else {
+                        warn!("Ignoring unencrypted stream: an encryption_key is set");
+                    }
+                    self.warned_encryption = true;
+                }
+                return Ok(Received::Skipped);
+            }
+            self.loss_reports = header.loss_reports;
+            // A restarted sender counts from scratch, so forget what the old stream left behind
+            if self.session_id.is_some_and(|session_id| session_id != header.session_id) {
+                info!("Sender restarted with a new session");
+                self.reassembly = Reassembly::default();
+                self.loss = LossCounter::new();
+                // The new session may come from another machine
+                if self.clock_sync.is_some() {
+                    self.clock_sync = Some(ClockSync::new());
+                }
+            }
+            self.session_id = Some(header.session_id);
+            return Ok(Received::Header(header));
+        }
+
+        // The MAC replaces the CRC on authenticated streams
+        let body = match &self.auth {
+            Some(auth) => auth.verify(data).ok_or(&mut self.dropped_forged),
+            None => verify_crc(data).ok_or(&mut self.dropped_corrupt),
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(counter) => {
+                count_dropped(counter, if self.auth.is_some() { "forged datagrams (wrong psk?)" } else { "corrupt packets" });
+                return Ok(Received::Skipped);
+            }
+        };
+        let Some(packet) = parse_packet(body) else { return Ok(Received::Skipped) };
+        // Answers to our PINGs are signed like audio but never encrypted
+        if packet.packet_type == PACKET_TYPE_PING {
+            if let Some(clock_sync) = &mut self.clock_sync {
+                clock_sync.on_reply(&packet, arrived_us);
+            }
+            return Ok(Received::Skipped);
+        }
+        // Someone checking a receiver is listening here before they stream to it
+        if packet.packet_type == PACKET_TYPE_HELLO {
+            if let (Source::Udp(socket), Some(peer)) = (&self.source, from) {
+                answer_hello(socket, peer, self.auth.as_ref(), &mut self.last_hello_reply);
+            }
+            return Ok(Received::Skipped);
+        }
+        if is_own_stream(self.ignore_local, &mut self.warned_local, packet.session_id) {
+            return Ok(Received::Skipped);
+        }
+        let packet = match &self.cipher {
+            // The wire TYPE byte, so the last-packet marker is covered too
+            Some(cipher) => match cipher.decrypt(&associated_data(body[0], packet.session_id, packet.sequence, packet.fragment_index, packet.fragment_count), packet.data) {
+                Some(data) => {
+                    self.decrypted = data;
+                    AudioPacket { data: &self.decrypted, ..packet }
+                }
+                None => {
+                    count_dropped(&mut self.dropped_undecryptable, "packets that failed decryption (wrong encryption_key?)");
+                    return Ok(Received::Skipped);
+                }
+            },
+            None => packet,
+        };
+        if self.session_id != Some(packet.session_id) {
+            if let (Source::Udp(socket), Some(peer)) = (&self.source, from) {
+                send_hello(socket, peer, self.auth.as_ref(), &mut self.last_hello);
+            }
+            // Stale packets from before a restart, or a new session whose header hasn't come yet
+            if self.session_id.is_some() {
+                return Ok(Received::Skipped);
+            }
+        }
+        match packet.packet_type {
+            PACKET_TYPE_KEEPALIVE => return Ok(Received::Keepalive),
+            PACKET_TYPE_EOS => return Ok(Received::EndOfStream),
+            _ => {}
+        }
+        if packet.fragment_count > 1 && !self.reassembly.push(&packet) {
+            return Ok(Received::Skipped);
+        }
+        if self.loss_reports {
+            self.loss.record(packet.sequence);
+            // Reports go back over UDP to wherever the audio came from
+            if let (Some(report), Source::Udp(socket), Some(peer)) = (self.loss.take_report(Instant::now()), &self.source, from) {
+                send_report(socket, peer, report, self.auth.as_ref());
+            }
+        }
+        if let (Some(clock_sync), Source::Udp(socket), Some(peer)) = (&mut self.clock_sync, &self.source, from) {
+            clock_sync.poll(socket, peer, self.auth.as_ref());
+        }
+        if packet.fragment_count == 1 {
+            return Ok(Received::Packet(packet));
+        }
+        Ok(Received::Packet(AudioPacket {
+            packet_type: packet.packet_type,
+            last: packet.last,
+            sequence: packet.sequence,
+            timestamp_us: packet.timestamp_us,
+            fragment_index: 0,
+            fragment_count: 1,
+            session_id: packet.session_id,
+            data: &self.reassembly.data,
+        }))
+    }
+}
+
+// Playing our own stream back would feed it into the capture again; a free
+// function so it can run while the receive buffer is still borrowed
+fn is_own_stream(ignore_local: bool, warned_local: &mut bool, session_id: u32) -> bool {
+    if !ignore_local || !is_local_session(session_id) {
+        return false;
+    }
+    if !*warned_local {
+        warn!("Ignoring a stream sent from this process (allow_loopback=True receives it)");
+        *warned_local = true;
+    }
+    true
+}
+
+// Warns on the first drop and every 100th so a bad stream doesn't flood the log
+fn count_dropped(counter: &mut u64, what: &str) {
+    *counter += 1;
+    if *counter == 1 || counter.is_multiple_of(100) {
+        warn!("Dropped {} {}", counter, what);
+    }
+}
@@ -0,0 +1,185 @@
+use pyo3::prelude::*;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::codec::StreamDecoder;
+use crate::crypto::{auth_from_psk, cipher_from_key};
+use crate::net::socket_address;
+use crate::protocol::StreamHeader;
+use crate::receiver::{PacketReceiver, Received};
+use crate::transport::TransportKind;
+
+// How often the recording thread checks for stop requests without traffic
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returned by `record_stream_to_wav` and `record_raw_packets`; stopping it
+/// finishes writing the file.
+#[pyclass]
+pub(crate) struct RecordingHandle {
+    stop_tx: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+}
+
+impl RecordingHandle {
+    /// Wraps a recording thread that stops once `stop_tx` is dropped.
+    pub(crate) fn new(stop_tx: mpsc::Sender<()>, thread: JoinHandle<()>, running: Arc<AtomicBool>) -> Self {
+        RecordingHandle { stop_tx: Some(stop_tx), thread: Some(thread), running }
+    }
+}
+
+#[pymethods]
+impl RecordingHandle {
+    /// Stops recording and waits for the file to be written out.
+    fn stop(&mut self, py: Python) {
+        self.stop_tx.take();
+        if let Some(thread) = self.thread.take() {
+            py.allow_threads(|| {
+                let _ = thread.join();
+            });
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// WAV writer plus decoder for the stream being recorded.
+struct Recording {
+    header: StreamHeader,
+    decoder: StreamDecoder,
+    writer: hound::WavWriter<BufWriter<File>>,
+    samples: Vec<f32>,
+}
+
+#[pyfunction]
+pub(crate) fn record_stream_to_wav(bind_ip: String, bind_port: u16, output_path: String, duration_secs: Option<u64>, encryption_key: Option<String>, psk: Option<String>, allow_loopback: Option<bool>) -> PyResult<RecordingHandle> {
+    let cipher = encryption_key.map(|key| cipher_from_key(&key)).transpose()?;
+    let auth = psk.map(|psk| auth_from_psk(&psk)).transpose()?;
+    let bind_addr = socket_address(&bind_ip, bind_port);
+    let mut receiver = PacketReceiver::bind(&bind_addr, TransportKind::Udp, STOP_CHECK_INTERVAL)?;
+    if let Some(cipher) = cipher {
+        receiver.decrypt_with(cipher);
+    }
+    if let Some(auth) = auth {
+        receiver.authenticate_with(auth);
+    }
+    if allow_loopback.unwrap_or(false) {
+        receiver.accept_local_streams();
+    }
+    // Open the file up front so a bad path is reported to the caller, not the thread
+    let file = File::create(&output_path).map_err(|e| PyErr::new::<pyo3::exceptions::PyOSError, _>(format!("Failed to create {}: {}", output_path, e)))?;
+    info!("Recording audio from {} to {}", bind_addr, output_path);
+
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let running = Arc::new(AtomicBool::new(true));
+    let running_clone = running.clone();
+
+    let thread = thread::spawn(move || {
+        let mut file = Some(BufWriter::new(file));
+        let mut recording: Option<Recording> = None;
+        // Counted from the first header, so waiting for a sender doesn't use up the duration
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            // Stop on an explicit stop() or when the handle is dropped
+            if !matches!(stop_rx.try_recv(), Err(TryRecvError::Empty)) {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+
+            let received = match receiver.recv() {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Recording error: {}", e);
+                    break;
+                }
+            };
+            match received {
+                Received::Header(header) => {
+                    if let Some(recording) = &mut recording {
+                        // A restarted sender carries on in the same file, decoded from scratch
+                        if header.restarts(&recording.header) {
+                            info!("Sender restarted, continuing the recording");
+                            match StreamDecoder::new(&header) {
+                                Ok(decoder) => recording.decoder = decoder,
+                                Err(e) => {
+                                    error!("Recording error: {}", e);
+                                    break;
+                                }
+                            }
+                            recording.header = header;
+                        }
+                        // A WAV file can only hold one format, so end the recording on a change
+                        if recording.header != header {
+                            warn!("Stream parameters changed, stopping recording");
+                            break;
+                        }
+                        continue;
+                    }
+                    let Some(file) = file.take() else { break };
+                    match start_recording(header, file) {
+                        Ok(started) => recording = Some(started),
+                        Err(e) => {
+                            error!("Recording error: {}", e);
+                            break;
+                        }
+                    }
+                    deadline = duration_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+                }
+                Received::Packet(packet) => {
+                    let Some(recording) = recording.as_mut() else { continue };
+                    if packet.last {
+                        info!("Sender ended the stream");
+                    }
+                    recording.samples.clear();
+                    recording.decoder.decode(packet.packet_type, packet.data, &mut recording.samples);
+                    if let Err(e) = recording.samples.iter().try_for_each(|&sample| recording.writer.write_sample(sample)) {
+                        error!("WAV write failed: {}", e);
+                        break;
+                    }
+                }
+                // Before the first header there's no recording to end yet
+                Received::EndOfStream if recording.is_some() => {
+                    info!("Sender stopped, ending recording");
+                    break;
+                }
+                Received::EndOfStream | Received::Keepalive | Received::Skipped => {}
+            }
+        }
+
+        if let Some(recording) = recording {
+            let frames = recording.writer.duration();
+            match recording.writer.finalize() {
+                Ok(()) => info!("Recording finished: {:.1}s written", frames as f64 / recording.header.sample_rate as f64),
+                Err(e) => error!("Failed to finalize WAV file: {}", e),
+            }
+        }
+        running_clone.store(false, Ordering::SeqCst);
+    });
+
+    Ok(RecordingHandle::new(stop_tx, thread, running))
+}
+
+fn start_recording(header: StreamHeader, file: BufWriter<File>) -> PyResult<Recording> {
+    let decoder = StreamDecoder::new(&header)?;
+    // Match the negotiated stream exactly so the file plays back at the right speed
+    let spec = hound::WavSpec {
+        channels: header.channels,
+        sample_rate: header.sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let writer = hound::WavWriter::new(file, spec).map_err(|e| PyErr::new::<pyo3::exceptions::PyOSError, _>(format!("Failed to write WAV header: {}", e)))?;
+    info!("Recording {}Hz, {} channels, compression: {}", header.sample_rate, header.channels, header.encoding_name());
+
+    Ok(Recording { header, decoder, writer, samples: Vec::new() })
+}
@@ -0,0 +1,50 @@
+/// Streaming linear-interpolation resampler for interleaved f32 audio.
+///
+/// State carries over between calls, so capture callbacks of any size can be
+/// fed in turn without clicks at the boundaries.
+pub(crate) struct Resampler {
+    channels: usize,
+    // Input frames advanced per output frame
+    step: f64,
+    // Position of the next output frame; 0.0 is the frame carried over in `prev`
+    pos: f64,
+    prev: Vec<f32>,
+}
+
+impl Resampler {
+    pub(crate) fn new(from_rate: u32, to_rate: u32, channels: u16) -> Self {
+        Resampler {
+            channels: channels as usize,
+            step: from_rate as f64 / to_rate as f64,
+            pos: 0.0,
+            prev: vec![0.0; channels as usize],
+        }
+    }
+
+    /// Resamples `input` and appends the result to `out`.
+    pub(crate) fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return;
+        }
+
+        // Frame 0 is the last frame of the previous call, frame i is input frame i - 1
+        let prev = &self.prev;
+        let frame = |i: usize, c: usize| if i == 0 { prev[c] } else { input[(i - 1) * channels + c] };
+
+        while (self.pos as usize) < frames {
+            let i = self.pos as usize;
+            let frac = (self.pos - i as f64) as f32;
+            for c in 0..channels {
+                let a = frame(i, c);
+                let b = frame(i + 1, c);
+                out.push(a + (b - a) * frac);
+            }
+            self.pos += self.step;
+        }
+
+        self.pos -= frames as f64;
+        self.prev.copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+    }
+}
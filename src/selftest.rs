@@ -0,0 +1,107 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::time::{Duration, Instant};
+
+use crate::codec::{encode_frame, frame_samples, max_packet_len, new_opus_encoder, opus_channels, opus_sample_rate, EncoderSettings, StreamDecoder};
+use crate::devices::{select_device, select_host};
+use crate::error::codec_error;
+use crate::null_source::TestTone;
+use crate::protocol::{StreamHeader, PACKET_TYPE_OPUS, PROTOCOL_VERSION};
+
+// One second of a 440 Hz tone at the server's default framing
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: u16 = 2;
+const FRAME_MS: f32 = 20.0;
+const TEST_SECS: usize = 1;
+
+/// Result of pushing the test tone through the codec.
+struct Roundtrip {
+    rms_error: f32,
+    // Frame duration plus encoder lookahead
+    codec_delay_ms: f64,
+    // Average time to encode and decode one frame
+    processing: Duration,
+}
+
+/// Encodes a sine wave with Opus and decodes it again, without touching the
+/// network. Returns a dict with the RMS error against the original tone, an
+/// estimated roundtrip latency and whether the default capture device's config
+/// (on `host`, or the default host) can be sent as Opus without resampling.
+#[pyfunction]
+pub(crate) fn selftest(py: Python, host: Option<String>) -> PyResult<PyObject> {
+    // Release GIL while the codec runs
+    let roundtrip = py.allow_threads(run_roundtrip)?;
+    let processing_ms = roundtrip.processing.as_secs_f64() * 1000.0;
+    info!("Self-test: RMS error {:.4}, latency {:.1} ms", roundtrip.rms_error, roundtrip.codec_delay_ms + processing_ms);
+
+    // A missing device is part of the diagnosis rather than a failure of the test
+    let host = select_host(host.as_deref());
+    let (device_config, opus_compatible) = match select_device(&host, None, None) {
+        Ok((_, config)) => {
+            let compatible = opus_sample_rate(config.sample_rate().0).is_some() && opus_channels(config.channels()).is_some();
+            (Some(format!("{} Hz, {} channels", config.sample_rate().0, config.channels())), compatible)
+        }
+        Err(e) => {
+            warn!("Self-test found no default device: {}", e);
+            (None, false)
+        }
+    };
+
+    let result = PyDict::new(py);
+    result.set_item("rms_error", roundtrip.rms_error)?;
+    result.set_item("latency_ms", roundtrip.codec_delay_ms + processing_ms)?;
+    result.set_item("processing_ms", processing_ms)?;
+    result.set_item("device_config", device_config)?;
+    result.set_item("device_opus_compatible", opus_compatible)?;
+    Ok(result.into())
+}
+
+fn run_roundtrip() -> PyResult<Roundtrip> {
+    let channels = CHANNELS as usize;
+    let frame_len = frame_samples(SAMPLE_RATE, FRAME_MS) * channels;
+    let mut tone = Vec::new();
+    TestTone::new(SAMPLE_RATE, CHANNELS).fill(SAMPLE_RATE as usize * TEST_SECS, &mut tone);
+
+    let encoder = new_opus_encoder(SAMPLE_RATE, CHANNELS, &EncoderSettings::default())?;
+    let header = StreamHeader {
+        version: PROTOCOL_VERSION,
+        sample_rate: SAMPLE_RATE,
+        channels: CHANNELS,
+        compressed: true,
+        encrypted: false,
+        authenticated: false,
+        loss_reports: false,
+        raw_i16: false,
+        monotonic_clock: false,
+        planar: false,
+        source_rate: SAMPLE_RATE,
+        session_id: 0,
+        name: None,
+    };
+    let mut decoder = StreamDecoder::new(&header)?;
+    let lookahead = encoder.lookahead().map_err(|e| codec_error(format!("Failed to read Opus lookahead: {:?}", e)))? as usize;
+
+    let mut packet = vec![0u8; max_packet_len(FRAME_MS, None)];
+    let mut decoded = Vec::with_capacity(tone.len());
+    let started = Instant::now();
+    for frame in tone.chunks_exact(frame_len) {
+        let len = encode_frame(&encoder, frame, &mut packet).map_err(|e| codec_error(format!("Opus encode failed: {:?}", e)))?;
+        decoder.decode(PACKET_TYPE_OPUS, &packet[..len], &mut decoded);
+    }
+    let frames = tone.len() / frame_len;
+    let processing = started.elapsed() / frames as u32;
+
+    // The decoded signal trails the input by the encoder's lookahead
+    let delay = lookahead * channels;
+    if decoded.len() <= delay {
+        return Err(codec_error(format!("Opus roundtrip produced only {} samples", decoded.len())));
+    }
+    let compared = decoded.len() - delay;
+    let squared: f32 = tone.iter().zip(&decoded[delay..]).map(|(original, output)| (original - output).powi(2)).sum();
+
+    Ok(Roundtrip {
+        rms_error: (squared / compared as f32).sqrt(),
+        codec_delay_ms: FRAME_MS as f64 + lookahead as f64 * 1000.0 / SAMPLE_RATE as f64,
+        processing,
+    })
+}
@@ -0,0 +1,1016 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use audiopus::coder::Encoder as OpusEncoder;
+use audiopus::Bitrate as OpusBitrate;
+
+use crate::adaptive::SharedBitrate;
+use crate::codec::{encode_frame, encoder_bitrate_kbps, frame_samples, max_packet_len};
+use crate::crypto::{associated_data, PacketAuth, PacketCipher, ENCRYPTION_OVERHEAD, MAC_LEN};
+use crate::error::{config_error, socket_error};
+use crate::framing::FrameBuffer;
+use crate::pacer::Pacer;
+use crate::process::ProcessHook;
+use crate::protocol::{as_u8_slice, build_packet, bundled_frames, deinterleave, encode_header, get_timestamp_us, i16_bytes_from_samples, push_bundled_frame, send_header, LocalSession, StreamHeader, CRC_LEN, PACKET_PREFIX_LEN, PACKET_FLAG_LAST, PACKET_TYPE_EOS, PACKET_TYPE_KEEPALIVE, PACKET_TYPE_OPUS, PACKET_TYPE_OPUS_BUNDLE, PACKET_TYPE_RAW};
+use crate::resample::Resampler;
+use crate::tee::WavTee;
+use crate::transport::{is_transient_send_error, Transport};
+
+/// Idle time after which a keepalive goes out unless the caller picks another.
+pub(crate) const DEFAULT_KEEPALIVE_MS: u32 = 1000;
+
+/// RMS below which `skip_silence` drops a raw buffer: about -60 dBFS, under the
+/// noise floor of most capture paths.
+pub(crate) const DEFAULT_SILENCE_THRESHOLD: f32 = 0.001;
+
+/// Length of the fade-in at start and fade-out at stop unless the caller picks another.
+pub(crate) const DEFAULT_FADE_MS: f32 = 5.0;
+
+/// Captured audio sent as silence at the start of a device stream unless the caller picks another length.
+pub(crate) const DEFAULT_WARMUP_MS: u32 = 30;
+
+/// Header copies sent back to back at the start of a stream unless the caller picks another count.
+pub(crate) const DEFAULT_HEADER_REPEAT: u32 = 5;
+/// Audio packets between header resends unless the caller picks another interval.
+pub(crate) const DEFAULT_HEADER_RESEND_EVERY: u64 = 1000;
+
+// EOS packets sent when a stream ends, and the gap between them
+const EOS_REPEATS: usize = 3;
+const EOS_INTERVAL: Duration = Duration::from_millis(20);
+
+// Level where the limiter starts bending samples towards full scale
+const LIMITER_KNEE: f32 = 0.8;
+
+/// Most Opus frames one packet may bundle, and most audio a bundle may hold.
+pub(crate) const MAX_FRAMES_PER_PACKET: u8 = 8;
+pub(crate) const MAX_BUNDLE_MS: f32 = 120.0;
+
+/// Raw payload bytes per packet unless the caller picks another size.
+pub(crate) const DEFAULT_MAX_PAYLOAD: usize = 1400;
+/// Largest payload that still fits a datagram, and the u16 SIZE field, with the packet prefix and CRC.
+pub(crate) const MAX_PAYLOAD_LIMIT: usize = 65507 - PACKET_PREFIX_LEN - CRC_LEN;
+
+/// Counters updated by the sender and read from other threads.
+pub(crate) struct SenderStats {
+    pub(crate) packets_sent: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    // Paced packets dropped because the queue was full
+    pub(crate) packets_dropped: AtomicU64,
+    pub(crate) frames_encoded: AtomicU64,
+    pub(crate) encode_us_total: AtomicU64,
+    // u64::MAX until the first frame is encoded
+    pub(crate) encode_us_min: AtomicU64,
+    pub(crate) encode_us_max: AtomicU64,
+    // Frames that took longer to encode than they last, so capture is at risk of falling behind
+    pub(crate) slow_encodes: AtomicU64,
+    // Packets no target accepted, whatever the reason
+    pub(crate) send_failures: AtomicU64,
+    // Packets in a row that failed with a non-transient error, and the OS error
+    // code of the latest (0 when it had none); any packet getting through resets them
+    pub(crate) consecutive_send_failures: AtomicU64,
+    pub(crate) last_send_error: AtomicI32,
+    // Capture stream errors by kind, and the latest with its message; locked
+    // only when an error comes in or is read
+    pub(crate) device_lost_errors: AtomicU64,
+    pub(crate) backend_errors: AtomicU64,
+    pub(crate) other_stream_errors: AtomicU64,
+    pub(crate) last_stream_error: Mutex<Option<(StreamErrorKind, String)>>,
+    // Captured buffers thrown away because the send thread was too far behind
+    pub(crate) backpressure_drops: AtomicU64,
+    // One per destination, in the transport's order, so the send path never looks anything up
+    pub(crate) targets: Vec<TargetStats>,
+}
+
+/// What went wrong with a capture stream: the device went away, the audio
+/// backend failed, or something else, such as a reconnect attempt failing.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamErrorKind {
+    DeviceLost,
+    Backend,
+    Other,
+}
+
+impl StreamErrorKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            StreamErrorKind::DeviceLost => "device_lost",
+            StreamErrorKind::Backend => "backend",
+            StreamErrorKind::Other => "other",
+        }
+    }
+}
+
+/// Packets and bytes one destination was sent, and how many sends to it failed.
+pub(crate) struct TargetStats {
+    pub(crate) addr: String,
+    pub(crate) packets_sent: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) send_errors: AtomicU64,
+}
+
+impl SenderStats {
+    pub(crate) fn new(target_addrs: Vec<String>) -> Self {
+        SenderStats {
+            packets_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            packets_dropped: AtomicU64::new(0),
+            frames_encoded: AtomicU64::new(0),
+            encode_us_total: AtomicU64::new(0),
+            encode_us_min: AtomicU64::new(u64::MAX),
+            encode_us_max: AtomicU64::new(0),
+            slow_encodes: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            consecutive_send_failures: AtomicU64::new(0),
+            last_send_error: AtomicI32::new(0),
+            device_lost_errors: AtomicU64::new(0),
+            backend_errors: AtomicU64::new(0),
+            other_stream_errors: AtomicU64::new(0),
+            last_stream_error: Mutex::new(None),
+            backpressure_drops: AtomicU64::new(0),
+            targets: target_addrs.into_iter().map(|addr| TargetStats { addr, packets_sent: AtomicU64::new(0), bytes_sent: AtomicU64::new(0), send_errors: AtomicU64::new(0) }).collect(),
+        }
+    }
+
+    /// Sends one audio packet on `transport`, counting it in the totals and
+    /// against each destination it went to or failed to reach.
+    pub(crate) fn send_counted(&self, transport: &Transport, packet: &[u8]) {
+        let sent = transport.send_each(packet, |target, result| {
+            let Some(stats) = self.targets.get(target) else { return };
+            match result {
+                Ok(len) => {
+                    stats.packets_sent.fetch_add(1, Ordering::Relaxed);
+                    stats.bytes_sent.fetch_add(*len as u64, Ordering::Relaxed);
+                }
+                Err(_) => {
+                    stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        match sent {
+            Ok(sent) => {
+                self.packets_sent.fetch_add(1, Ordering::Relaxed);
+                self.bytes_sent.fetch_add(sent as u64, Ordering::Relaxed);
+                self.consecutive_send_failures.store(0, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.send_failures.fetch_add(1, Ordering::Relaxed);
+                if !is_transient_send_error(&e) {
+                    self.last_send_error.store(e.raw_os_error().unwrap_or(0), Ordering::Relaxed);
+                    self.consecutive_send_failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Describes the error behind the current run of send failures.
+    pub(crate) fn last_send_error(&self) -> String {
+        match self.last_send_error.load(Ordering::Relaxed) {
+            0 => "unknown error".to_string(),
+            code => std::io::Error::from_raw_os_error(code).to_string(),
+        }
+    }
+
+    /// Counts a capture stream error of `kind` and keeps `message` as the latest.
+    pub(crate) fn record_stream_error(&self, kind: StreamErrorKind, message: String) {
+        let counter = match kind {
+            StreamErrorKind::DeviceLost => &self.device_lost_errors,
+            StreamErrorKind::Backend => &self.backend_errors,
+            StreamErrorKind::Other => &self.other_stream_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        *self.last_stream_error.lock().unwrap() = Some((kind, message));
+    }
+
+    /// Stream error counts as "device_lost", "backend" and "other", plus
+    /// "last_kind" and "last_message" for the latest (None before any).
+    pub(crate) fn stream_errors_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("device_lost", self.device_lost_errors.load(Ordering::Relaxed))?;
+        dict.set_item("backend", self.backend_errors.load(Ordering::Relaxed))?;
+        dict.set_item("other", self.other_stream_errors.load(Ordering::Relaxed))?;
+        let last = self.last_stream_error.lock().unwrap().clone();
+        dict.set_item("last_kind", last.as_ref().map(|(kind, _)| kind.as_str()))?;
+        dict.set_item("last_message", last.map(|(_, message)| message))?;
+        Ok(dict)
+    }
+
+    /// Records one Opus encode that took `elapsed` for a frame of `frame_duration`.
+    pub(crate) fn record_encode(&self, elapsed: Duration, frame_duration: Duration) {
+        let us = elapsed.as_micros() as u64;
+        self.frames_encoded.fetch_add(1, Ordering::Relaxed);
+        self.encode_us_total.fetch_add(us, Ordering::Relaxed);
+        self.encode_us_min.fetch_min(us, Ordering::Relaxed);
+        self.encode_us_max.fetch_max(us, Ordering::Relaxed);
+        if elapsed > frame_duration {
+            self.slow_encodes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stream parameters a sender settled on, reported back to Python once it starts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StreamInfo {
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: u16,
+    pub(crate) compressed: bool,
+    pub(crate) source_rate: u32,
+    pub(crate) local_addr: Option<SocketAddr>,
+}
+
+impl StreamInfo {
+    pub(crate) fn to_dict<'py>(self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let info = PyDict::new(py);
+        info.set_item("sample_rate", self.sample_rate)?;
+        info.set_item("channels", self.channels)?;
+        info.set_item("compression", self.compressed)?;
+        info.set_item("source_sample_rate", self.source_rate)?;
+        info.set_item("bind_addr", self.local_addr.map(|addr| addr.ip().to_string()))?;
+        info.set_item("bind_port", self.local_addr.map(|addr| addr.port()))?;
+        Ok(info)
+    }
+}
+
+/// Linear gain shared between the sender and its Python handle.
+///
+/// Stored as the f32 bit pattern so it can change while audio is flowing.
+#[derive(Clone)]
+pub(crate) struct SharedGain(Arc<AtomicU32>);
+
+impl SharedGain {
+    pub(crate) fn new(gain: f32) -> Self {
+        SharedGain(Arc::new(AtomicU32::new(gain.to_bits())))
+    }
+
+    pub(crate) fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set(&self, gain: f32) {
+        self.0.store(gain.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Checks a gain from Python: finite and not negative.
+pub(crate) fn validate_gain(gain: f32) -> PyResult<f32> {
+    if gain.is_finite() && gain >= 0.0 {
+        Ok(gain)
+    } else {
+        Err(config_error(format!("Gain {} must be a finite number >= 0", gain)))
+    }
+}
+
+/// Passes samples under the knee through untouched and bends louder ones
+/// along a tanh curve that meets full scale smoothly instead of clipping.
+pub(crate) fn soft_clip(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= LIMITER_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - LIMITER_KNEE;
+    (LIMITER_KNEE + headroom * ((level - LIMITER_KNEE) / headroom).tanh()).copysign(sample)
+}
+
+/// Turns captured samples into SYNC packets and sends them to one target.
+///
+/// Shared by every audio source so they all produce the same wire format.
+pub(crate) struct PacketSender {
+    link: Link,
+    gain: SharedGain,
+    scaled: Vec<f32>,
+    // Soft-clips instead of hard clamping after gain
+    limiter: bool,
+    // Captured channel count when it differs from the header's
+    remix_channels: Option<usize>,
+    remixed: Vec<f32>,
+    resampler: Option<Resampler>,
+    resampled: Vec<f32>,
+    encoder: Option<OpusEncoder>,
+    frame_buffer: FrameBuffer,
+    encoded_buffer: Vec<u8>,
+    frame_ms: f32,
+    // Target set by adaptive bitrate and the value last given to the encoder
+    bitrate: Option<SharedBitrate>,
+    applied_kbps: u32,
+    max_payload: usize,
+    // Raw audio held back until the packet rate cap allows the next send
+    packet_interval: Option<Duration>,
+    pending: Vec<f32>,
+    next_raw_send: Instant,
+    // Where the throttle reads the time from; only tests swap it
+    clock: fn() -> Instant,
+    // Raw buffers with an RMS below this aren't sent
+    silence_threshold: Option<f32>,
+    // Captured blocks at or above this RMS count as sound, and when the last one came
+    idle_threshold: Option<f32>,
+    last_sound: Instant,
+    // Warmup and fade lengths in source frames (0 for none), how far through
+    // both the stream has got and the newest frame, which the fade-out ramps down from
+    warmup_frames: usize,
+    fade_frames: usize,
+    faded_in: usize,
+    last_frame: Vec<f32>,
+    ramped: Vec<f32>,
+    // Python DSP run over captured audio before anything else touches it
+    process: Option<ProcessHook>,
+    // Local copy of the audio as it goes to the encoder
+    tee: Option<WavTee>,
+    // Header copies sent by announce
+    header_repeat: u32,
+    // Keeps receivers in this process from playing this stream back
+    session: Option<LocalSession>,
+}
+
+impl PacketSender {
+    /// `frame_len` is the number of interleaved samples per Opus frame; `resampler`
+    /// converts from the source rate to `header.sample_rate` when they differ.
+    /// Raw buffers larger than `max_payload` bytes are fragmented.
+    pub(crate) fn new(transport: Transport, header: StreamHeader, resampler: Option<Resampler>, encoder: Option<OpusEncoder>, frame_len: usize, max_payload: usize, gain: SharedGain) -> Self {
+        let frame_ms = (frame_len / header.channels as usize) as f32 * 1000.0 / header.sample_rate as f32;
+        let encoded_len = encoder.as_ref().map_or(0, |encoder| max_packet_len(frame_ms, encoder_bitrate_kbps(encoder)));
+        let stats = Arc::new(SenderStats::new(transport.target_addrs()));
+        PacketSender {
+            gain,
+            scaled: Vec::new(),
+            limiter: false,
+            remix_channels: None,
+            remixed: Vec::new(),
+            max_payload,
+            packet_interval: None,
+            pending: Vec::new(),
+            next_raw_send: Instant::now(),
+            clock: Instant::now,
+            silence_threshold: None,
+            idle_threshold: None,
+            last_sound: Instant::now(),
+            warmup_frames: 0,
+            fade_frames: 0,
+            faded_in: 0,
+            last_frame: Vec::new(),
+            ramped: Vec::new(),
+            process: None,
+            tee: None,
+            header_repeat: DEFAULT_HEADER_REPEAT,
+            session: None,
+            link: Link { transport, header, sequence: 0, stats, cipher: None, auth: None, last_audio: Instant::now(), pacer: None, header_resend_every: Some(DEFAULT_HEADER_RESEND_EVERY), buffers_sent: 0, raw_bytes: Vec::new(), planar_samples: Vec::new(), clock_start: header.monotonic_clock.then(Instant::now), frames_per_packet: 1, bundle: Vec::new(), bundled: 0 },
+            resampler,
+            resampled: Vec::new(),
+            encoder,
+            frame_buffer: FrameBuffer::new(frame_len),
+            encoded_buffer: vec![0u8; encoded_len],
+            frame_ms,
+            bitrate: None,
+            applied_kbps: 0,
+        }
+    }
+
+    /// Converts captured audio with `source_channels` to the header's channel count
+    /// before resampling and encoding: averaged down to mono, or mono duplicated
+    /// into every channel.
+    pub(crate) fn remix_from(mut self, source_channels: u16) -> Self {
+        self.remix_channels = (source_channels != self.link.header.channels).then_some(source_channels as usize);
+        self
+    }
+
+    /// Encrypts every packet's payload; the header must have `encrypted` set.
+    /// Raw fragments shrink so the sealed payload still fits `max_payload`.
+    pub(crate) fn encrypt_with(mut self, cipher: PacketCipher) -> Self {
+        self.max_payload = self.max_payload.saturating_sub(ENCRYPTION_OVERHEAD);
+        self.link.cipher = Some(cipher);
+        self
+    }
+
+    /// Signs every datagram; the header must have `authenticated` set.
+    /// Raw fragments shrink to make room for the MAC in place of the CRC.
+    pub(crate) fn authenticate_with(mut self, auth: PacketAuth) -> Self {
+        self.max_payload = self.max_payload.saturating_sub(MAC_LEN - CRC_LEN);
+        self.link.auth = Some(auth);
+        self
+    }
+
+    /// Follows `bitrate` as it changes, sizing the packet buffer for up to `max_kbps`.
+    /// The encoder must already be running at the current value.
+    pub(crate) fn adapt_bitrate(mut self, bitrate: SharedBitrate, max_kbps: u32) -> Self {
+        self.applied_kbps = bitrate.get();
+        self.encoded_buffer.resize(max_packet_len(self.frame_ms, Some(max_kbps)), 0);
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    /// Keeps raw audio under `max_packets_per_sec` sends by holding buffers back
+    /// and sending them together, each send taking one SEQUENCE. Audio is delayed
+    /// by up to the time between sends. A send bigger than `max_payload` is still
+    /// fragmented, so the datagram rate only stays under the cap when the raw
+    /// bitrate fits (see `check_packet_rate` in server.rs). Opus sends one packet
+    /// per frame, or per bundle, and is unaffected.
+    pub(crate) fn limit_packet_rate(mut self, max_packets_per_sec: u32) -> Self {
+        self.packet_interval = Some(Duration::from_secs_f64(1.0 / max_packets_per_sec as f64));
+        self
+    }
+
+    /// Reads the time for the packet rate cap from `clock` instead of the system clock.
+    #[cfg(test)]
+    fn with_clock(mut self, clock: fn() -> Instant) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sends `frames` Opus frames per packet instead of one, cutting per-packet
+    /// overhead at low bitrates. Each frame waits for its bundle to fill, adding
+    /// up to `frames - 1` frames of latency, and a lost packet loses all of them.
+    pub(crate) fn bundle_frames(mut self, frames: u8) -> Self {
+        self.link.frames_per_packet = frames.max(1) as usize;
+        self
+    }
+
+    /// Keeps track of when captured audio last reached `threshold` RMS, for `silent_for`.
+    pub(crate) fn detect_idle_below(mut self, threshold: f32) -> Self {
+        self.idle_threshold = Some(threshold);
+        self
+    }
+
+    /// Soft-clips audio after gain instead of hard clamping it to [-1, 1], and
+    /// does so even at unity gain so a hot source is tamed too.
+    pub(crate) fn soft_limit(mut self) -> Self {
+        self.limiter = true;
+        self
+    }
+
+    /// Skips raw buffers whose RMS is below `threshold`. Each still takes a sequence
+    /// number so receivers see the gap, and keepalives cover the idle link.
+    pub(crate) fn skip_silence_below(mut self, threshold: f32) -> Self {
+        self.silence_threshold = Some(threshold);
+        self
+    }
+
+    /// Fades the first `fade_ms` of audio in from silence, and on `finish()` adds
+    /// a `fade_ms` ramp from the last captured frame down to silence, so neither
+    /// end of the stream clicks. Streams that stop before the fade-in completes
+    /// end without the ramp.
+    pub(crate) fn fade_in_out(mut self, fade_ms: f32) -> Self {
+        self.fade_frames = frame_samples(self.link.header.source_rate, fade_ms);
+        self
+    }
+
+    /// Sends the first `warmup_ms` of audio as silence, ahead of any fade-in,
+    /// so the partial or zeroed buffers some devices deliver as they start
+    /// don't pop. The stream's timing is unchanged.
+    pub(crate) fn warm_up(mut self, warmup_ms: u32) -> Self {
+        self.warmup_frames = frame_samples(self.link.header.source_rate, warmup_ms as f32);
+        self
+    }
+
+    /// Sends packets from a thread on `transport`, a clone of the stream's, spaced
+    /// by the audio they carry instead of in bursts as capture callbacks arrive.
+    /// Opus packets go out a frame apart; packets with no audio go straight after
+    /// the one before.
+    pub(crate) fn pace_on(mut self, transport: Transport) -> Self {
+        let frame_duration = Duration::from_secs_f32(self.frame_ms / 1000.0);
+        self.link.pacer = Some(Pacer::start(transport, self.link.stats.clone(), frame_duration));
+        self
+    }
+
+    /// Holds `session`, the header's SESSION, as local for as long as the sender runs.
+    pub(crate) fn own_session(mut self, session: LocalSession) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Carries on `previous`'s stream after the server is reconfigured: the same
+    /// sequence numbers, counters and monotonic clock, so with the same SESSION
+    /// receivers see a new header mid-stream rather than a new sender. Call
+    /// before `pace_on` so paced packets are counted too.
+    pub(crate) fn continue_from(mut self, previous: &PacketSender) -> Self {
+        self.link.sequence = previous.link.sequence;
+        self.link.stats = previous.link.stats.clone();
+        self.link.clock_start = previous.link.clock_start.filter(|_| self.link.header.monotonic_clock);
+        self.last_sound = previous.last_sound;
+        self
+    }
+
+    /// Hands captured audio to `process` first and sends what it returns, at
+    /// the capture rate and channel count, ahead of gain and remixing.
+    pub(crate) fn process_with(mut self, process: ProcessHook) -> Self {
+        self.process = Some(process);
+        self
+    }
+
+    /// Copies the audio to `tee` after gain, remixing and fading, just before
+    /// resampling and encoding: at the source rate with the header's channels.
+    pub(crate) fn tee_to(mut self, tee: WavTee) -> Self {
+        self.tee = Some(tee);
+        self
+    }
+
+    /// Sends the header `repeat` times on `announce`, then again every
+    /// `resend_every` audio packets (never, for `None`, so only answers to
+    /// HELLO reach late receivers).
+    pub(crate) fn repeat_header(mut self, repeat: u32, resend_every: Option<u64>) -> Self {
+        self.header_repeat = repeat;
+        self.link.header_resend_every = resend_every;
+        self
+    }
+
+    pub(crate) fn stream_info(&self) -> StreamInfo {
+        let header = &self.link.header;
+        StreamInfo {
+            sample_rate: header.sample_rate,
+            channels: header.channels,
+            compressed: header.compressed,
+            source_rate: header.source_rate,
+            local_addr: self.link.transport.local_addr().ok(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Arc<SenderStats> {
+        self.link.stats.clone()
+    }
+
+    /// How long captured audio has stayed below the `detect_idle_below`
+    /// threshold, counting time when no audio arrived at all.
+    pub(crate) fn silent_for(&self) -> Duration {
+        self.last_sound.elapsed()
+    }
+
+    pub(crate) fn session(&self) -> Option<&LocalSession> {
+        self.session.as_ref()
+    }
+
+    /// The bitrate being followed, when adapting to reported loss.
+    pub(crate) fn shared_bitrate(&self) -> Option<SharedBitrate> {
+        self.bitrate.clone()
+    }
+
+    /// Another handle on the socket or connection packets go out on.
+    pub(crate) fn clone_transport(&self) -> io::Result<Transport> {
+        self.link.transport.try_clone()
+    }
+
+    /// The header datagram as `announce` sends it.
+    pub(crate) fn encoded_header(&self) -> Vec<u8> {
+        encode_header(&self.link.header, self.link.auth.as_ref())
+    }
+
+    /// Sends the header several times so a receiver is unlikely to miss it.
+    pub(crate) fn announce(&self) -> PyResult<()> {
+        for _ in 0..self.header_repeat {
+            send_header(&self.link.transport, &self.link.header, self.link.auth.as_ref()).map_err(|e| socket_error(format!("Header send failed: {}", e)))?;
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        info!("Header sent {} times for redundancy", self.header_repeat);
+        thread::sleep(Duration::from_millis(100));
+        Ok(())
+    }
+
+    /// Sends a keepalive if no audio has gone out for `interval`, e.g. during
+    /// DTX silence or a pause.
+    pub(crate) fn send_keepalive_if_idle(&mut self, interval: Duration) {
+        if self.link.last_audio.elapsed() >= interval {
+            self.link.send_keepalive();
+        }
+    }
+
+    /// Sends a block of interleaved samples at the source rate.
+    pub(crate) fn send_samples(&mut self, data: &[f32]) {
+        if self.idle_threshold.is_some_and(|threshold| rms(data) >= threshold) {
+            self.last_sound = Instant::now();
+        }
+        // Taken out for the loop, since sending needs the whole sender
+        let Some(mut process) = self.process.take() else {
+            self.send_captured(data);
+            return;
+        };
+        process.submit(data);
+        while let Some(block) = process.next_processed() {
+            self.send_captured(&block);
+        }
+        self.process = Some(process);
+    }
+
+    fn send_captured(&mut self, data: &[f32]) {
+        let gain = self.gain.get();
+        let data = if self.limiter {
+            self.scaled.clear();
+            self.scaled.extend(data.iter().map(|sample| soft_clip(sample * gain)));
+            &self.scaled
+        } else if gain == 1.0 {
+            data
+        } else {
+            self.scaled.clear();
+            self.scaled.extend(data.iter().map(|sample| (sample * gain).clamp(-1.0, 1.0)));
+            &self.scaled
+        };
+        let data = if let Some(source_channels) = self.remix_channels {
+            let channels = self.link.header.channels as usize;
+            self.remixed.clear();
+            if channels == 1 {
+                self.remixed.extend(data.chunks_exact(source_channels).map(|frame| frame.iter().sum::<f32>() / source_channels as f32));
+            } else {
+                self.remixed.extend(data.iter().flat_map(|&sample| std::iter::repeat_n(sample, channels)));
+            }
+            &self.remixed
+        } else {
+            data
+        };
+        let channels = self.link.header.channels as usize;
+        let ramp_end = self.warmup_frames + self.fade_frames;
+        let data = if self.faded_in < ramp_end {
+            self.ramped.clear();
+            for (i, frame) in data.chunks_exact(channels).enumerate() {
+                // Silent through the warmup, then fading in
+                let factor = match (self.faded_in + i).checked_sub(self.warmup_frames) {
+                    None => 0.0,
+                    Some(position) if position >= self.fade_frames => 1.0,
+                    Some(position) => position as f32 / self.fade_frames as f32,
+                };
+                self.ramped.extend(frame.iter().map(|sample| sample * factor));
+            }
+            self.faded_in = (self.faded_in + data.len() / channels).min(ramp_end);
+            &self.ramped
+        } else {
+            data
+        };
+        if let Some(tee) = &self.tee {
+            tee.write(data);
+        }
+        if self.fade_frames > 0 {
+            if let Some(frame) = data.chunks_exact(channels).last() {
+                self.last_frame.clear();
+                self.last_frame.extend_from_slice(frame);
+            }
+        }
+
+        let Some(encoder) = &mut self.encoder else {
+            // Held-back audio is sent with its trailing silence rather than left waiting
+            if self.pending.is_empty() && self.silence_threshold.is_some_and(|threshold| rms(data) < threshold) {
+                self.link.skip_sequence();
+                return;
+            }
+            let Some(interval) = self.packet_interval else {
+                self.link.send_raw(data, self.max_payload, 0);
+                return;
+            };
+            // One interval between sends however much each carries, so a big send
+            // doesn't push the next one back and let even more pile up
+            self.pending.extend_from_slice(data);
+            let now = (self.clock)();
+            if now >= self.next_raw_send {
+                self.link.send_raw(&self.pending, self.max_payload, 0);
+                self.pending.clear();
+                self.next_raw_send = now + interval;
+            }
+            return;
+        };
+
+        if let Some(kbps) = self.bitrate.as_ref().map(SharedBitrate::get).filter(|&kbps| kbps != self.applied_kbps) {
+            // Don't retry a rejected value on every buffer
+            self.applied_kbps = kbps;
+            if let Err(e) = encoder.set_bitrate(OpusBitrate::BitsPerSecond(kbps as i32 * 1000)) {
+                error!("Failed to set Opus bitrate: {:?}", e);
+            }
+        }
+
+        if let Some(resampler) = &mut self.resampler {
+            self.resampled.clear();
+            resampler.process(data, &mut self.resampled);
+            self.frame_buffer.push(&self.resampled);
+        } else {
+            self.frame_buffer.push(data);
+        }
+
+        while let Some(frame) = self.frame_buffer.next_frame() {
+            let started = Instant::now();
+            let encoded = encode_frame(encoder, frame, &mut self.encoded_buffer);
+            self.link.stats.record_encode(started.elapsed(), Duration::from_secs_f32(self.frame_ms / 1000.0));
+            match encoded {
+                // Packets of two bytes or less are DTX silence and don't need sending;
+                // receivers play silence while nothing arrives. A bundle in progress
+                // goes out short rather than wait out the silence
+                Ok(len) if len <= 2 => self.link.flush_bundle(0),
+                Ok(len) => self.link.send_opus(&self.encoded_buffer[0..len], 0),
+                Err(e) => error!("Opus encode error: {:?}", e),
+            }
+        }
+    }
+
+    /// Sends the audio still buffered as the stream's last packet, marked so
+    /// receivers know it ended cleanly, then EOS packets. Call once capture has
+    /// stopped; a partial Opus frame is padded with silence, and with nothing
+    /// buffered a short silent packet still carries the marker.
+    pub(crate) fn finish(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            for block in process.finish() {
+                self.send_captured(&block);
+            }
+        }
+        self.push_fade_out();
+        self.send_last_packet();
+        for i in 0..EOS_REPEATS {
+            if i > 0 {
+                thread::sleep(EOS_INTERVAL);
+            }
+            self.link.send_packet(PACKET_TYPE_EOS, 0, 1, &[]);
+        }
+        if let Some(pacer) = &mut self.link.pacer {
+            pacer.finish();
+        }
+        if let Some(tee) = &mut self.tee {
+            tee.finish();
+        }
+    }
+
+    // Queues the ramp down where send_last_packet picks it up: held-back raw
+    // audio, or the frame buffer at the encoder's rate
+    fn push_fade_out(&mut self) {
+        if self.fade_frames == 0 || self.faded_in < self.warmup_frames + self.fade_frames || self.last_frame.is_empty() {
+            return;
+        }
+        let mut ramp = Vec::with_capacity(self.fade_frames * self.last_frame.len());
+        for i in 1..=self.fade_frames {
+            let factor = 1.0 - i as f32 / self.fade_frames as f32;
+            ramp.extend(self.last_frame.iter().map(|sample| sample * factor));
+        }
+        if let Some(tee) = &self.tee {
+            tee.write(&ramp);
+        }
+        match (&self.encoder, &mut self.resampler) {
+            (None, _) => self.pending.extend_from_slice(&ramp),
+            (Some(_), Some(resampler)) => {
+                self.resampled.clear();
+                resampler.process(&ramp, &mut self.resampled);
+                self.frame_buffer.push(&self.resampled);
+            }
+            (Some(_), None) => self.frame_buffer.push(&ramp),
+        }
+    }
+
+    fn send_last_packet(&mut self) {
+        let Some(encoder) = &mut self.encoder else {
+            if self.pending.is_empty() {
+                self.pending.resize(self.link.header.channels as usize, 0.0);
+            }
+            let pending = std::mem::take(&mut self.pending);
+            self.link.send_raw(&pending, self.max_payload, PACKET_FLAG_LAST);
+            return;
+        };
+        // Anything past one full frame goes out normally first
+        while let Some(frame) = self.frame_buffer.next_frame() {
+            match encode_frame(encoder, frame, &mut self.encoded_buffer) {
+                Ok(len) if len > 2 => self.link.send_opus(&self.encoded_buffer[0..len], 0),
+                Ok(_) => self.link.flush_bundle(0),
+                Err(e) => error!("Opus encode error: {:?}", e),
+            }
+        }
+        match encode_frame(encoder, self.frame_buffer.finish_frame(), &mut self.encoded_buffer) {
+            Ok(len) => self.link.send_opus(&self.encoded_buffer[0..len], PACKET_FLAG_LAST),
+            Err(e) => {
+                error!("Opus encode error: {:?}", e);
+                self.link.flush_bundle(0);
+            }
+        }
+    }
+}
+
+/// Transport and sequence counter for one outgoing stream.
+struct Link {
+    transport: Transport,
+    header: StreamHeader,
+    sequence: u32,
+    stats: Arc<SenderStats>,
+    cipher: Option<PacketCipher>,
+    auth: Option<PacketAuth>,
+    last_audio: Instant,
+    // Set when packets are paced rather than sent as soon as they're built
+    pacer: Option<Pacer>,
+    // Audio packets between header resends, so late joiners pick up the stream,
+    // and the audio buffers sent so far; counted apart from the sequence, which
+    // also moves on for skipped buffers
+    header_resend_every: Option<u64>,
+    buffers_sent: u64,
+    // Raw samples converted to i16, and split into channels, when the header says so
+    raw_bytes: Vec<u8>,
+    planar_samples: Vec<f32>,
+    // What packet timestamps count from when the header puts them on a monotonic clock
+    clock_start: Option<Instant>,
+    // Opus frames per packet, and the frames bundled so far when that's more than one
+    frames_per_packet: usize,
+    bundle: Vec<u8>,
+    bundled: usize,
+}
+
+impl Link {
+    fn timestamp_us(&self) -> u64 {
+        match self.clock_start {
+            Some(start) => start.elapsed().as_micros() as u64,
+            None => get_timestamp_us(),
+        }
+    }
+
+    fn send_audio(&mut self, packet_type: u8, payload: &[u8]) {
+        self.send_fragments(packet_type, payload, payload.len());
+    }
+
+    // An encoded frame goes out by itself, or joins the bundle, which is sent
+    // once it's full or the frame is the stream's last
+    fn send_opus(&mut self, frame: &[u8], flags: u8) {
+        if self.frames_per_packet == 1 {
+            self.send_audio(PACKET_TYPE_OPUS | flags, frame);
+            return;
+        }
+        push_bundled_frame(&mut self.bundle, frame);
+        self.bundled += 1;
+        if self.bundled == self.frames_per_packet || flags & PACKET_FLAG_LAST != 0 {
+            self.flush_bundle(flags);
+        }
+    }
+
+    // Sends whatever frames are bundled so far
+    fn flush_bundle(&mut self, flags: u8) {
+        if self.bundled == 0 {
+            return;
+        }
+        let bundle = std::mem::take(&mut self.bundle);
+        self.send_audio(PACKET_TYPE_OPUS_BUNDLE | flags, &bundle);
+        self.bundle = bundle;
+        self.bundle.clear();
+        self.bundled = 0;
+    }
+
+    // Raw audio, split on sample frames so every fragment is playable by itself.
+    // FRAG_COUNT is a single byte, so a buffer too big for 255 fragments goes out
+    // as several, each with its own SEQUENCE, rather than in bigger fragments;
+    // only the last of them carries `flags`.
+    fn send_raw(&mut self, samples: &[f32], max_payload: usize, flags: u8) {
+        let channels = self.header.channels as usize;
+        let max_frames = (max_payload / (channels * self.header.raw_sample_bytes())).max(1);
+        let buffer_len = max_frames * u8::MAX as usize * channels;
+        let buffers = samples.len().div_ceil(buffer_len);
+        for (index, buffer) in samples.chunks(buffer_len).enumerate() {
+            self.send_raw_buffer(buffer, max_frames, if index + 1 == buffers { flags } else { 0 });
+        }
+    }
+
+    // One buffer of at most 255 fragments of `max_frames` sample frames
+    fn send_raw_buffer(&mut self, samples: &[f32], max_frames: usize, flags: u8) {
+        // Taken out for the send so the payload can borrow them alongside self
+        let mut converted = std::mem::take(&mut self.raw_bytes);
+        let mut planar = std::mem::take(&mut self.planar_samples);
+        let samples = if self.header.planar {
+            planar.clear();
+            deinterleave(samples, self.header.channels as usize, &mut planar);
+            &planar[..]
+        } else {
+            samples
+        };
+        let payload = if self.header.raw_i16 {
+            converted.clear();
+            i16_bytes_from_samples(samples, &mut converted);
+            &converted[..]
+        } else {
+            as_u8_slice(samples)
+        };
+        let fragment_len = max_frames * self.header.channels as usize * self.header.raw_sample_bytes();
+        self.send_fragments(PACKET_TYPE_RAW | flags, payload, fragment_len);
+        self.raw_bytes = converted;
+        self.planar_samples = planar;
+    }
+
+    // Every audio buffer takes the next sequence number so receivers can spot gaps;
+    // its fragments share it. `fragment_len` must split `payload` into at most 255 parts.
+    fn send_fragments(&mut self, packet_type: u8, payload: &[u8], fragment_len: usize) {
+        if payload.is_empty() {
+            return;
+        }
+        if self.header_resend_every.is_some_and(|every| self.buffers_sent.is_multiple_of(every)) {
+            match &self.pacer {
+                // Queued like the packets so it stays ahead of the ones that follow it
+                Some(pacer) => pacer.push(encode_header(&self.header, self.auth.as_ref()), Duration::ZERO),
+                None => {
+                    let _ = send_header(&self.transport, &self.header, self.auth.as_ref());
+                }
+            }
+        }
+        let fragment_count = payload.len().div_ceil(fragment_len) as u8;
+        for (index, fragment) in payload.chunks(fragment_len).enumerate() {
+            self.send_packet(packet_type, index as u8, fragment_count, fragment);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.buffers_sent += 1;
+        self.last_audio = Instant::now();
+    }
+
+    // Leaves a gap in the sequence for a buffer that wasn't worth sending
+    fn skip_sequence(&mut self) {
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+
+    // Carries only the prefix's timestamp and doesn't take a sequence number
+    fn send_keepalive(&mut self) {
+        self.send_packet(PACKET_TYPE_KEEPALIVE, 0, 1, &[]);
+        self.last_audio = Instant::now();
+    }
+
+    fn send_packet(&self, packet_type: u8, fragment_index: u8, fragment_count: u8, payload: &[u8]) {
+        let packet = match &self.cipher {
+            Some(cipher) => {
+                let sealed = cipher.encrypt(&associated_data(packet_type, self.header.session_id, self.sequence, fragment_index, fragment_count), payload);
+                build_packet(packet_type, self.header.session_id, self.sequence, self.timestamp_us(), fragment_index, fragment_count, &sealed, self.auth.as_ref())
+            }
+            None => build_packet(packet_type, self.header.session_id, self.sequence, self.timestamp_us(), fragment_index, fragment_count, payload, self.auth.as_ref()),
+        };
+        if let Some(pacer) = &self.pacer {
+            let audio = match packet_type & !PACKET_FLAG_LAST {
+                PACKET_TYPE_RAW => {
+                    let frames = payload.len() / (self.header.channels as usize * self.header.raw_sample_bytes());
+                    Duration::from_secs_f64(frames as f64 / self.header.sample_rate as f64)
+                }
+                PACKET_TYPE_OPUS => pacer.frame_duration(),
+                PACKET_TYPE_OPUS_BUNDLE => pacer.frame_duration() * bundled_frames(payload).count() as u32,
+                _ => Duration::ZERO,
+            };
+            pacer.push(packet, audio);
+            return;
+        }
+        self.stats.send_counted(&self.transport, &packet);
+    }
+}
+
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::net::UdpSocket;
+    use crate::protocol::{parse_header, PROTOCOL_VERSION};
+
+    thread_local! {
+        static NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    fn test_clock() -> Instant {
+        NOW.with(|now| now.get().expect("test clock not set"))
+    }
+
+    fn raw_header(sample_rate: u32, channels: u16) -> StreamHeader {
+        StreamHeader { version: PROTOCOL_VERSION, sample_rate, channels, compressed: false, encrypted: false, authenticated: false, loss_reports: false, raw_i16: false, monotonic_clock: false, planar: false, source_rate: sample_rate, session_id: 1, name: None }
+    }
+
+    #[test]
+    fn soft_clip_stays_in_range_and_rises_through_the_knee() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-LIMITER_KNEE), -LIMITER_KNEE);
+        let mut previous = soft_clip(-8.0);
+        for step in -799..=800 {
+            let sample = soft_clip(step as f32 / 100.0);
+            assert!((-1.0..=1.0).contains(&sample), "{} out of range", sample);
+            assert!(sample >= previous, "not monotonic at {}", step);
+            previous = sample;
+        }
+        assert!(soft_clip(4.0) > 0.99);
+    }
+
+    #[test]
+    fn packet_rate_cap_keeps_sends_evenly_spaced() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let transport = Transport::Udp { socket: UdpSocket::bind("127.0.0.1:0").unwrap(), target_addrs: vec![receiver.local_addr().unwrap().to_string()] };
+        let max_payload = 1200;
+        let start = Instant::now();
+        NOW.with(|now| now.set(Some(start)));
+        let mut sender = PacketSender::new(transport, raw_header(48000, 2), None, None, 1920, max_payload, SharedGain::new(1.0)).limit_packet_rate(50).with_clock(test_clock);
+
+        // 10ms buffers against a 20ms interval, each send several fragments
+        let buffer = vec![0.25f32; 960];
+        let mut datagram = vec![0u8; 65536];
+        let mut sends = Vec::new();
+        for tick in 1..=100 {
+            let now = start + Duration::from_millis(10 * tick);
+            NOW.with(|clock| clock.set(Some(now)));
+            sender.send_samples(&buffer);
+            let mut sent = false;
+            while let Ok(len) = receiver.recv(&mut datagram) {
+                if parse_header(&datagram[..len]).is_some() {
+                    continue;
+                }
+                assert!(len <= PACKET_PREFIX_LEN + max_payload + CRC_LEN, "{}-byte datagram", len);
+                sent = true;
+            }
+            if sent {
+                sends.push(now);
+            }
+        }
+        assert!(sends.len() >= 49, "only {} sends", sends.len());
+        assert!(sends.windows(2).all(|pair| pair[1] - pair[0] == Duration::from_millis(20)));
+    }
+}
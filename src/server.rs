@@ -0,0 +1,1475 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use socket2::SockRef;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::adaptive::{BitrateController, FeedbackListener, SharedBitrate, DEFAULT_MAX_KBPS, DEFAULT_MIN_KBPS};
+use crate::capture_queue::{CaptureQueue, SendThread, BLOCK_SAMPLES};
+use crate::codec::{frame_samples, new_opus_encoder, EncoderSettings, opus_channels, opus_sample_rate, parse_bandwidth, parse_opus_application, parse_signal, OPUS_FRAME_DURATIONS_MS, OPUS_RESAMPLE_RATE};
+use crate::config::{BitrateArg, ServerConfig};
+use crate::crypto::{auth_from_psk, cipher_from_key, PacketAuth, PacketCipher};
+use crate::devices::{select_device, select_device_by_index, select_host, select_loopback_device, supports_channels};
+use crate::discovery::Advertisement;
+use crate::error::{config_error, device_error, socket_error};
+use crate::net::{bind_sender_socket, configure_multicast_sender, DEFAULT_MULTICAST_TTL, parse_multicast_group, set_send_buffer, socket_address};
+use crate::null_source::{NullSource, NULL_DEFAULT_CHANNELS, NULL_DEFAULT_RATE, NULL_SOURCE};
+use crate::process::ProcessHook;
+use crate::protocol::{encode_header, LocalSession, StreamHeader, StreamName, MAX_STREAM_NAME_LEN, PROTOCOL_VERSION};
+use crate::resample::Resampler;
+use crate::sender::{validate_gain, PacketSender, SenderStats, SharedGain, StreamErrorKind, StreamInfo, DEFAULT_FADE_MS, DEFAULT_HEADER_REPEAT, DEFAULT_HEADER_RESEND_EVERY, DEFAULT_KEEPALIVE_MS, DEFAULT_MAX_PAYLOAD, DEFAULT_SILENCE_THRESHOLD, DEFAULT_WARMUP_MS, MAX_BUNDLE_MS, MAX_FRAMES_PER_PACKET, MAX_PAYLOAD_LIMIT};
+use crate::stats::spawn_stats_reporter;
+use crate::tee::WavTee;
+use crate::transport::{connect_tcp, parse_transport, Transport, TransportKind};
+
+// Tries at reopening a capture device that dropped out unless the caller picks another count
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+// Wait before the first reconnect attempt, doubling after each failure up to the max
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+// How often the server thread looks for a disconnected capture device or failing sends
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+// Packets in a row that may fail to send before it's reported, unless the caller picks another count
+const DEFAULT_MAX_SEND_FAILURES: u32 = 50;
+
+/// Settings for one server run, resolved from the Python arguments.
+#[derive(Clone)]
+struct ServerOptions {
+    target_ip: String,
+    target_port: u16,
+    use_compression: bool,
+    broadcast: bool,
+    source: Option<String>,
+    device_name: Option<String>,
+    device_index: Option<usize>,
+    loopback: bool,
+    resample: bool,
+    // Rate to encode at regardless of the capture rate
+    opus_rate: Option<u32>,
+    frame_ms: f32,
+    frames_per_packet: u8,
+    encoder: EncoderSettings,
+    bind_addr: Option<String>,
+    bind_port: Option<u16>,
+    // Sent to instead of target_ip when set
+    multicast_group: Option<IpAddr>,
+    // Hops multicast may cross, and the interface it leaves through
+    multicast_ttl: u32,
+    multicast_interface: Option<String>,
+    // Mirrored to alongside the main target over UDP
+    extra_targets: Vec<String>,
+    // Bitrate bounds when adapting to reported loss
+    adaptive_bounds: Option<(u32, u32)>,
+    host: Option<String>,
+    max_packets_per_sec: Option<u32>,
+    silence_threshold: Option<f32>,
+    idle_threshold: Option<f32>,
+    fade_ms: f32,
+    // Silence sent in place of a capture device's first audio
+    warmup_ms: u32,
+    pace: bool,
+    tee_wav: Option<String>,
+    buffer_frames: Option<u32>,
+    preferred_rate: Option<u32>,
+    header_repeat: u32,
+    header_resend_every: Option<u64>,
+    // Raw audio goes out as i16 rather than f32
+    raw_i16: bool,
+    // Packets are stamped from stream start on a monotonic clock
+    monotonic_clock: bool,
+    // Raw buffers go out channel by channel rather than interleaved
+    planar: bool,
+    stream_name: Option<StreamName>,
+    // Test tone rate when streaming from the null source instead of a device
+    null_rate: Option<u32>,
+    transport: TransportKind,
+    max_payload: usize,
+    send_buffer_bytes: Option<usize>,
+    gain: SharedGain,
+    limiter: bool,
+    // Python DSP each captured block goes through first
+    process_callback: Option<PyObject>,
+    downmix_mono: bool,
+    channels: Option<u16>,
+    cipher: Option<PacketCipher>,
+    auth: Option<PacketAuth>,
+}
+
+/// Where the server's audio comes from.
+enum Capture {
+    // Boxed since it's far bigger than the null source
+    Device(Box<DeviceCapture>),
+    Null(NullSource),
+}
+
+impl Capture {
+    fn pause(&self) -> Result<(), String> {
+        match self {
+            Capture::Device(capture) => capture.stream.pause().map_err(|e| e.to_string()),
+            Capture::Null(source) => {
+                source.pause();
+                Ok(())
+            }
+        }
+    }
+
+    fn play(&self) -> Result<(), String> {
+        match self {
+            Capture::Device(capture) => capture.stream.play().map_err(|e| e.to_string()),
+            Capture::Null(source) => {
+                source.play();
+                Ok(())
+            }
+        }
+    }
+
+    // Waits for captured audio still queued for the send thread to go out; the
+    // null source sends from its own thread and has nothing queued
+    fn drain(&self) {
+        if let Capture::Device(capture) = self {
+            capture.send_thread.drain();
+        }
+    }
+}
+
+/// How the capture device was picked, kept so it can be reopened after it drops out.
+struct InputSelection {
+    host: Option<String>,
+    source: Option<String>,
+    device_name: Option<String>,
+    device_index: Option<usize>,
+    loopback: bool,
+    channels: Option<u16>,
+    buffer_frames: Option<u32>,
+    preferred_rate: Option<u32>,
+}
+
+/// Where a capture stream's errors go: the log, the on_error callback when there
+/// is one, the stats by kind, and a flag the server thread polls to notice the
+/// device is gone.
+#[derive(Clone)]
+struct StreamErrors {
+    reporter: Option<mpsc::Sender<String>>,
+    stats: Arc<SenderStats>,
+    device_lost: Arc<AtomicBool>,
+}
+
+impl StreamErrors {
+    fn report(&self, message: String) {
+        if let Some(reporter) = &self.reporter {
+            let _ = reporter.send(message);
+        }
+    }
+
+    // Counts the error in the stats as well as reporting it
+    fn record(&self, kind: StreamErrorKind, message: String) {
+        if kind == StreamErrorKind::DeviceLost {
+            self.device_lost.store(true, Ordering::SeqCst);
+        }
+        self.stats.record_stream_error(kind, message.clone());
+        self.report(message);
+    }
+}
+
+/// A stream from a capture device, with what it takes to rebuild it.
+struct DeviceCapture {
+    stream: cpal::Stream,
+    selection: InputSelection,
+    // Format the stream was announced with; a reopened device must match it
+    sample_rate: u32,
+    channels: u16,
+    // Declared after the stream so it stops only once nothing pushes to it
+    queue: CaptureQueue,
+    send_thread: SendThread,
+    errors: StreamErrors,
+}
+
+impl DeviceCapture {
+    fn device_lost(&self) -> bool {
+        self.errors.device_lost.load(Ordering::SeqCst)
+    }
+
+    // Opens the device the way it was first selected, so without a name or index
+    // that's whatever the default is now, and swaps its stream in for the old one
+    fn reopen(&mut self, paused: bool) -> PyResult<()> {
+        let (device, config, sample_format) = open_input(&self.selection)?;
+        if config.sample_rate.0 != self.sample_rate || config.channels != self.channels {
+            return Err(device_error(format!("Device now captures {} Hz, {} channels instead of {} Hz, {} channels", config.sample_rate.0, config.channels, self.sample_rate, self.channels)));
+        }
+        // A fresh flag, so late errors from the old stream don't mark the new one lost
+        let errors = StreamErrors { reporter: self.errors.reporter.clone(), stats: self.errors.stats.clone(), device_lost: Arc::new(AtomicBool::new(false)) };
+        let stream = build_capture_stream(&device, config, sample_format, &self.queue, &errors)?;
+        if !paused {
+            stream.play().map_err(|e| device_error(format!("Play stream failed: {}", e)))?;
+        }
+        self.stream = stream;
+        self.errors = errors;
+        Ok(())
+    }
+}
+
+/// Requests from a handle to its streaming thread; dropping the sender stops the stream.
+pub(crate) enum StreamCommand {
+    Pause,
+    Resume,
+    // Answered with the new stream's info, or why it couldn't be rebuilt
+    Reconfigure(Reconfigure, mpsc::Sender<PyResult<StreamInfo>>),
+}
+
+/// Why a streaming thread ended, as the handle reports it.
+#[derive(Clone, Copy)]
+pub(crate) enum StopReason {
+    // stop() was called or the handle dropped
+    Explicit,
+    Duration,
+    Idle,
+    // The device or network gave out
+    Error,
+    // A WAV file ran out without looping
+    Finished,
+}
+
+impl StopReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            StopReason::Explicit => "explicit",
+            StopReason::Duration => "duration",
+            StopReason::Idle => "idle",
+            StopReason::Error => "error",
+            StopReason::Finished => "finished",
+        }
+    }
+}
+
+/// Set by a streaming thread as it exits.
+pub(crate) type SharedStopReason = Arc<Mutex<Option<StopReason>>>;
+
+/// Settings a running server can change; None keeps the current value.
+pub(crate) struct Reconfigure {
+    use_compression: Option<bool>,
+    frame_ms: Option<f32>,
+    // Some(None) switches back to the encoder's automatic bitrate
+    bitrate_kbps: Option<Option<u32>>,
+    channels: Option<u16>,
+}
+
+impl Reconfigure {
+    fn apply(&self, options: &mut ServerOptions) -> PyResult<()> {
+        // A WAV file can't change format partway through
+        if options.tee_wav.is_some() {
+            return Err(config_error("Can't reconfigure while writing tee_wav"));
+        }
+        if let Some(use_compression) = self.use_compression {
+            if !use_compression && options.adaptive_bounds.is_some() {
+                return Err(config_error("adaptive_bitrate needs use_compression"));
+            }
+            options.use_compression = use_compression;
+        }
+        if let Some(frame_ms) = self.frame_ms {
+            options.frame_ms = frame_ms;
+        }
+        if let Some(bitrate_kbps) = self.bitrate_kbps {
+            if options.adaptive_bounds.is_some() {
+                return Err(config_error("The bitrate follows reported loss with adaptive_bitrate"));
+            }
+            options.encoder.bitrate_kbps = bitrate_kbps;
+        }
+        if let Some(channels) = self.channels {
+            check_channels(channels, options.downmix_mono)?;
+            options.channels = Some(channels);
+        }
+        Ok(())
+    }
+}
+
+/// Handle to a server streaming on a background thread.
+///
+/// Dropping the handle stops the stream just like calling `stop()`.
+#[pyclass]
+pub(crate) struct ServerHandle {
+    commands: Option<mpsc::Sender<StreamCommand>>,
+    thread: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+    gain: SharedGain,
+    info: StreamInfo,
+    stats: Arc<SenderStats>,
+    stop_reason: SharedStopReason,
+}
+
+impl ServerHandle {
+    /// Wraps a streaming thread that follows `commands`, exits once it is
+    /// dropped and records why it stopped in `stop_reason`.
+    pub(crate) fn new(commands: mpsc::Sender<StreamCommand>, thread: JoinHandle<()>, running: Arc<AtomicBool>, gain: SharedGain, info: StreamInfo, stats: Arc<SenderStats>, stop_reason: SharedStopReason) -> Self {
+        ServerHandle { commands: Some(commands), thread: Some(thread), running, gain, info, stats, stop_reason }
+    }
+
+    fn send(&self, command: StreamCommand) {
+        // A stopped server has nothing left to pause
+        if let Some(commands) = &self.commands {
+            let _ = commands.send(command);
+        }
+    }
+}
+
+#[pymethods]
+impl ServerHandle {
+    /// Stops capture, releases the audio device and waits for the server thread to exit.
+    fn stop(&mut self, py: Python) {
+        self.commands.take();
+        if let Some(thread) = self.thread.take() {
+            py.allow_threads(|| {
+                let _ = thread.join();
+            });
+        }
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Stops the server as the `with` block ends, also when it raised; the
+    /// exception still propagates.
+    fn __exit__(&mut self, py: Python, _exc_type: Option<&PyAny>, _exc_value: Option<&PyAny>, _traceback: Option<&PyAny>) -> bool {
+        self.stop(py);
+        false
+    }
+
+    /// Stops sending until `resume()`; the sequence number picks up where it left off.
+    fn pause(&self) {
+        self.send(StreamCommand::Pause);
+    }
+
+    fn resume(&self) {
+        self.send(StreamCommand::Resume);
+    }
+
+    /// Rebuilds capture and the encoder with new settings, keeping the socket,
+    /// bind port and session, then resends the header so receivers switch over.
+    /// Arguments left as None keep their current value; if the new settings
+    /// fail, the old stream carries on and the error is raised.
+    #[pyo3(signature = (use_compression=None, frame_ms=None, bitrate_kbps=None, channels=None))]
+    fn reconfigure(&mut self, py: Python, use_compression: Option<bool>, frame_ms: Option<f32>, bitrate_kbps: Option<BitrateArg>, channels: Option<u16>) -> PyResult<()> {
+        let changes = Reconfigure { use_compression, frame_ms: frame_ms.map(check_frame_ms).transpose()?, bitrate_kbps: bitrate_kbps.map(resolve_bitrate).transpose()?, channels };
+        let commands = self.commands.as_ref().ok_or_else(|| device_error("Server is stopped"))?;
+        let (reply_tx, reply_rx) = mpsc::channel();
+        commands.send(StreamCommand::Reconfigure(changes, reply_tx)).map_err(|_| device_error("Server thread has exited"))?;
+        self.info = py.allow_threads(move || reply_rx.recv()).map_err(|_| device_error("Server thread exited while reconfiguring"))??;
+        info!("Reconfigured to {} Hz, {} channels", self.info.sample_rate, self.info.channels);
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Changes the linear gain applied to outgoing audio; takes effect on the next buffer.
+    fn set_gain(&self, gain: f32) -> PyResult<()> {
+        self.gain.set(validate_gain(gain)?);
+        Ok(())
+    }
+
+    fn get_gain(&self) -> f32 {
+        self.gain.get()
+    }
+
+    /// What was actually negotiated: sample_rate, channels, compression,
+    /// source_sample_rate (before any resampling) and the local bind_addr/bind_port.
+    #[getter]
+    fn stream_info<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        self.info.to_dict(py)
+    }
+
+    /// Packets put on the wire so far, headers excluded. Reading it never
+    /// waits on the audio thread, so it's fine to poll.
+    fn packets_sent(&self) -> u64 {
+        self.stats.packets_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes put on the wire so far by those packets.
+    fn bytes_sent(&self) -> u64 {
+        self.stats.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Capture stream errors so far, counted by kind: device_lost, backend and
+    /// other (e.g. failed reconnects), with last_kind and last_message for the
+    /// latest one. Stays readable after the server stops.
+    fn stream_errors<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        self.stats.stream_errors_dict(py)
+    }
+
+    /// Why the stream ended: "explicit" (stop() or the handle dropped),
+    /// "duration", "idle", "error" or, for a WAV file, "finished". None while
+    /// it's still running.
+    #[getter]
+    fn stop_reason(&self) -> Option<&'static str> {
+        self.stop_reason.lock().unwrap().map(StopReason::as_str)
+    }
+}
+
+/// Argument-list form of `start_audio_server_with_config`, kept for existing callers.
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn start_audio_server(
+    py: Python,
+    target_ip: String,
+    target_port: u16,
+    use_compression: Option<bool>,
+    broadcast: Option<bool>,
+    source: Option<String>,
+    device_name: Option<String>,
+    resample: Option<bool>,
+    frame_ms: Option<f32>,
+    bitrate_kbps: Option<BitrateArg>,
+    opus_application: Option<String>,
+    bind_addr: Option<String>,
+    bind_port: Option<u16>,
+    stats_callback: Option<PyObject>,
+    enable_fec: Option<bool>,
+    packet_loss_perc: Option<u8>,
+    enable_dtx: Option<bool>,
+    multicast_group: Option<String>,
+    transport: Option<String>,
+    max_payload: Option<usize>,
+    gain: Option<f32>,
+    downmix_mono: Option<bool>,
+    channels: Option<u16>,
+    encryption_key: Option<String>,
+    psk: Option<String>,
+    keepalive_ms: Option<u32>,
+    targets: Option<Vec<(String, u16)>>,
+    adaptive_bitrate: Option<bool>,
+    min_kbps: Option<u32>,
+    max_kbps: Option<u32>,
+    host: Option<String>,
+    max_packets_per_sec: Option<u32>,
+    duration_secs: Option<u64>,
+    skip_silence: Option<bool>,
+    silence_threshold: Option<f32>,
+    sample_rate: Option<u32>,
+    complexity: Option<i32>,
+    max_bandwidth: Option<String>,
+    device_index: Option<usize>,
+    fade_ms: Option<f32>,
+    loopback: Option<bool>,
+    pace: Option<bool>,
+    signal: Option<String>,
+    tee_wav: Option<String>,
+    buffer_frames: Option<u32>,
+    header_repeat: Option<u32>,
+    header_resend_every: Option<u64>,
+    on_error: Option<PyObject>,
+    reconnect_attempts: Option<u32>,
+    raw_format: Option<String>,
+    preferred_rate: Option<u32>,
+    send_buffer_bytes: Option<usize>,
+    use_monotonic_clock: Option<bool>,
+    max_send_failures: Option<u32>,
+    stop_on_send_failure: Option<bool>,
+    channel_layout: Option<String>,
+    stream_name: Option<String>,
+    advertise: Option<bool>,
+    multicast_ttl: Option<u32>,
+    multicast_interface: Option<String>,
+    limiter: Option<bool>,
+    process_callback: Option<PyObject>,
+    max_idle_secs: Option<u64>,
+    frames_per_packet: Option<u8>,
+    opus_rate: Option<u32>,
+    warmup_ms: Option<u32>,
+) -> PyResult<ServerHandle> {
+    start_server(py, ServerConfig {
+        target_ip,
+        target_port,
+        use_compression,
+        broadcast,
+        source,
+        device_name,
+        resample,
+        frame_ms,
+        bitrate_kbps,
+        opus_application,
+        bind_addr,
+        bind_port,
+        stats_callback,
+        enable_fec,
+        packet_loss_perc,
+        enable_dtx,
+        multicast_group,
+        transport,
+        max_payload,
+        gain,
+        downmix_mono,
+        channels,
+        encryption_key,
+        psk,
+        keepalive_ms,
+        targets,
+        adaptive_bitrate,
+        min_kbps,
+        max_kbps,
+        host,
+        max_packets_per_sec,
+        duration_secs,
+        skip_silence,
+        silence_threshold,
+        sample_rate,
+        complexity,
+        max_bandwidth,
+        device_index,
+        fade_ms,
+        loopback,
+        pace,
+        signal,
+        tee_wav,
+        buffer_frames,
+        header_repeat,
+        header_resend_every,
+        on_error,
+        reconnect_attempts,
+        raw_format,
+        preferred_rate,
+        send_buffer_bytes,
+        use_monotonic_clock,
+        max_send_failures,
+        stop_on_send_failure,
+        channel_layout,
+        stream_name,
+        advertise,
+        multicast_ttl,
+        multicast_interface,
+        limiter,
+        process_callback,
+        max_idle_secs,
+        frames_per_packet,
+        opus_rate,
+        warmup_ms,
+    })
+}
+
+/// Starts streaming with every setting taken from `config`.
+#[pyfunction]
+pub(crate) fn start_audio_server_with_config(py: Python, config: PyRef<ServerConfig>) -> PyResult<ServerHandle> {
+    start_server(py, config.clone())
+}
+
+fn start_server(py: Python, config: ServerConfig) -> PyResult<ServerHandle> {
+    let ServerConfig { target_ip, target_port, use_compression, broadcast, source, device_name, resample, frame_ms, bitrate_kbps, opus_application, bind_addr, bind_port, stats_callback, enable_fec, packet_loss_perc, enable_dtx, multicast_group, transport, max_payload, gain, downmix_mono, channels, encryption_key, psk, keepalive_ms, targets, adaptive_bitrate, min_kbps, max_kbps, host, max_packets_per_sec, duration_secs, skip_silence, silence_threshold, sample_rate, complexity, max_bandwidth, device_index, fade_ms, loopback, pace, signal, tee_wav, buffer_frames, header_repeat, header_resend_every, on_error, reconnect_attempts, raw_format, preferred_rate, send_buffer_bytes, use_monotonic_clock, max_send_failures, stop_on_send_failure, channel_layout, stream_name, advertise, multicast_ttl, multicast_interface, limiter, process_callback, max_idle_secs, frames_per_packet, opus_rate, warmup_ms } = config;
+    let frame_ms = check_frame_ms(frame_ms.unwrap_or(20.0))?;
+    let bitrate_kbps = bitrate_kbps.map(resolve_bitrate).transpose()?.flatten();
+    let opus_application_name = opus_application.unwrap_or_else(|| "audio".to_string());
+    let opus_application = parse_opus_application(&opus_application_name).ok_or_else(|| config_error(format!("Unknown Opus application '{}' (use \"voip\", \"audio\" or \"lowdelay\")", opus_application_name)))?;
+
+    let complexity = match complexity {
+        None => None,
+        Some(level @ 0..=10) => Some(level as u8),
+        Some(level) => return Err(config_error(format!("Opus complexity {} out of range (0-10)", level))),
+    };
+    let max_bandwidth = max_bandwidth
+        .map(|name| parse_bandwidth(&name).ok_or_else(|| config_error(format!("Unknown max_bandwidth '{}' (use \"narrow\", \"medium\", \"wide\", \"superwide\" or \"full\")", name))))
+        .transpose()?;
+    let signal_name = signal.unwrap_or_else(|| "auto".to_string());
+    let signal = parse_signal(&signal_name).ok_or_else(|| config_error(format!("Unknown signal '{}' (use \"auto\", \"voice\" or \"music\")", signal_name)))?;
+
+    // Receivers only recover lost frames if their decoder also requests FEC
+    let fec_loss_perc = if enable_fec.unwrap_or(false) {
+        let loss_perc = packet_loss_perc.unwrap_or(10);
+        if loss_perc > 100 {
+            return Err(config_error(format!("Packet loss {}% out of range (0-100)", loss_perc)));
+        }
+        Some(loss_perc)
+    } else {
+        None
+    };
+
+    let multicast_group = multicast_group
+        .map(|group| parse_multicast_group(&group).ok_or_else(|| config_error(format!("'{}' is not a multicast address (224.0.0.0/4 or ff00::/8)", group))))
+        .transpose()?;
+    if multicast_group.is_none() && (multicast_ttl.is_some() || multicast_interface.is_some()) {
+        return Err(config_error("multicast_ttl and multicast_interface need a multicast_group"));
+    }
+    let multicast_ttl = multicast_ttl.unwrap_or(DEFAULT_MULTICAST_TTL);
+    if multicast_ttl > 255 {
+        return Err(config_error(format!("multicast_ttl {} must be between 0 and 255", multicast_ttl)));
+    }
+
+    let transport_name = transport.unwrap_or_else(|| "udp".to_string());
+    let transport = parse_transport(&transport_name).ok_or_else(|| config_error(format!("Unknown transport '{}' (use \"udp\" or \"tcp\")", transport_name)))?;
+    // A TCP connection only ever reaches one receiver
+    if transport == TransportKind::Tcp && (broadcast == Some(true) || multicast_group.is_some()) {
+        return Err(config_error("Broadcast and multicast need the \"udp\" transport"));
+    }
+    // Announced as a UDP service, and a TCP sender has only one receiver anyway
+    let advertise = advertise.unwrap_or(false);
+    if transport == TransportKind::Tcp && advertise {
+        return Err(config_error("advertise needs the \"udp\" transport"));
+    }
+    let extra_targets: Vec<String> = targets.unwrap_or_default().iter().map(|(ip, port)| socket_address(ip, *port)).collect();
+    if transport == TransportKind::Tcp && !extra_targets.is_empty() {
+        return Err(config_error("Multiple targets need the \"udp\" transport"));
+    }
+
+    // Loss reports only come back over UDP, and only Opus has a bitrate to adapt
+    let adaptive_bounds = if adaptive_bitrate.unwrap_or(false) {
+        let min_kbps = min_kbps.unwrap_or(DEFAULT_MIN_KBPS);
+        let max_kbps = max_kbps.unwrap_or(DEFAULT_MAX_KBPS);
+        if min_kbps < 6 || max_kbps > 510 || min_kbps > max_kbps {
+            return Err(config_error(format!("Adaptive bitrate bounds {}-{} kbps invalid (need 6 <= min_kbps <= max_kbps <= 510)", min_kbps, max_kbps)));
+        }
+        if use_compression != Some(true) {
+            return Err(config_error("adaptive_bitrate needs use_compression"));
+        }
+        if transport == TransportKind::Tcp {
+            return Err(config_error("adaptive_bitrate needs the \"udp\" transport"));
+        }
+        Some((min_kbps, max_kbps))
+    } else {
+        None
+    };
+    // Start from the requested bitrate, or the top of the range, and adapt from there
+    let bitrate_kbps = adaptive_bounds.map_or(bitrate_kbps, |(min_kbps, max_kbps)| Some(bitrate_kbps.unwrap_or(max_kbps).clamp(min_kbps, max_kbps)));
+
+    // Leaves room for IP/UDP headers and the packet prefix inside a 1500-byte MTU
+    let max_payload = max_payload.unwrap_or(DEFAULT_MAX_PAYLOAD);
+    if !(64..=MAX_PAYLOAD_LIMIT).contains(&max_payload) {
+        return Err(config_error(format!("max_payload {} out of range (64-{} bytes)", max_payload, MAX_PAYLOAD_LIMIT)));
+    }
+
+    let raw_i16 = match raw_format.as_deref() {
+        None | Some("f32") => false,
+        Some("i16") => true,
+        Some(other) => return Err(config_error(format!("Unknown raw_format '{}' (use \"f32\" or \"i16\")", other))),
+    };
+    let planar = match channel_layout.as_deref() {
+        None | Some("interleaved") => false,
+        Some("planar") => true,
+        Some(other) => return Err(config_error(format!("Unknown channel_layout '{}' (use \"interleaved\" or \"planar\")", other))),
+    };
+
+    let stream_name = stream_name.filter(|name| !name.is_empty()).map(|name| {
+        let truncated = StreamName::new(&name);
+        if truncated.as_str().len() < name.len() {
+            warn!("stream_name is longer than {} bytes, sending '{}'", MAX_STREAM_NAME_LEN, truncated.as_str());
+        }
+        truncated
+    });
+
+    let max_send_failures = max_send_failures.unwrap_or(DEFAULT_MAX_SEND_FAILURES) as u64;
+    if max_send_failures == 0 {
+        return Err(config_error("max_send_failures must be at least 1"));
+    }
+    let stop_on_send_failure = stop_on_send_failure.unwrap_or(false);
+
+    if send_buffer_bytes == Some(0) {
+        return Err(config_error("send_buffer_bytes must be at least 1"));
+    }
+
+    if max_packets_per_sec == Some(0) {
+        return Err(config_error("max_packets_per_sec must be at least 1"));
+    }
+
+    let silence_threshold = silence_threshold.unwrap_or(DEFAULT_SILENCE_THRESHOLD);
+    if !silence_threshold.is_finite() || silence_threshold < 0.0 {
+        return Err(config_error(format!("silence_threshold {} must be a finite number >= 0", silence_threshold)));
+    }
+    if max_idle_secs == Some(0) {
+        return Err(config_error("max_idle_secs must be at least 1"));
+    }
+    // Idle means nothing above the level skip_silence would drop
+    let idle_threshold = max_idle_secs.map(|_| silence_threshold);
+    let silence_threshold = skip_silence.unwrap_or(false).then_some(silence_threshold);
+
+    let fade_ms = fade_ms.unwrap_or(DEFAULT_FADE_MS);
+    if !(0.0..=1000.0).contains(&fade_ms) {
+        return Err(config_error(format!("fade_ms {} out of range (0-1000)", fade_ms)));
+    }
+
+    let header_repeat = header_repeat.unwrap_or(DEFAULT_HEADER_REPEAT);
+    if header_repeat == 0 {
+        return Err(config_error("header_repeat must be at least 1"));
+    }
+    // 0 leaves the header to the start of the stream
+    let header_resend_every = Some(header_resend_every.unwrap_or(DEFAULT_HEADER_RESEND_EVERY)).filter(|&every| every > 0);
+
+    if buffer_frames == Some(0) {
+        return Err(config_error("buffer_frames must be at least 1"));
+    }
+    if buffer_frames.is_some() && source.as_deref() == Some(NULL_SOURCE) {
+        return Err(config_error("buffer_frames only applies to capture devices, not source=\"null\""));
+    }
+    if preferred_rate.is_some() && source.as_deref() == Some(NULL_SOURCE) {
+        return Err(config_error("preferred_rate only applies to capture devices; use sample_rate with source=\"null\""));
+    }
+    if let Some(rate) = preferred_rate.filter(|rate| !(8000..=192_000).contains(rate)) {
+        return Err(config_error(format!("preferred_rate {} Hz out of range (8000-192000)", rate)));
+    }
+    if let Some(rate) = opus_rate.filter(|&rate| opus_sample_rate(rate).is_none()) {
+        return Err(config_error(format!("opus_rate {} Hz not supported by Opus (supported: 8k, 12k, 16k, 24k, 48k)", rate)));
+    }
+    if opus_rate.is_some() && resample == Some(false) {
+        return Err(config_error("opus_rate resamples to the rate it names; leave resample on"));
+    }
+
+    if device_name.is_some() && device_index.is_some() {
+        return Err(config_error("Give device_name or device_index, not both"));
+    }
+    let loopback = loopback.unwrap_or(false);
+    if loopback && device_index.is_some() {
+        return Err(config_error("loopback picks an output device by device_name, not device_index"));
+    }
+    if loopback && source.as_deref().is_some_and(|source| source != "output") {
+        return Err(config_error("loopback captures an output device; leave source unset or \"output\""));
+    }
+
+    let null_rate = match (source.as_deref() == Some(NULL_SOURCE), sample_rate) {
+        (true, rate) => Some(rate.unwrap_or(NULL_DEFAULT_RATE)),
+        (false, Some(_)) => return Err(config_error("sample_rate only applies to source=\"null\"; devices run at their own rate")),
+        (false, None) => None,
+    };
+    if let Some(rate) = null_rate.filter(|rate| !(8000..=192_000).contains(rate)) {
+        return Err(config_error(format!("sample_rate {} Hz out of range (8000-192000)", rate)));
+    }
+
+    let gain = SharedGain::new(validate_gain(gain.unwrap_or(1.0))?);
+
+    let downmix_mono = downmix_mono.unwrap_or(false);
+    if let Some(count) = channels {
+        check_channels(count, downmix_mono)?;
+    }
+
+    // Hides the audio from anyone without the key; it doesn't authenticate the sender
+    let cipher = encryption_key.map(|key| cipher_from_key(&key)).transpose()?;
+    // Lets receivers reject audio injected by hosts that don't know the psk
+    let auth = psk.map(|psk| auth_from_psk(&psk)).transpose()?;
+
+    // 0 turns keepalives off
+    let keepalive = Some(keepalive_ms.unwrap_or(DEFAULT_KEEPALIVE_MS)).filter(|&ms| ms > 0).map(|ms| Duration::from_millis(ms as u64));
+    // 0 stops the server as soon as the capture device goes away
+    let reconnect_attempts = reconnect_attempts.unwrap_or(DEFAULT_RECONNECT_ATTEMPTS);
+
+    let options = ServerOptions {
+        target_ip,
+        target_port,
+        use_compression: use_compression.unwrap_or(false),
+        broadcast: broadcast.unwrap_or(false),
+        source,
+        device_name,
+        device_index,
+        loopback,
+        resample: resample.unwrap_or(true),
+        opus_rate,
+        frame_ms,
+        frames_per_packet: frames_per_packet.unwrap_or(1),
+        encoder: EncoderSettings { application: opus_application, bitrate_kbps, fec_loss_perc, dtx: enable_dtx.unwrap_or(false), complexity, max_bandwidth, signal },
+        bind_addr,
+        bind_port,
+        multicast_group,
+        multicast_ttl,
+        multicast_interface,
+        extra_targets,
+        adaptive_bounds,
+        host,
+        max_packets_per_sec,
+        silence_threshold,
+        idle_threshold,
+        fade_ms,
+        warmup_ms: warmup_ms.unwrap_or(DEFAULT_WARMUP_MS),
+        pace: pace.unwrap_or(false),
+        tee_wav,
+        buffer_frames,
+        preferred_rate,
+        header_repeat,
+        header_resend_every,
+        raw_i16,
+        monotonic_clock: use_monotonic_clock.unwrap_or(false),
+        planar,
+        stream_name,
+        null_rate,
+        transport,
+        max_payload,
+        send_buffer_bytes,
+        gain: gain.clone(),
+        limiter: limiter.unwrap_or(false),
+        process_callback,
+        downmix_mono,
+        channels,
+        cipher,
+        auth,
+    };
+
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let (command_tx, command_rx) = mpsc::channel();
+    let running = Arc::new(AtomicBool::new(false));
+    let running_clone = running.clone();
+    let stop_reason = SharedStopReason::default();
+    let stop_reason_clone = stop_reason.clone();
+
+    // cpal streams can't move between threads, so the stream is built and dropped on its own thread
+    let thread = thread::spawn(move || {
+        let (stream_errors, error_reporter) = on_error.map(spawn_error_reporter).unzip();
+        let send_errors = stream_errors.clone();
+        let mut options = options;
+        let (mut capture, mut sender, feedback) = match build_server_stream(options.clone(), None, stream_errors) {
+            Ok(built) => built,
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+        running_clone.store(true, Ordering::SeqCst);
+        let stats = sender.lock().unwrap().stats();
+        let _ = ready_tx.send(Ok((sender.lock().unwrap().stream_info(), stats.clone())));
+        let reporter = stats_callback.map(|callback| spawn_stats_reporter(stats.clone(), callback, running_clone.clone()));
+        // Kept so HELLOs are answered with the new header after a reconfigure
+        let announced = feedback.as_ref().map(|feedback| feedback.header.clone());
+        let feedback = feedback.map(|feedback| feedback.spawn(running_clone.clone()));
+        let advertisement = advertise.then(|| advertise_stream(&sender.lock().unwrap(), &options)).flatten();
+
+        // Runs until stop() is called, the handle is dropped, `duration_secs` is up
+        // or capture has been silent for `max_idle_secs`, checking for idle periods
+        // a few times per keepalive interval
+        let deadline = duration_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+        let max_idle = max_idle_secs.map(Duration::from_secs);
+        // Silence while paused doesn't count towards max_idle_secs
+        let mut active_since = Instant::now();
+        let mut paused = false;
+        // Attempts made since the device dropped out, and when the next one is due
+        let mut reconnect: Option<(u32, Instant)> = None;
+        // Set once a run of send failures has been reported, until a packet gets through
+        let mut send_failing = false;
+        let reason = loop {
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                info!("Duration reached, stopping server");
+                break StopReason::Duration;
+            }
+            if let Some(max_idle) = max_idle.filter(|_| !paused) {
+                let silent_for = sender.lock().map_or(Duration::ZERO, |sender| sender.silent_for());
+                if silent_for.min(now - active_since) >= max_idle {
+                    info!("No audio for {}s, stopping server", max_idle.as_secs());
+                    break StopReason::Idle;
+                }
+            }
+            let failures = stats.consecutive_send_failures.load(Ordering::Relaxed);
+            if failures >= max_send_failures && !send_failing {
+                let message = format!("{} packets in a row failed to send: {}", failures, stats.last_send_error());
+                error!("{}", message);
+                if let Some(errors) = &send_errors {
+                    let _ = errors.send(message);
+                }
+                if stop_on_send_failure {
+                    error!("Network unusable, stopping server");
+                    break StopReason::Error;
+                }
+                send_failing = true;
+            } else if failures == 0 && send_failing {
+                info!("Packets are getting through again");
+                send_failing = false;
+            }
+            if let Capture::Device(device) = &mut capture {
+                if reconnect.is_none() && device.device_lost() {
+                    if reconnect_attempts == 0 {
+                        error!("Capture device lost, stopping server");
+                        break StopReason::Error;
+                    }
+                    warn!("Capture device lost, reconnecting");
+                    reconnect = Some((0, now + RECONNECT_INITIAL_DELAY));
+                }
+                if let Some((attempts, _)) = reconnect.filter(|&(_, due)| now >= due) {
+                    let attempt = attempts + 1;
+                    match device.reopen(paused) {
+                        Ok(()) => {
+                            info!("Capture device reconnected");
+                            device.errors.report(format!("Reconnect attempt {}/{} succeeded", attempt, reconnect_attempts));
+                            reconnect = None;
+                        }
+                        Err(e) => {
+                            warn!("Reconnect attempt {}/{} failed: {}", attempt, reconnect_attempts, e);
+                            device.errors.record(StreamErrorKind::Other, format!("Reconnect attempt {}/{} failed: {}", attempt, reconnect_attempts, e));
+                            if attempt >= reconnect_attempts {
+                                error!("Capture device didn't come back, stopping server");
+                                break StopReason::Error;
+                            }
+                            reconnect = Some((attempt, now + reconnect_delay(attempt)));
+                        }
+                    }
+                }
+            }
+            // Checked regularly so a lost device or a dead route is noticed without a keepalive or deadline
+            let reconnect_due = reconnect.map(|(_, due)| due.saturating_duration_since(now));
+            let wait = [keepalive.map(|interval| interval / 4), deadline.map(|deadline| deadline - now), reconnect_due].into_iter().flatten().fold(HEALTH_CHECK_INTERVAL, Duration::min);
+            let command = command_rx.recv_timeout(wait);
+            match command {
+                // A paused source stops delivering audio, so nothing is sent or counted
+                Ok(StreamCommand::Pause) => match capture.pause() {
+                    Ok(()) => {
+                        paused = true;
+                        info!("Server paused");
+                    }
+                    Err(e) => error!("Pause failed: {}", e),
+                },
+                Ok(StreamCommand::Resume) => match capture.play() {
+                    Ok(()) => {
+                        paused = false;
+                        active_since = Instant::now();
+                        info!("Server resumed");
+                    }
+                    Err(e) => error!("Resume failed: {}", e),
+                },
+                Ok(StreamCommand::Reconfigure(changes, reply)) => {
+                    let rebuilt = rebuild_server_stream(&mut options, &changes, &mut capture, &mut sender, paused, send_errors.clone());
+                    if let (Ok(_), Some(announced)) = (&rebuilt, &announced) {
+                        *announced.lock().unwrap() = sender.lock().unwrap().encoded_header();
+                    }
+                    if let Err(e) = &rebuilt {
+                        warn!("Reconfigure failed, keeping the current stream: {}", e);
+                    }
+                    let _ = reply.send(rebuilt);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let (Some(interval), Ok(mut sender)) = (keepalive, sender.lock()) {
+                        sender.send_keepalive_if_idle(interval);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break StopReason::Explicit,
+            }
+        };
+        // Stop capture first so the final packet really is the last one
+        if let Err(e) = capture.pause() {
+            warn!("Stopping capture failed: {}", e);
+        }
+        capture.drain();
+        if let Ok(mut sender) = sender.lock() {
+            sender.finish();
+        }
+        drop(capture);
+        drop(advertisement);
+        // The reporter below runs until the last sender on its channel is gone
+        drop(send_errors);
+        *stop_reason_clone.lock().unwrap() = Some(reason);
+        running_clone.store(false, Ordering::SeqCst);
+        // The error reporter ends once the stream that fed it is gone
+        for helper in reporter.into_iter().chain(feedback).chain(error_reporter) {
+            let _ = helper.join();
+        }
+        info!("Server stopped");
+    });
+
+    // Release GIL while the stream starts up
+    let (info, stats) = py.allow_threads(move || ready_rx.recv())
+        .map_err(|_| device_error("Server thread exited unexpectedly"))??;
+    info!("Negotiated {} Hz, {} channels", info.sample_rate, info.channels);
+
+    Ok(ServerHandle::new(command_tx, thread, running, gain, info, stats, stop_reason))
+}
+
+// Swaps in a capture and sender built from `options` with `changes` applied,
+// carrying on the old stream's socket, SESSION and sequence, and announces the
+// new header. On failure the old stream is left running as it was.
+fn rebuild_server_stream(options: &mut ServerOptions, changes: &Reconfigure, capture: &mut Capture, sender: &mut Arc<Mutex<PacketSender>>, paused: bool, stream_errors: Option<mpsc::Sender<String>>) -> PyResult<StreamInfo> {
+    let mut next = options.clone();
+    changes.apply(&mut next)?;
+    // Nothing more goes out under the old header once the new one is announced
+    capture.pause().map_err(|e| device_error(format!("Pause failed: {}", e)))?;
+    capture.drain();
+    let built = build_server_stream(next.clone(), Some(&*sender.lock().unwrap()), stream_errors);
+    let (rebuilt, rebuilt_sender, _) = match built {
+        Ok(built) => built,
+        Err(e) => {
+            if !paused {
+                capture.play().map_err(|e| device_error(format!("Resume failed: {}", e)))?;
+            }
+            return Err(e);
+        }
+    };
+    if paused {
+        rebuilt.pause().map_err(|e| device_error(format!("Pause failed: {}", e)))?;
+    }
+    *capture = rebuilt;
+    *sender = rebuilt_sender;
+    *options = next;
+    Ok(sender.lock().unwrap().stream_info())
+}
+
+// Announces the server over mDNS; best effort, since the stream works without
+// it, just not discoverably
+fn advertise_stream(sender: &PacketSender, options: &ServerOptions) -> Option<Advertisement> {
+    let bind_port = sender.stream_info().local_addr.map_or(0, |addr| addr.port());
+    let session_id = sender.session().map_or(0, LocalSession::id);
+    match Advertisement::start(session_id, bind_port, options.target_port, options.stream_name.as_ref().map(StreamName::as_str)) {
+        Ok(advertisement) => {
+            info!("Advertising the stream over mDNS");
+            Some(advertisement)
+        }
+        Err(e) => {
+            warn!("mDNS advertisement failed: {}", e);
+            None
+        }
+    }
+}
+
+fn check_frame_ms(frame_ms: f32) -> PyResult<f32> {
+    if !OPUS_FRAME_DURATIONS_MS.contains(&frame_ms) {
+        return Err(config_error(format!("Frame size {} ms not supported by Opus (allowed: 2.5, 5, 10, 20, 40, 60)", frame_ms)));
+    }
+    Ok(frame_ms)
+}
+
+fn check_frames_per_packet(frames: u8, frame_ms: f32) -> PyResult<()> {
+    if !(1..=MAX_FRAMES_PER_PACKET).contains(&frames) {
+        return Err(config_error(format!("frames_per_packet {} out of range (1-{})", frames, MAX_FRAMES_PER_PACKET)));
+    }
+    if frames as f32 * frame_ms > MAX_BUNDLE_MS {
+        return Err(config_error(format!("{} frames of {} ms is more than the {} ms a packet can bundle", frames, frame_ms, MAX_BUNDLE_MS)));
+    }
+    Ok(())
+}
+
+// The cap coalesces raw buffers into fewer sends, which only keeps datagrams
+// under it while a second of audio fits in that many max_payload packets
+fn check_packet_rate(max_packets_per_sec: u32, header: &StreamHeader, max_payload: usize) -> PyResult<()> {
+    let packet_frames = (max_payload / (header.channels as usize * header.raw_sample_bytes())).max(1) as u64;
+    let needed = (header.sample_rate as u64).div_ceil(packet_frames);
+    if needed > max_packets_per_sec as u64 {
+        return Err(config_error(format!("max_packets_per_sec {} is too low for raw {} Hz {}-channel audio in {}-byte packets; it needs at least {}", max_packets_per_sec, header.sample_rate, header.channels, max_payload, needed)));
+    }
+    Ok(())
+}
+
+// None for "auto", which leaves the encoder to pick
+fn resolve_bitrate(bitrate_kbps: BitrateArg) -> PyResult<Option<u32>> {
+    match bitrate_kbps {
+        BitrateArg::Named(name) if name == "auto" => Ok(None),
+        BitrateArg::Named(name) => Err(config_error(format!("Unknown bitrate '{}' (use kbps or \"auto\")", name))),
+        BitrateArg::Kbps(kbps) if (6..=510).contains(&kbps) => Ok(Some(kbps)),
+        BitrateArg::Kbps(kbps) => Err(config_error(format!("Bitrate {} kbps out of range (Opus supports 6-510 kbps)", kbps))),
+    }
+}
+
+fn check_channels(channels: u16, downmix_mono: bool) -> PyResult<()> {
+    match channels {
+        0 => Err(config_error("Channel count must be at least 1")),
+        count if downmix_mono && count != 1 => Err(config_error(format!("downmix_mono conflicts with channels={}", count))),
+        _ => Ok(()),
+    }
+}
+
+// With `previous`, the stream carries on from that sender on its socket instead
+// of opening a new one, and no feedback listener is returned: the first one
+// keeps running.
+fn build_server_stream(options: ServerOptions, previous: Option<&PacketSender>, stream_errors: Option<mpsc::Sender<String>>) -> PyResult<(Capture, Arc<Mutex<PacketSender>>, Option<FeedbackListener>)> {
+    let ServerOptions { target_ip, target_port, use_compression, broadcast, source, device_name, device_index, loopback, resample, opus_rate, frame_ms, frames_per_packet, encoder, bind_addr, bind_port, multicast_group, multicast_ttl, multicast_interface, extra_targets, adaptive_bounds, host, max_packets_per_sec, silence_threshold, idle_threshold, fade_ms, warmup_ms, pace, tee_wav, buffer_frames, preferred_rate, header_repeat, header_resend_every, raw_i16, monotonic_clock, planar, stream_name, null_rate, transport, max_payload, send_buffer_bytes, gain, limiter, process_callback, downmix_mono, channels: requested_channels, cipher, auth } = options;
+    // Here rather than at start so a reconfigured frame_ms is checked too
+    check_frames_per_packet(frames_per_packet, frame_ms)?;
+    let target_ip = multicast_group.map_or(target_ip, |group| group.to_string());
+
+    let target_addr = socket_address(&target_ip, target_port);
+    let mut target_addrs = vec![target_addr.clone()];
+    for extra in extra_targets {
+        if !target_addrs.contains(&extra) {
+            target_addrs.push(extra);
+        }
+    }
+    let mut feedback_socket = None;
+    let transport = match (previous, transport) {
+        // Receivers keep hearing from the same address and port
+        (Some(previous), _) => previous.clone_transport().map_err(|e| socket_error(format!("Socket clone failed: {}", e)))?,
+        (None, TransportKind::Udp) => {
+            let socket = bind_sender_socket(bind_addr.as_deref(), bind_port, &target_ip)?;
+            // HELLOs and loss reports arrive on the socket the audio leaves from
+            feedback_socket = Some(socket.try_clone().map_err(|e| socket_error(format!("Socket clone failed: {}", e)))?);
+
+            if broadcast {
+                socket.set_broadcast(true).map_err(|e| socket_error(format!("Broadcast enable failed: {}", e)))?;
+                info!("Broadcast mode enabled");
+            }
+            if let Some(group) = multicast_group {
+                configure_multicast_sender(&socket, group, multicast_ttl, multicast_interface.as_deref())?;
+                info!("Multicast mode enabled (TTL {})", multicast_ttl);
+            }
+            if let Some(bytes) = send_buffer_bytes {
+                info!("Send buffer: {} bytes ({} requested)", set_send_buffer(SockRef::from(&socket), bytes)?, bytes);
+            }
+            Transport::Udp { socket, target_addrs: target_addrs.clone() }
+        }
+        (None, TransportKind::Tcp) => {
+            let stream = connect_tcp(bind_addr.as_deref(), bind_port, &target_addr)?;
+            info!("Connected over TCP");
+            if let Some(bytes) = send_buffer_bytes {
+                info!("Send buffer: {} bytes ({} requested)", set_send_buffer(SockRef::from(&stream), bytes)?, bytes);
+            }
+            Transport::Tcp(stream)
+        }
+    };
+    info!("Streaming audio to: {}", target_addrs.join(", "));
+
+    let selection = InputSelection { host, source, device_name, device_index, loopback, channels: requested_channels, buffer_frames, preferred_rate };
+    // The null source makes up its own audio in whatever format was asked for
+    let input = match null_rate {
+        Some(_) => None,
+        None => Some(open_input(&selection)?),
+    };
+    let (sample_rate, capture_channels) = match (&input, null_rate) {
+        (Some((_, config, _)), _) => (config.sample_rate.0, config.channels),
+        (None, rate) => {
+            let (rate, channels) = (rate.unwrap_or(NULL_DEFAULT_RATE), requested_channels.unwrap_or(NULL_DEFAULT_CHANNELS));
+            info!("Streaming a test tone: {} Hz, {} channels", rate, channels);
+            (rate, channels)
+        }
+    };
+
+    // Everything after the remix, from resampling to the header, sees the wire channel count
+    let channels = match requested_channels {
+        Some(requested) if requested == capture_channels => requested,
+        Some(1) => {
+            info!("Downmixing {} channels to mono", capture_channels);
+            1
+        }
+        Some(requested) if capture_channels == 1 => {
+            info!("Duplicating mono capture to {} channels", requested);
+            requested
+        }
+        Some(requested) => return Err(device_error(format!("Device can't provide {} channels (it captures {})", requested, capture_channels))),
+        None if downmix_mono && capture_channels > 1 => {
+            info!("Downmixing {} channels to mono", capture_channels);
+            1
+        }
+        None => capture_channels,
+    };
+
+    // Opus carries mono or stereo only; surround goes out raw with every channel intact
+    let use_compression = if use_compression && opus_channels(channels).is_none() {
+        warn!("{} channels not supported by Opus (1 or 2 only). Falling back to raw audio.", channels);
+        false
+    } else {
+        use_compression
+    };
+
+    // Opus only runs at a handful of rates, so convert anything else to 48kHz
+    // unless told not to; opus_rate picks the rate whatever the capture runs at
+    let stream_rate = match opus_rate.filter(|_| use_compression) {
+        Some(rate) => rate,
+        None if use_compression && resample && opus_sample_rate(sample_rate).is_none() => OPUS_RESAMPLE_RATE,
+        None => sample_rate,
+    };
+    if stream_rate != sample_rate {
+        info!("Resampling {} Hz to {} Hz for Opus", sample_rate, stream_rate);
+    }
+    let resampler = (stream_rate != sample_rate).then(|| Resampler::new(sample_rate, stream_rate, channels));
+
+    // Without resampling an unsupported rate can't be encoded, so send raw audio
+    // instead; decided here so the announced header already says so
+    let use_compression = if use_compression && opus_sample_rate(stream_rate).is_none() {
+        warn!("Sample rate {} Hz not supported by Opus (supported: 8k, 12k, 16k, 24k, 48k). Falling back to raw audio.", stream_rate);
+        false
+    } else {
+        use_compression
+    };
+
+    let adaptive_bounds = adaptive_bounds.filter(|_| use_compression);
+    // A reconfigured stream keeps adapting from the bitrate it had reached
+    let previous_bitrate = previous.and_then(PacketSender::shared_bitrate).filter(|_| adaptive_bounds.is_some());
+    let encoder = match &previous_bitrate {
+        Some(bitrate) => EncoderSettings { bitrate_kbps: Some(bitrate.get()), ..encoder },
+        None => encoder,
+    };
+
+    // Initialize Opus encoder if compression is enabled
+    let opus_encoder = if use_compression {
+        Some(new_opus_encoder(stream_rate, channels, &encoder)?)
+    } else {
+        None
+    };
+
+    // A reconfigured stream keeps its SESSION, so receivers take the new header as a change of format
+    let session = previous.and_then(PacketSender::session).cloned().unwrap_or_else(LocalSession::start);
+    let header = StreamHeader {
+        version: PROTOCOL_VERSION,
+        sample_rate: stream_rate,
+        channels,
+        compressed: use_compression,
+        encrypted: cipher.is_some(),
+        authenticated: auth.is_some(),
+        loss_reports: adaptive_bounds.is_some(),
+        raw_i16: raw_i16 && !use_compression,
+        monotonic_clock,
+        planar: planar && !use_compression,
+        source_rate: sample_rate,
+        session_id: session.id(),
+        name: stream_name,
+    };
+    let encoded_header = encode_header(&header, auth.as_ref());
+    if let Some(rate) = max_packets_per_sec.filter(|_| !use_compression) {
+        check_packet_rate(rate, &header, max_payload)?;
+    }
+
+    let paced_transport = if pace {
+        Some(transport.try_clone().map_err(|e| socket_error(format!("Socket clone failed: {}", e)))?)
+    } else {
+        None
+    };
+
+    let samples_per_frame = frame_samples(stream_rate, frame_ms) * channels as usize;
+    let mut sender = PacketSender::new(transport, header, resampler, opus_encoder, samples_per_frame, max_payload, gain).own_session(session);
+    if let Some(previous) = previous {
+        sender = sender.continue_from(previous);
+    }
+    if use_compression && frames_per_packet > 1 {
+        sender = sender.bundle_frames(frames_per_packet);
+        info!("Bundling {} Opus frames per packet ({} ms)", frames_per_packet, frames_per_packet as f32 * frame_ms);
+    }
+    if channels != capture_channels {
+        sender = sender.remix_from(capture_channels);
+    }
+    if let Some(cipher) = cipher {
+        sender = sender.encrypt_with(cipher);
+        info!("Encrypting packets with AES-256-GCM");
+    }
+    let adaptive = match (adaptive_bounds, previous_bitrate) {
+        (Some((_, max_kbps)), Some(bitrate)) => {
+            sender = sender.adapt_bitrate(bitrate, max_kbps);
+            None
+        }
+        (Some((min_kbps, max_kbps)), None) if feedback_socket.is_some() => {
+            let controller = BitrateController::new(min_kbps, max_kbps, encoder.bitrate_kbps.unwrap_or(max_kbps));
+            let bitrate = SharedBitrate::new(controller.kbps());
+            sender = sender.adapt_bitrate(bitrate.clone(), max_kbps);
+            info!("Adapting bitrate to reported loss between {} and {} kbps", min_kbps, max_kbps);
+            Some((controller, bitrate))
+        }
+        _ => None,
+    };
+    let feedback = feedback_socket.map(|socket| FeedbackListener { socket, auth: auth.clone(), header: Arc::new(Mutex::new(encoded_header)), adaptive });
+    if let Some(auth) = auth {
+        sender = sender.authenticate_with(auth);
+        info!("Signing packets with HMAC-SHA256");
+    }
+    if let Some(rate) = max_packets_per_sec.filter(|_| !use_compression) {
+        sender = sender.limit_packet_rate(rate);
+        info!("Raw packets capped at {} per second", rate);
+    }
+    if let Some(threshold) = idle_threshold {
+        sender = sender.detect_idle_below(threshold);
+    }
+    if limiter {
+        sender = sender.soft_limit();
+        info!("Soft-clipping audio instead of hard clamping");
+    }
+    if let Some(threshold) = silence_threshold.filter(|_| !use_compression) {
+        sender = sender.skip_silence_below(threshold);
+        info!("Skipping raw buffers below {} RMS", threshold);
+    }
+    sender = sender.repeat_header(header_repeat, header_resend_every);
+    if header_resend_every.is_none() {
+        info!("Header resends off; late receivers need to send HELLO for it");
+    }
+    if fade_ms > 0.0 {
+        sender = sender.fade_in_out(fade_ms);
+    }
+    // The null source's tone is clean from its first sample
+    if input.is_some() && warmup_ms > 0 {
+        sender = sender.warm_up(warmup_ms);
+    }
+    if let Some(callback) = process_callback {
+        sender = sender.process_with(ProcessHook::start(callback, sample_rate, capture_channels));
+        info!("Running process_callback on captured audio");
+    }
+    if let Some(path) = tee_wav {
+        sender = sender.tee_to(WavTee::create(&path, sample_rate, channels)?);
+        info!("Writing outgoing audio to {}", path);
+    }
+    if let Some(transport) = paced_transport {
+        sender = sender.pace_on(transport);
+        info!("Pacing packets evenly");
+    }
+    sender.announce()?;
+
+    // Shared with the server thread, which sends keepalives while capture is quiet
+    let sender = Arc::new(Mutex::new(sender));
+    let Some((device, config, sample_format)) = input else {
+        info!("Server running with timestamps & latency measurement");
+        return Ok((Capture::Null(NullSource::start(sample_rate, capture_channels, sender.clone())), sender, feedback));
+    };
+    let stats = sender.lock().unwrap().stats();
+    let errors = StreamErrors { reporter: stream_errors, stats: stats.clone(), device_lost: Arc::new(AtomicBool::new(false)) };
+    // Capture only queues audio; encoding and sending happen on the send thread
+    let send_sender = sender.clone();
+    let (queue, send_thread) = SendThread::start(stats, move |block| {
+        if let Ok(mut sender) = send_sender.lock() {
+            sender.send_samples(block);
+        }
+    });
+    let stream = build_capture_stream(&device, config, sample_format, &queue, &errors)?;
+    stream.play().map_err(|e| device_error(format!("Play stream failed: {}", e)))?;
+
+    info!("Server running with timestamps & latency measurement");
+    let capture = DeviceCapture { stream, selection, sample_rate, channels: capture_channels, queue, send_thread, errors };
+    Ok((Capture::Device(Box::new(capture)), sender, feedback))
+}
+
+// Opens the capture device, with the requested channel count when it has one
+// (otherwise the sender converts), and reports its config and sample format
+fn open_input(selection: &InputSelection) -> PyResult<(cpal::Device, cpal::StreamConfig, cpal::SampleFormat)> {
+    let host = select_host(selection.host.as_deref());
+    let (device, default_config) = match selection.device_index {
+        Some(index) => select_device_by_index(&host, index)?,
+        None if selection.loopback => select_loopback_device(&host, selection.device_name.as_deref())?,
+        None => select_device(&host, selection.source.as_deref(), selection.device_name.as_deref())?,
+    };
+    info!("Capturing from: {}", device.name().unwrap_or_else(|_| "unknown device".to_string()));
+
+    let sample_format = default_config.sample_format();
+    let buffer_range = *default_config.buffer_size();
+    let mut config: cpal::StreamConfig = default_config.into();
+    if let Some(requested) = selection.channels {
+        if requested != config.channels && supports_channels(&device, requested, config.sample_rate.0, sample_format) {
+            config.channels = requested;
+        }
+    }
+    if let Some(rate) = selection.preferred_rate.filter(|&rate| rate != config.sample_rate.0) {
+        if supports_channels(&device, config.channels, rate, sample_format) {
+            config.sample_rate = cpal::SampleRate(rate);
+        } else {
+            warn!("Device can't capture {} channels at {} Hz, using its default {} Hz", config.channels, rate, config.sample_rate.0);
+        }
+    }
+    let sample_rate = config.sample_rate.0;
+    if let Some(frames) = selection.buffer_frames {
+        match buffer_range {
+            cpal::SupportedBufferSize::Range { min, max } if !(min..=max).contains(&frames) => {
+                warn!("Buffer of {} frames outside what the device supports ({}-{}), using its default", frames, min, max);
+            }
+            // An unknown range is left for the stream build to accept or reject
+            _ => config.buffer_size = cpal::BufferSize::Fixed(frames),
+        }
+    }
+    info!("Device config: {} Hz, {} channels", sample_rate, config.channels);
+    if sample_format == cpal::SampleFormat::F32 {
+        info!("Native sample format: f32");
+    } else {
+        info!("Native sample format: {}, converting to f32", sample_format);
+    }
+    Ok((device, config, sample_format))
+}
+
+// Builds a stream from an opened device, without a fixed buffer size if the
+// device turns the requested one down; it starts out stopped
+fn build_capture_stream(device: &cpal::Device, mut config: cpal::StreamConfig, sample_format: cpal::SampleFormat, queue: &CaptureQueue, errors: &StreamErrors) -> PyResult<cpal::Stream> {
+    if !matches!(sample_format, cpal::SampleFormat::F32 | cpal::SampleFormat::I16 | cpal::SampleFormat::U16 | cpal::SampleFormat::I32) {
+        return Err(device_error(format!("Sample format {} not supported (f32, i16, u16 or i32 only)", sample_format)));
+    }
+    let stream = match build_input_stream(device, &config, sample_format, queue, errors) {
+        Err(e) if config.buffer_size != cpal::BufferSize::Default => {
+            warn!("Device rejected a {:?} buffer ({}), using its default", config.buffer_size, e);
+            config.buffer_size = cpal::BufferSize::Default;
+            build_input_stream(device, &config, sample_format, queue, errors)
+        }
+        result => result,
+    }.map_err(|e| device_error(format!("Build stream failed: {}", e)))?;
+    match config.buffer_size {
+        cpal::BufferSize::Fixed(frames) => info!("Capture buffer: {} frames ({:.1} ms)", frames, frames as f64 * 1000.0 / config.sample_rate.0 as f64),
+        cpal::BufferSize::Default => info!("Capture buffer: device default"),
+    }
+    Ok(stream)
+}
+
+// Builds the capture stream for the device's native sample format, feeding the send queue
+fn build_input_stream(device: &cpal::Device, config: &cpal::StreamConfig, sample_format: cpal::SampleFormat, queue: &CaptureQueue, errors: &StreamErrors) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    match sample_format {
+        cpal::SampleFormat::F32 => {
+            let queue = queue.clone();
+            device.build_input_stream(
+                config,
+                move |data: &[f32], _: &_| queue.push(data),
+                stream_error_handler(errors.clone()),
+                None
+            )
+        }
+        cpal::SampleFormat::I16 => build_converting_stream::<i16>(device, config, queue.clone(), errors.clone()),
+        cpal::SampleFormat::U16 => build_converting_stream::<u16>(device, config, queue.clone(), errors.clone()),
+        cpal::SampleFormat::I32 => build_converting_stream::<i32>(device, config, queue.clone(), errors.clone()),
+        // Screened out before the stream is built
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+// Captures integer samples and converts them to f32 before they're queued
+fn build_converting_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, queue: CaptureQueue, errors: StreamErrors) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::SizedSample,
+    f32: cpal::FromSample<T>,
+{
+    // Sized like a queue block up front; grows at most once for a bigger buffer
+    let mut converted = Vec::with_capacity(BLOCK_SAMPLES);
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &_| {
+            converted.clear();
+            converted.extend(data.iter().map(|&sample| sample.to_sample::<f32>()));
+            queue.push(&converted);
+        },
+        stream_error_handler(errors),
+        None
+    )
+}
+
+// Logs stream errors, counts them by kind, queues them for the error reporter
+// and flags a device that has gone away for the server thread to reconnect
+fn stream_error_handler(errors: StreamErrors) -> impl FnMut(cpal::StreamError) + Send + 'static {
+    move |err| {
+        error!("Stream error: {}", err);
+        let kind = match err {
+            cpal::StreamError::DeviceNotAvailable => StreamErrorKind::DeviceLost,
+            cpal::StreamError::BackendSpecific { .. } => StreamErrorKind::Backend,
+        };
+        errors.record(kind, err.to_string());
+    }
+}
+
+// Doubles from RECONNECT_INITIAL_DELAY after each failed attempt, up to RECONNECT_MAX_DELAY
+fn reconnect_delay(failed_attempts: u32) -> Duration {
+    RECONNECT_INITIAL_DELAY.saturating_mul(1 << failed_attempts.min(16)).min(RECONNECT_MAX_DELAY)
+}
+
+// Calls `callback` with each queued stream error until every sender is dropped.
+// Runs on its own thread so cpal's error closure never waits on the GIL.
+fn spawn_error_reporter(callback: PyObject) -> (mpsc::Sender<String>, JoinHandle<()>) {
+    let (errors, received) = mpsc::channel::<String>();
+    let thread = thread::spawn(move || {
+        for message in received {
+            if let Err(e) = Python::with_gil(|py| callback.call1(py, (message,)).map(|_| ())) {
+                error!("Error callback error: {}", e);
+            }
+        }
+    });
+    (errors, thread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stopping_a_server_with_on_error_returns() {
+        pyo3::prepare_freethreaded_python();
+        let mut handle = Python::with_gil(|py| -> PyResult<ServerHandle> {
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("source", NULL_SOURCE)?;
+            kwargs.set_item("on_error", py.eval("lambda message: None", None, None)?)?;
+            let config = py.get_type::<ServerConfig>().call(("127.0.0.1", 45999), Some(kwargs))?.extract()?;
+            start_server(py, config)
+        }).unwrap();
+
+        // A stop that hangs fails the test rather than the whole run
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+        thread::spawn(move || {
+            Python::with_gil(|py| handle.stop(py));
+            let _ = stopped_tx.send(handle.stop_reason());
+        });
+        assert_eq!(stopped_rx.recv_timeout(Duration::from_secs(5)), Ok(Some("explicit")));
+    }
+}
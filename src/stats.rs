@@ -0,0 +1,229 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::adaptive::{LossCounter, LossReport};
+use crate::protocol::{get_timestamp_us, Clock};
+use crate::sender::SenderStats;
+
+// Report roughly once a second at the default 20ms frame size
+const REPORT_EVERY_PACKETS: u64 = 50;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Packets the receive-side latency average is taken over
+const LATENCY_WINDOW: usize = 100;
+// One-second intervals the receive-side loss percentage is taken over
+const LOSS_WINDOW: usize = 10;
+
+/// Calls `callback` with a stats dict every `REPORT_EVERY_PACKETS` packets until `running` clears.
+///
+/// Runs on its own thread so the audio callback never waits on the GIL.
+pub(crate) fn spawn_stats_reporter(stats: Arc<SenderStats>, callback: PyObject, running: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut next_report = REPORT_EVERY_PACKETS;
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+
+            let packet_count = stats.packets_sent.load(Ordering::Relaxed);
+            if packet_count < next_report {
+                continue;
+            }
+            next_report = (packet_count / REPORT_EVERY_PACKETS + 1) * REPORT_EVERY_PACKETS;
+
+            let bytes_sent = stats.bytes_sent.load(Ordering::Relaxed);
+            let packets_dropped = stats.packets_dropped.load(Ordering::Relaxed);
+            let frames_encoded = stats.frames_encoded.load(Ordering::Relaxed);
+            let average_encode_us = if frames_encoded > 0 {
+                stats.encode_us_total.load(Ordering::Relaxed) as f64 / frames_encoded as f64
+            } else {
+                0.0
+            };
+            // Raw streams encode nothing, so all of these stay at 0
+            let min_encode_us = if frames_encoded > 0 { stats.encode_us_min.load(Ordering::Relaxed) } else { 0 };
+            let max_encode_us = stats.encode_us_max.load(Ordering::Relaxed);
+            let slow_encodes = stats.slow_encodes.load(Ordering::Relaxed);
+            let send_failures = stats.send_failures.load(Ordering::Relaxed);
+            let consecutive_send_failures = stats.consecutive_send_failures.load(Ordering::Relaxed);
+            let backpressure_drops = stats.backpressure_drops.load(Ordering::Relaxed);
+            let targets: Vec<_> = stats.targets.iter().map(|target| (target.addr.as_str(), target.packets_sent.load(Ordering::Relaxed), target.bytes_sent.load(Ordering::Relaxed), target.send_errors.load(Ordering::Relaxed))).collect();
+
+            let result = Python::with_gil(|py| {
+                let report = PyDict::new(py);
+                report.set_item("packet_count", packet_count)?;
+                report.set_item("bytes_sent", bytes_sent)?;
+                report.set_item("packets_dropped", packets_dropped)?;
+                report.set_item("average_encode_us", average_encode_us)?;
+                report.set_item("min_encode_us", min_encode_us)?;
+                report.set_item("max_encode_us", max_encode_us)?;
+                report.set_item("slow_encodes", slow_encodes)?;
+                report.set_item("send_failures", send_failures)?;
+                report.set_item("consecutive_send_failures", consecutive_send_failures)?;
+                report.set_item("backpressure_drops", backpressure_drops)?;
+                report.set_item("stream_errors", stats.stream_errors_dict(py)?)?;
+                // Keyed by "ip:port", so one slow or unreachable target stands out
+                let per_target = PyDict::new(py);
+                for (addr, packets_sent, bytes_sent, send_errors) in targets {
+                    let target = PyDict::new(py);
+                    target.set_item("packets_sent", packets_sent)?;
+                    target.set_item("bytes_sent", bytes_sent)?;
+                    target.set_item("send_errors", send_errors)?;
+                    per_target.set_item(addr, target)?;
+                }
+                report.set_item("targets", per_target)?;
+                callback.call1(py, (report,)).map(|_| ())
+            });
+            if let Err(e) = result {
+                error!("Stats callback error: {}", e);
+            }
+        }
+    })
+}
+
+/// Rolling average of one-way latency, from packet timestamps to the local clock.
+///
+/// Only as accurate as the sync between the two machines' clocks: any offset
+/// shows up directly in the result, which can even go negative. Use NTP on both
+/// ends for meaningful numbers; on one machine it is exact.
+///
+/// Timestamps from a sender's monotonic clock share no origin with ours, so
+/// latency is then measured from the fastest packet seen: it shows delay added
+/// by the network, not the total.
+pub(crate) struct LatencyTracker {
+    samples_us: VecDeque<i64>,
+    total_us: i64,
+    packet_count: u64,
+    // For monotonic timestamps: when tracking started, and the smallest gap
+    // between a packet's arrival and its timestamp so far
+    monotonic: Option<(Instant, Option<i64>)>,
+    // Sender wall clock minus ours, when measured; wall clock latency is corrected by it
+    clock_offset_us: Option<i64>,
+    // Our wall clock, for arrival times
+    clock: Clock,
+}
+
+impl LatencyTracker {
+    pub(crate) fn new(monotonic_clock: bool) -> Self {
+        LatencyTracker { samples_us: VecDeque::with_capacity(LATENCY_WINDOW), total_us: 0, packet_count: 0, monotonic: monotonic_clock.then(|| (Instant::now(), None)), clock_offset_us: None, clock: get_timestamp_us }
+    }
+
+    /// Times arrivals with `clock` instead of the system clock.
+    #[cfg(test)]
+    fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub(crate) fn set_clock_offset(&mut self, offset_us: Option<i64>) {
+        self.clock_offset_us = offset_us;
+    }
+
+    /// Records a packet stamped with the sender's `timestamp_us`.
+    pub(crate) fn record(&mut self, timestamp_us: u64) {
+        let latency_us = match &mut self.monotonic {
+            Some((started, fastest)) => {
+                let offset_us = started.elapsed().as_micros() as i64 - timestamp_us as i64;
+                let fastest = fastest.get_or_insert(offset_us);
+                *fastest = (*fastest).min(offset_us);
+                offset_us - *fastest
+            }
+            None => (self.clock)() as i64 + self.clock_offset_us.unwrap_or(0) - timestamp_us as i64,
+        };
+        if self.samples_us.len() == LATENCY_WINDOW {
+            self.total_us -= self.samples_us.pop_front().unwrap_or(0);
+        }
+        self.samples_us.push_back(latency_us);
+        self.total_us += latency_us;
+        self.packet_count += 1;
+    }
+
+    /// Average over the last `LATENCY_WINDOW` packets, or None before any arrive.
+    pub(crate) fn average_ms(&self) -> Option<f64> {
+        (!self.samples_us.is_empty()).then(|| self.total_us as f64 / self.samples_us.len() as f64 / 1000.0)
+    }
+
+    /// Calls the client's stats callback with the packet count, average latency,
+    /// the measured clock offset, recent packet loss and output buffer underruns
+    /// and overruns.
+    pub(crate) fn report(&self, py: Python, callback: &PyObject, loss: &LossTracker, playback: &PlaybackStats) -> PyResult<()> {
+        let report = PyDict::new(py);
+        report.set_item("packet_count", self.packet_count)?;
+        report.set_item("average_latency_ms", self.average_ms())?;
+        report.set_item("latency_clock", if self.monotonic.is_some() { "monotonic" } else { "wall" })?;
+        report.set_item("clock_offset_ms", self.clock_offset_us.filter(|_| self.monotonic.is_none()).map(|offset_us| offset_us as f64 / 1000.0))?;
+        report.set_item("loss_percent", loss.loss_percent())?;
+        report.set_item("underruns", playback.underruns.load(Ordering::Relaxed))?;
+        report.set_item("overruns", playback.overruns.load(Ordering::Relaxed))?;
+        callback.call1(py, (report,)).map(|_| ())
+    }
+}
+
+/// How often the client's output buffer ran dry or overflowed, for tuning
+/// jitter_ms: underruns mean it's too short for the network, overruns that
+/// audio is arriving faster than the device plays it.
+#[derive(Default)]
+pub(crate) struct PlaybackStats {
+    // Times the output ran out of audio mid-stream and played silence until more came
+    pub(crate) underruns: AtomicU64,
+    // Times the queue went over its cap and the oldest samples were dropped
+    pub(crate) overruns: AtomicU64,
+}
+
+/// Packet loss over the last `LOSS_WINDOW` one-second intervals, from gaps in
+/// the sequence numbers.
+pub(crate) struct LossTracker {
+    counter: LossCounter,
+    intervals: VecDeque<LossReport>,
+}
+
+impl LossTracker {
+    pub(crate) fn new() -> Self {
+        LossTracker { counter: LossCounter::new(), intervals: VecDeque::with_capacity(LOSS_WINDOW) }
+    }
+
+    pub(crate) fn record(&mut self, sequence: u32) {
+        // Losses counted against the old stream say nothing about the new one
+        if self.counter.record(sequence) {
+            self.intervals.clear();
+        }
+        if let Some(report) = self.counter.take_report(Instant::now()) {
+            if self.intervals.len() == LOSS_WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(report);
+        }
+    }
+
+    /// Percentage of packets lost over the window, or None until an interval completes.
+    pub(crate) fn loss_percent(&self) -> Option<f64> {
+        let expected: u64 = self.intervals.iter().map(|report| report.expected as u64).sum();
+        let lost: u64 = self.intervals.iter().map(|report| report.lost as u64).sum();
+        (expected > 0).then(|| lost as f64 * 100.0 / expected as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed_clock() -> u64 {
+        1_700_000_000_050_000
+    }
+
+    #[test]
+    fn wall_clock_latency_is_averaged_and_corrected_for_offset() {
+        let mut latency = LatencyTracker::new(false).with_clock(fixed_clock);
+        assert_eq!(latency.average_ms(), None);
+        latency.record(1_700_000_000_040_000);
+        latency.record(1_700_000_000_020_000);
+        assert_eq!(latency.average_ms(), Some(20.0));
+
+        // A sender clock running 5ms ahead stamps packets 5ms later than ours would
+        let mut latency = LatencyTracker::new(false).with_clock(fixed_clock);
+        latency.set_clock_offset(Some(5_000));
+        latency.record(1_700_000_000_045_000);
+        assert_eq!(latency.average_ms(), Some(10.0));
+    }
+}
@@ -0,0 +1,62 @@
+use pyo3::prelude::*;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+/// Writes a copy of the audio being sent to a WAV file from its own thread, so
+/// the capture callback never waits on the disk.
+pub(crate) struct WavTee {
+    samples: Option<mpsc::Sender<Vec<f32>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WavTee {
+    /// Creates `path` as a 32-bit float WAV at `sample_rate` with `channels`.
+    pub(crate) fn create(path: &str, sample_rate: u32, channels: u16) -> PyResult<Self> {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).map_err(|e| PyErr::new::<pyo3::exceptions::PyOSError, _>(format!("Failed to create {}: {}", path, e)))?;
+        let (samples, received) = mpsc::channel::<Vec<f32>>();
+        let path = path.to_string();
+
+        let thread = thread::spawn(move || {
+            // Ends once the sender side is dropped and everything queued is written
+            for block in received {
+                if let Err(e) = block.iter().try_for_each(|&sample| writer.write_sample(sample)) {
+                    error!("Tee WAV write failed, no longer writing {}: {}", path, e);
+                    break;
+                }
+            }
+            let frames = writer.duration();
+            match writer.finalize() {
+                Ok(()) => info!("Tee WAV finished: {:.1}s written to {}", frames as f64 / sample_rate as f64, path),
+                Err(e) => error!("Failed to finalize tee WAV {}: {}", path, e),
+            }
+        });
+
+        Ok(WavTee { samples: Some(samples), thread: Some(thread) })
+    }
+
+    pub(crate) fn write(&self, samples: &[f32]) {
+        if let Some(sender) = &self.samples {
+            let _ = sender.send(samples.to_vec());
+        }
+    }
+
+    /// Writes out whatever is still queued and finalizes the file.
+    pub(crate) fn finish(&mut self) {
+        self.samples.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for WavTee {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,232 @@
+use pyo3::prelude::*;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use socket2::{Domain, Socket, Type};
+
+use crate::error::{config_error, socket_error};
+use crate::net::local_address;
+
+// Over TCP every datagram is prefixed with its length so the receiver can
+// split the byte stream back into headers and packets:
+//   [LENGTH(2)][HEADER or PACKET(LENGTH)]
+
+// A receiver that's gone without closing the connection would otherwise hold
+// up the connect, or a write once the socket buffer fills, for minutes, and
+// with them stop() on the handle
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How packets travel to the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransportKind {
+    Udp,
+    Tcp,
+}
+
+/// Parses a `transport` name: "udp" or "tcp".
+pub(crate) fn parse_transport(name: &str) -> Option<TransportKind> {
+    match name {
+        "udp" => Some(TransportKind::Udp),
+        "tcp" => Some(TransportKind::Tcp),
+        _ => None,
+    }
+}
+
+/// True for send errors that say nothing about the route, e.g. a full socket
+/// buffer, as opposed to ones like an unreachable network that won't clear up.
+pub(crate) fn is_transient_send_error(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut)
+}
+
+/// Outgoing connection that headers and packets are written to.
+pub(crate) enum Transport {
+    // Every datagram goes to each target in turn
+    Udp { socket: UdpSocket, target_addrs: Vec<String> },
+    Tcp(TcpStream),
+}
+
+impl Transport {
+    /// Sends one header or packet, returning the number of bytes put on the wire.
+    /// With several UDP targets this fails only if none of them could be reached.
+    pub(crate) fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.send_each(data, |_, _| {})
+    }
+
+    /// Like `send`, also passing `on_sent` each destination's position in
+    /// `target_addrs()` and what sending to it returned.
+    pub(crate) fn send_each(&self, data: &[u8], mut on_sent: impl FnMut(usize, &io::Result<usize>)) -> io::Result<usize> {
+        match self {
+            Transport::Udp { socket, target_addrs } => {
+                let mut sent = 0;
+                let mut last_error = None;
+                for (index, target_addr) in target_addrs.iter().enumerate() {
+                    let result = socket.send_to(data, target_addr.as_str());
+                    on_sent(index, &result);
+                    match result {
+                        Ok(len) => sent += len,
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                match last_error {
+                    Some(e) if sent == 0 => Err(e),
+                    _ => Ok(sent),
+                }
+            }
+            Transport::Tcp(stream) => {
+                let mut framed = Vec::with_capacity(2 + data.len());
+                framed.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                framed.extend_from_slice(data);
+                // Build the whole frame first so it goes out in a single write
+                let result = write_frame(stream, &framed).map(|()| framed.len());
+                on_sent(0, &result);
+                result
+            }
+        }
+    }
+
+    /// Destinations as "ip:port", in the order `send_each` reports them.
+    pub(crate) fn target_addrs(&self) -> Vec<String> {
+        match self {
+            Transport::Udp { target_addrs, .. } => target_addrs.clone(),
+            Transport::Tcp(stream) => stream.peer_addr().map(|addr| vec![addr.to_string()]).unwrap_or_default(),
+        }
+    }
+
+    /// A second handle on the same socket or connection.
+    pub(crate) fn try_clone(&self) -> io::Result<Transport> {
+        Ok(match self {
+            Transport::Udp { socket, target_addrs } => Transport::Udp { socket: socket.try_clone()?, target_addrs: target_addrs.clone() },
+            Transport::Tcp(stream) => Transport::Tcp(stream.try_clone()?),
+        })
+    }
+
+    /// Address the packets leave from.
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Transport::Udp { socket, .. } => socket.local_addr(),
+            Transport::Tcp(stream) => stream.local_addr(),
+        }
+    }
+}
+
+// Writes one length-prefixed frame. A write that times out before any of it
+// went out just loses the frame, but one cut off partway leaves the receiver
+// reading the rest as the next length, so the connection is closed instead and
+// every send after fails for good.
+fn write_frame(stream: &TcpStream, framed: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < framed.len() {
+        match (&*stream).write(&framed[written..]) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(len) => written += len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                if written > 0 {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Opens a TCP connection to `target_addr`, binding locally first if asked to.
+pub(crate) fn connect_tcp(bind_addr: Option<&str>, bind_port: Option<u16>, target_addr: &str) -> PyResult<TcpStream> {
+    let connect_error = |e: io::Error| socket_error(format!("TCP connect to {} failed: {}", target_addr, e));
+    let target = target_addr.to_socket_addrs().map_err(connect_error)?.next()
+        .ok_or_else(|| socket_error(format!("TCP connect to {} failed: address did not resolve", target_addr)))?;
+
+    let stream = if bind_addr.is_none() && bind_port.is_none() {
+        TcpStream::connect_timeout(&target, TCP_CONNECT_TIMEOUT).map_err(connect_error)?
+    } else {
+        let local_addr = local_address(bind_addr, bind_port, &target.ip().to_string());
+        let local: SocketAddr = local_addr.parse().map_err(|e| config_error(format!("Invalid bind address {}: {}", local_addr, e)))?;
+        let socket = Socket::new(Domain::for_address(target), Type::STREAM, None).map_err(connect_error)?;
+        socket.bind(&local.into()).map_err(|e| socket_error(format!("Socket bind failed on {}: {}", local_addr, e)))?;
+        socket.connect_timeout(&target.into(), TCP_CONNECT_TIMEOUT).map_err(connect_error)?;
+        socket.into()
+    };
+    // Packets are small and latency-sensitive, so don't let Nagle hold them back
+    stream.set_nodelay(true).map_err(connect_error)?;
+    stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT)).map_err(connect_error)?;
+    Ok(stream)
+}
+
+/// Listening end of the TCP transport; accepts one sender at a time.
+pub(crate) struct TcpSource {
+    listener: TcpListener,
+    read_timeout: Duration,
+    stream: Option<TcpStream>,
+    // Bytes read from the stream that don't form a complete frame yet
+    pending: Vec<u8>,
+}
+
+impl TcpSource {
+    pub(crate) fn bind(bind_addr: &str, read_timeout: Duration) -> PyResult<Self> {
+        let listener = TcpListener::bind(bind_addr).map_err(|e| socket_error(format!("Socket bind failed: {}", e)))?;
+        // Poll for senders so receive calls still return after the timeout
+        listener.set_nonblocking(true).map_err(|e| socket_error(format!("Socket setup failed: {}", e)))?;
+        Ok(TcpSource { listener, read_timeout, stream: None, pending: Vec::new() })
+    }
+
+    /// Copies the next complete frame into `buf`, returning its length, or
+    /// None when `read_timeout` passes first.
+    pub(crate) fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            if let Some(len) = self.take_frame(buf) {
+                return Ok(Some(len));
+            }
+
+            let Some(stream) = &mut self.stream else {
+                match self.listener.accept() {
+                    Ok((stream, peer)) => {
+                        stream.set_nonblocking(false)?;
+                        stream.set_read_timeout(Some(self.read_timeout))?;
+                        info!("TCP sender connected from {}", peer);
+                        self.stream = Some(stream);
+                        continue;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(self.read_timeout);
+                        return Ok(None);
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+
+            let mut chunk = [0u8; 8192];
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.disconnect();
+                    return Ok(None);
+                }
+                Ok(n) => self.pending.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => {
+                    self.disconnect();
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn take_frame(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let prefix: [u8; 2] = self.pending.get(0..2)?.try_into().ok()?;
+        let len = u16::from_le_bytes(prefix) as usize;
+        let frame = self.pending.get(2..2 + len)?;
+        buf[..len].copy_from_slice(frame);
+        self.pending.drain(..2 + len);
+        Some(len)
+    }
+
+    // Drop any partial frame so the next sender starts on a frame boundary
+    fn disconnect(&mut self) {
+        info!("TCP sender disconnected");
+        self.stream = None;
+        self.pending.clear();
+    }
+}
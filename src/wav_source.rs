@@ -0,0 +1,177 @@
+use pyo3::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::codec::{frame_samples, new_opus_encoder, EncoderSettings, opus_channels, opus_sample_rate, OPUS_RESAMPLE_RATE};
+use crate::error::config_error;
+use crate::net::{bind_sender_socket, socket_address};
+use crate::protocol::{LocalSession, StreamHeader, PROTOCOL_VERSION};
+use crate::resample::Resampler;
+use crate::sender::{PacketSender, SharedGain, DEFAULT_KEEPALIVE_MS, DEFAULT_MAX_PAYLOAD};
+use crate::server::{ServerHandle, SharedStopReason, StopReason, StreamCommand};
+use crate::transport::Transport;
+
+// Same framing as the live path's default
+const FRAME_MS: f32 = 20.0;
+
+/// Streams a WAV file to `target_ip:target_port`. With `use_compression`
+/// the file has to be mono or stereo; one at a rate Opus can't encode is
+/// resampled to 48kHz, or rejected before anything is sent when `resample` is
+/// False.
+#[pyfunction]
+pub(crate) fn stream_wav_file(
+    py: Python,
+    file_path: String,
+    target_ip: String,
+    target_port: u16,
+    use_compression: Option<bool>,
+    loop_playback: Option<bool>,
+    resample: Option<bool>,
+) -> PyResult<ServerHandle> {
+    let use_compression = use_compression.unwrap_or(false);
+    let loop_playback = loop_playback.unwrap_or(false);
+    let resample = resample.unwrap_or(true);
+
+    let (samples, spec) = read_wav(&file_path)?;
+    let (sample_rate, channels) = (spec.sample_rate, spec.channels);
+    let sample_format = match spec.sample_format {
+        hound::SampleFormat::Float => "float",
+        hound::SampleFormat::Int => "int",
+    };
+    info!("Streaming {} ({} Hz, {} channels, {}-bit {})", file_path, sample_rate, channels, spec.bits_per_sample, sample_format);
+    let stream_rate = wav_stream_rate(&file_path, sample_rate, channels, use_compression, resample)?;
+
+    let socket = bind_sender_socket(None, None, &target_ip)?;
+    let target_addr = socket_address(&target_ip, target_port);
+    info!("Streaming audio to: {}", target_addr);
+
+    let resampler = (stream_rate != sample_rate).then(|| Resampler::new(sample_rate, stream_rate, channels));
+    let opus_encoder = if use_compression {
+        Some(new_opus_encoder(stream_rate, channels, &EncoderSettings::default())?)
+    } else {
+        None
+    };
+
+    let session = LocalSession::start();
+    let header = StreamHeader {
+        version: PROTOCOL_VERSION,
+        sample_rate: stream_rate,
+        channels,
+        compressed: use_compression,
+        encrypted: false,
+        authenticated: false,
+        loss_reports: false,
+        raw_i16: false,
+        monotonic_clock: false,
+        planar: false,
+        source_rate: sample_rate,
+        session_id: session.id(),
+        name: None,
+    };
+    let gain = SharedGain::new(1.0);
+    let sender = PacketSender::new(Transport::Udp { socket, target_addrs: vec![target_addr] }, header, resampler, opus_encoder, frame_samples(stream_rate, FRAME_MS) * channels as usize, DEFAULT_MAX_PAYLOAD, gain.clone()).own_session(session);
+    // Release GIL while the header goes out
+    let mut sender = py.allow_threads(move || sender.announce().map(|()| sender))?;
+    let info = sender.stream_info();
+    let stats = sender.stats();
+
+    let (command_tx, command_rx) = mpsc::channel();
+    let running = Arc::new(AtomicBool::new(true));
+    let running_clone = running.clone();
+    let stop_reason = SharedStopReason::default();
+    let stop_reason_clone = stop_reason.clone();
+
+    let thread = thread::spawn(move || {
+        // Pace by the file's own rate so each chunk covers one frame of real time
+        let chunk_len = frame_samples(sample_rate, FRAME_MS) * channels as usize;
+        let chunk_duration = Duration::from_secs_f64(FRAME_MS as f64 / 1000.0);
+        let mut next_send = Instant::now();
+        let mut paused = false;
+        let keepalive = Duration::from_millis(DEFAULT_KEEPALIVE_MS as u64);
+        let mut reason = StopReason::Finished;
+
+        'playback: loop {
+            for chunk in samples.chunks(chunk_len) {
+                // Wait out a pause, keeping the session alive, and stop on an explicit
+                // stop() or when the handle is dropped
+                loop {
+                    let wait = if paused { keepalive / 4 } else { Duration::ZERO };
+                    match command_rx.recv_timeout(wait) {
+                        Ok(StreamCommand::Pause) => paused = true,
+                        Ok(StreamCommand::Resume) => {
+                            if paused {
+                                next_send = Instant::now();
+                            }
+                            paused = false;
+                        }
+                        Ok(StreamCommand::Reconfigure(_, reply)) => {
+                            let _ = reply.send(Err(config_error("reconfigure only applies to capture servers, not WAV files")));
+                        }
+                        Err(RecvTimeoutError::Timeout) if paused => sender.send_keepalive_if_idle(keepalive),
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            reason = StopReason::Explicit;
+                            break 'playback;
+                        }
+                    }
+                }
+                sender.send_samples(chunk);
+
+                next_send += chunk_duration;
+                if let Some(wait) = next_send.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+            if !loop_playback {
+                break;
+            }
+        }
+
+        sender.finish();
+        *stop_reason_clone.lock().unwrap() = Some(reason);
+        running_clone.store(false, Ordering::SeqCst);
+        info!("File streaming stopped");
+    });
+
+    Ok(ServerHandle::new(command_tx, thread, running, gain, info, stats, stop_reason))
+}
+
+// Checks the file's format against Opus when compressing and picks the rate to
+// stream at, so a file that can't be encoded fails before any packets go out
+fn wav_stream_rate(file_path: &str, sample_rate: u32, channels: u16, use_compression: bool, resample: bool) -> PyResult<u32> {
+    if !use_compression {
+        return Ok(sample_rate);
+    }
+    if opus_channels(channels).is_none() {
+        return Err(config_error(format!("{} has {} channels, Opus takes 1 or 2; stream it without compression", file_path, channels)));
+    }
+    if opus_sample_rate(sample_rate).is_some() {
+        return Ok(sample_rate);
+    }
+    if !resample {
+        return Err(config_error(format!("{} is {} Hz, which Opus can't encode (supported: 8k, 12k, 16k, 24k, 48k); enable resample or stream it without compression", file_path, sample_rate)));
+    }
+    info!("Resampling {} Hz to {} Hz for Opus", sample_rate, OPUS_RESAMPLE_RATE);
+    Ok(OPUS_RESAMPLE_RATE)
+}
+
+/// Reads a whole WAV file as interleaved f32 samples, returning them with its format.
+fn read_wav(file_path: &str) -> PyResult<(Vec<f32>, hound::WavSpec)> {
+    let reader = hound::WavReader::open(file_path).map_err(|e| PyErr::new::<pyo3::exceptions::PyOSError, _>(format!("Failed to open {}: {}", file_path, e)))?;
+    let spec = reader.spec();
+
+    let samples: Result<Vec<f32>, _> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.into_samples::<f32>().collect(),
+        hound::SampleFormat::Int => {
+            // Scale integer PCM of any width into [-1.0, 1.0)
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader.into_samples::<i32>().map(|sample| sample.map(|s| s as f32 / scale)).collect()
+        }
+    };
+    let samples = samples.map_err(|e| PyErr::new::<pyo3::exceptions::PyOSError, _>(format!("Failed to read {}: {}", file_path, e)))?;
+
+    Ok((samples, spec))
+}
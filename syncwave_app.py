@@ -22,6 +22,7 @@ import pyaudio
 import struct
 import time
 import collections
+import logging
 from tkinter import messagebox
 import sys
 
@@ -93,6 +94,7 @@ class SyncWaveApp(ctk.CTk):
         # Application state
         self.config = self.load_config()
         self.server_thread = None
+        self.server_handles = []
         self.receiver_thread = None
         self.server_running = False
         self.receiver_running = False
@@ -722,8 +724,9 @@ class SyncWaveApp(ctk.CTk):
     def run_server(self, ip, port, compression, broadcast):
         """Run server in background"""
         try:
-            # This will run in a daemon thread and release Python's GIL
-            syncwave_core.start_audio_server(ip, port, compression, broadcast)
+            # Returns once the stream is running; the handle stops it later
+            handle = syncwave_core.start_audio_server(ip, port, compression, broadcast)
+            self.server_handles.append(handle)
         except Exception as e:
             self.log_server(f"Server error: {e}")
             self.after(0, lambda: messagebox.showerror("Server Error", str(e)))
@@ -733,10 +736,9 @@ class SyncWaveApp(ctk.CTk):
         self.log_server("Stopping server...")
         self.server_running = False
         
-        # Note: Server thread is daemon and will terminate when app closes
-        # No graceful shutdown mechanism yet - requires Rust refactoring
-        if self.server_thread and self.server_thread.is_alive():
-            self.log_server("⚠ Server thread still running (will stop on app exit)")
+        for handle in self.server_handles:
+            handle.stop()
+        self.server_handles = []
         
         self.server_button.configure(
             text="▶ Start Server",
@@ -751,7 +753,7 @@ class SyncWaveApp(ctk.CTk):
         self.server_ip_entry.configure(state="normal")
         self.server_port_entry.configure(state="normal")
         
-        messagebox.showinfo("Info", "Server will stop after current stream ends.")
+        self.log_server("Server stopped")
     
     def toggle_receiver(self):
         """Start or stop receiver"""
@@ -858,6 +860,8 @@ Rust Core: {'✅ Available' if RUST_CORE_AVAILABLE else '❌ Not Found'}
         """Handle window close"""
         if self.server_running or self.receiver_running:
             if messagebox.askokcancel("Quit", "Server/Receiver is running. Stop and quit?"):
+                for handle in self.server_handles:
+                    handle.stop()
                 self.server_running = False
                 self.receiver_running = False
                 self.destroy()
@@ -866,6 +870,8 @@ Rust Core: {'✅ Available' if RUST_CORE_AVAILABLE else '❌ Not Found'}
 
 def main():
     """Main entry point"""
+    # Keep the core's startup messages visible on the console
+    logging.basicConfig(level=logging.INFO, format="%(message)s")
     app = SyncWaveApp()
     app.mainloop()
 